@@ -1,11 +1,15 @@
 #![allow(clippy::unreadable_literal)]
 #![forbid(unsafe_code)]
-use win_etw_macros::trace_logging_provider;
+use win_etw_macros::{trace_logging_provider, EventEnum, EventFields};
 
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
-use widestring::{U16CString, U16String};
-use win_etw_provider::{guid, FILETIME, GUID};
+use widestring::{U16CStr, U16CString, U16Str, U16String};
+use win_etw_provider::metadata::{InFlag, OutFlag};
+use win_etw_provider::{counted_bytes, guid, EventDataDescriptor, EventField, FILETIME, GUID};
 
 // {861A3948-3B6B-4DDF-B862-B2CB361E238E}
 // DEFINE_GUID(my_provider_guid, 0x861a3948, 0x3b6b, 0x4ddf, 0xb8, 0x62, 0xb2, 0xcb, 0x36, 0x1e, 0x23, 0x8e);
@@ -30,8 +34,19 @@ fn main() {
     hello_provider.client_connected(None, &SocketAddr::V4(client_addr_v4));
     hello_provider.client_connected(None, &SocketAddr::V6(client_addr_v6));
 
+    hello_provider.peer_addresses(
+        None,
+        *client_addr_v4.ip(),
+        *client_addr_v6.ip(),
+        IpAddr::V4(*client_addr_v4.ip()),
+        Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+    );
+
     hello_provider.something_bad_happened(None, "uh oh!");
 
+    let generic_provider = GenericBackendProvider::new();
+    generic_provider.arg_str(None, "Hello from a generic provider!");
+
     hello_provider.file_created(None, SystemTime::now());
     hello_provider.file_created_filetime(
         None,
@@ -76,7 +91,9 @@ fn main() {
 /// Hello, World, from ETW
 #[trace_logging_provider(
     guid = "861A3948-3B6B-4DDF-B862-B2CB361E238E",
-    provider_group_guid = "6aeb6059-444a-4606-a3ea-06fd00fe3378"
+    provider_group_guid = "6aeb6059-444a-4606-a3ea-06fd00fe3378",
+    tasks(Connect = 1, DbQuery = 2),
+    keywords(Network = 0x1, Storage = 0x2)
 )]
 trait HelloWorldProvider {
     fn hello(a: &str);
@@ -107,6 +124,8 @@ trait HelloWorldProvider {
 
     fn file_created(create_time: SystemTime);
 
+    fn file_modified(modify_time: SystemTime, size: u64);
+
     fn file_created_filetime(t: FILETIME);
 
     fn arg_bool(a: bool);
@@ -141,6 +160,152 @@ trait HelloWorldProvider {
 
     #[event(level = 8)]
     fn message_at_level_8(msg: &str);
+
+    #[event(version = 2, channel = "operational")]
+    fn versioned_event(a: u32);
+
+    #[event(task = "DbQuery", opcode = "start", keywords("Network", "Storage"))]
+    fn db_query_start(query: &str);
+
+    #[event(task = "DbQuery", opcode = "stop")]
+    fn db_query_stop(rows: u32);
+
+    /// Handles an HTTP request.
+    #[activity(stop(status: u32))]
+    #[event(task = "Connect")]
+    fn request(url: &str);
+
+    fn request_received(request: &RequestInfo, client: ClientInfo);
+
+    fn optional_values(
+        name: Option<&str>,
+        #[event(has_field)] id: Option<&GUID>,
+        address: Option<&SocketAddrV4>,
+        count: Option<u32>,
+    );
+
+    fn optional_types(types: &OptionalTypes);
+
+    fn fixed_arrays(hash: &[u8; 32], mac: [u8; 6], #[event(output = "hex")] words: [u32; 2]);
+
+    fn string_arrays(hosts: &[&str], features: &[String], ids: &[GUID], aliases: Option<&[&str]>);
+
+    fn owned_strings(a: String, b: &String, c: Cow<'_, str>, d: Box<str>, e: impl AsRef<str>);
+
+    fn file_opened(path: &Path, dir: PathBuf, file: &FileInfo);
+
+    fn optional_strings(
+        a: Option<String>,
+        b: Option<&String>,
+        #[event(has_field)] c: Option<Cow<'_, str>>,
+        path: Option<&Path>,
+        dir: Option<PathBuf>,
+    );
+
+    fn qualified_types(
+        time: std::time::SystemTime,
+        addr: &core::net::SocketAddr,
+        id: &win_etw_provider::GUID,
+        started: Timestamp,
+        port: Port,
+    );
+
+    fn user_logged_in(user: UserId, password_hash: &Sha256);
+
+    fn peer_addresses(v4: Ipv4Addr, v6: Ipv6Addr, mapped: IpAddr, missing: Option<IpAddr>);
+
+    fn listener_started(
+        #[event(output = "pid")] process: u32,
+        #[event(output = "ipv4")] address: u32,
+        #[event(output = "port")] port: u16,
+        #[event(output = "json")] config: &str,
+        #[event(output = "utc")] time: SystemTime,
+    );
+
+    fn connection_state_changed(
+        #[event(variant_name)] state: ConnectionState,
+        previous: ConnectionState,
+    );
+}
+
+/// Type aliases are encoded by the `EventField` implementation of the type that they refer to.
+type Timestamp = SystemTime;
+type Port = u16;
+
+/// Describes a client. Deriving `EventFields` allows this type to be used as an event field.
+#[derive(EventFields)]
+struct ClientInfo {
+    id: u64,
+    #[event(output = "hex")]
+    flags: u32,
+    address: [u8; 4],
+}
+
+#[derive(EventFields)]
+struct RequestInfo<'a> {
+    url: &'a str,
+    client: ClientInfo,
+    sizes: &'a [u32],
+}
+
+/// Every well-known type can be used in an `Option`.
+#[derive(EventFields)]
+struct OptionalTypes<'a> {
+    a: Option<bool>,
+    b: Option<f64>,
+    c: Option<usize>,
+    d: Option<&'a U16Str>,
+    e: Option<&'a U16CStr>,
+    f: Option<&'a OsStr>,
+    g: Option<&'a SocketAddrV6>,
+    h: Option<&'a SocketAddr>,
+    i: Option<SystemTime>,
+    j: Option<FILETIME>,
+    #[event(output = "hex")]
+    k: Option<u32>,
+    l: Option<i16>,
+    m: Option<&'a [u16]>,
+}
+
+/// Owned strings and paths are borrowed when the struct is written.
+#[derive(EventFields)]
+struct FileInfo {
+    name: String,
+    path: PathBuf,
+    owner: Option<String>,
+}
+
+/// Implementing `EventField` allows a type to be used as an event field. This type is displayed
+/// in hexadecimal.
+struct UserId(u64);
+
+impl EventField for UserId {
+    const IN_TYPE: InFlag = InFlag::UINT64;
+    const OUT_TYPE: OutFlag = OutFlag::HEX;
+
+    fn data_descriptor<'a>(&'a self, _buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        EventDataDescriptor::from(&self.0)
+    }
+}
+
+/// Binary data must be preceded by its length, so it is copied into `buffer`.
+struct Sha256([u8; 32]);
+
+impl EventField for Sha256 {
+    const IN_TYPE: InFlag = InFlag::BINARY;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        counted_bytes(&self.0, buffer)
+    }
+}
+
+/// Deriving `EventEnum` allows this type to be used as an event field. It is encoded as a `u16`.
+#[derive(Clone, Copy, EventEnum)]
+#[repr(u16)]
+enum ConnectionState {
+    Connecting,
+    Connected,
+    Closed = 10,
 }
 
 #[trace_logging_provider(guid = "76d66486-d11a-47a8-af05-88942b6edb55")]
@@ -220,6 +385,18 @@ trait ProviderWithAutogeneratedGuid {
     fn arg_str(arg: &str);
 }
 
+#[trace_logging_provider(
+    guid = "c6b1e6a3-63c6-4f1e-9d0f-5d3bf0b0c1a7",
+    backend = generic,
+    keywords(Work = 0x10)
+)]
+trait GenericBackendProvider {
+    fn arg_u32(a: u32);
+    fn arg_str(arg: &str);
+    #[activity]
+    fn work();
+}
+
 #[trace_logging_provider(name = "Your.Provider.Name")]
 trait ProviderWithAutogeneratedGuidAndCustomProviderName {
     fn arg_str(arg: &str);
@@ -236,10 +413,967 @@ trait ProviderWithCustomGuidAndCustomProviderName {
 #[cfg(test)]
 mod tests {
     use super::{
-        ProviderWithAutogeneratedGuid, ProviderWithAutogeneratedGuidAndCustomProviderName,
-        ProviderWithCustomGuidAndCustomProviderName,
+        GenericBackendProvider, HelloWorldProvider, ProviderWithAutogeneratedGuid,
+        ProviderWithAutogeneratedGuidAndCustomProviderName,
+        ProviderWithCustomGuidAndCustomProviderName, TestManyEvents,
     };
-    use win_etw_provider::guid;
+    use std::sync::Arc;
+    use win_etw_provider::{guid, EventOptions, Level, RecordingProvider};
+
+    #[test]
+    fn recording_provider() {
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        assert!(provider.arg_f32_is_enabled(None));
+
+        provider.arg_i32(None, -5);
+        provider.arg_str(None, "hello");
+        provider.arg_f32(
+            Some(&EventOptions {
+                level: Some(Level::ERROR),
+                ..Default::default()
+            }),
+            1.5,
+        );
+
+        let events = recorder.take_events();
+        assert_eq!(events.len(), 3);
+
+        assert_eq!(events[0].descriptor.level, Level::VERBOSE);
+        assert_eq!(events[0].data, vec![(-5i32).to_le_bytes().to_vec()]);
+        let event_metadata = events[0].event_metadata.as_ref().unwrap();
+        assert_eq!(&event_metadata[3..11], b"arg_i32\0");
+        let provider_metadata = events[0].provider_metadata.as_ref().unwrap();
+        assert_eq!(&provider_metadata[2..], b"HelloWorldProvider\0");
+
+        assert_eq!(
+            events[1].data,
+            vec![5u16.to_le_bytes().to_vec(), b"hello".to_vec()]
+        );
+        assert_eq!(events[1].payload(), b"\x05\x00hello");
+
+        assert_eq!(events[2].descriptor.level, Level::ERROR);
+        assert!(events[2].options.is_some());
+        assert_eq!(events[2].data, vec![1.5f32.to_le_bytes().to_vec()]);
+
+        recorder.set_enabled(false);
+        assert!(!provider.arg_f32_is_enabled(None));
+        provider.arg_f32(None, 2.5);
+        assert!(recorder.events().is_empty());
+    }
+
+    /// Counts the events that reach an `EtwProvider`.
+    #[cfg(target_os = "windows")]
+    struct CountingProvider {
+        etw: win_etw_provider::EtwProvider,
+        writes: std::sync::atomic::AtomicUsize,
+    }
+
+    #[cfg(target_os = "windows")]
+    impl win_etw_provider::Provider for CountingProvider {
+        fn write(
+            &self,
+            options: Option<&EventOptions>,
+            descriptor: &win_etw_provider::EventDescriptor,
+            data: &[win_etw_provider::EventDataDescriptor<'_>],
+        ) {
+            self.writes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            win_etw_provider::Provider::write(&self.etw, options, descriptor, data);
+        }
+
+        fn is_enabled(&self, level: u8, keyword: u64) -> bool {
+            win_etw_provider::Provider::is_enabled(&self.etw, level, keyword)
+        }
+
+        fn is_event_enabled(&self, event_descriptor: &win_etw_provider::EventDescriptor) -> bool {
+            win_etw_provider::Provider::is_event_enabled(&self.etw, event_descriptor)
+        }
+    }
+
+    /// No ETW session has enabled this provider, so `EventProviderEnabled` reports that it is
+    /// disabled, and the event method does not write the event.
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn etw_events_are_not_written_without_a_session() {
+        let counter = Arc::new(CountingProvider {
+            etw: win_etw_provider::EtwProvider::new(&guid!(
+                0x3c5f8e59, 0x5d3b, 0x4b8a, 0x9d, 0x53, 0x0f, 0x1f, 0x5c, 0x1e, 0x0f, 0x7a
+            ))
+            .unwrap(),
+            writes: Default::default(),
+        });
+        let provider = HelloWorldProvider::with_provider(counter.clone());
+        assert!(!provider.arg_f32_is_enabled(None));
+        provider.arg_f32(None, 2.5);
+        assert_eq!(counter.writes.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    /// Disables every event by its event ID, but leaves all levels and keywords enabled, like an
+    /// `EtwProvider` that a session has enabled with an event ID filter.
+    struct EventIdFiltered(RecordingProvider);
+
+    impl win_etw_provider::Provider for EventIdFiltered {
+        fn write(
+            &self,
+            options: Option<&EventOptions>,
+            descriptor: &win_etw_provider::EventDescriptor,
+            data: &[win_etw_provider::EventDataDescriptor<'_>],
+        ) {
+            self.0.write(options, descriptor, data);
+        }
+
+        fn is_enabled(&self, level: u8, keyword: u64) -> bool {
+            self.0.is_enabled(level, keyword)
+        }
+
+        fn is_event_enabled(&self, event_descriptor: &win_etw_provider::EventDescriptor) -> bool {
+            self.0.is_event_enabled(event_descriptor)
+        }
+
+        fn is_event_id_enabled(&self, _id: u16) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn event_id_filter_does_not_drop_events() {
+        use win_etw_provider::GUID;
+
+        let recorder = Arc::new(EventIdFiltered(RecordingProvider::new()));
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        // The event ID filter is only a hint for the `*_is_enabled` methods. ETW applies it to
+        // the session that sent it, so events are still written for other sessions.
+        assert!(!provider.arg_guid_is_enabled(None));
+        provider.arg_guid(None, &GUID::default());
+        assert_eq!(recorder.0.events().len(), 1);
+    }
+
+    #[test]
+    fn version_and_channel() {
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.versioned_event(None, 1);
+        provider.arg_u8(None, 1);
+
+        let events = recorder.take_events();
+        assert_eq!(events[0].descriptor.version, 2);
+        assert_eq!(events[0].descriptor.channel, 17);
+        assert_eq!(events[1].descriptor.version, 0);
+        assert_eq!(events[1].descriptor.channel, 11);
+    }
+
+    #[test]
+    fn named_tasks_opcodes_and_keywords() {
+        use win_etw_provider::metadata::{WINEVENT_OPCODE_START, WINEVENT_OPCODE_STOP};
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.db_query_start(None, "select 1");
+        provider.db_query_stop(None, 1);
+
+        let events = recorder.take_events();
+        assert_eq!(HelloWorldProvider::TASK_CONNECT, 1);
+        assert_eq!(events[0].descriptor.task, HelloWorldProvider::TASK_DB_QUERY);
+        assert_eq!(events[0].descriptor.opcode, WINEVENT_OPCODE_START);
+        assert_eq!(
+            events[0].descriptor.keyword,
+            HelloWorldProvider::KEYWORD_NETWORK | HelloWorldProvider::KEYWORD_STORAGE
+        );
+        assert_eq!(events[1].descriptor.keyword, 0);
+        assert_eq!(events[1].descriptor.task, HelloWorldProvider::TASK_DB_QUERY);
+        assert_eq!(events[1].descriptor.opcode, WINEVENT_OPCODE_STOP);
+    }
+
+    #[test]
+    fn activity() {
+        use win_etw_provider::metadata::{WINEVENT_OPCODE_START, WINEVENT_OPCODE_STOP};
+        use win_etw_provider::{EventOptions, GUID};
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let parent = EventOptions {
+            activity_id: Some(GUID {
+                data1: 42,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let request = provider.request_start(Some(&parent), "/index.html");
+        provider.arg_u8(Some(&request.event_options()), 1);
+        provider.request_stop(request, 200);
+
+        // The stop event has fields, so dropping the guard does not write it.
+        drop(provider.request_start(None, "/favicon.ico"));
+
+        let events = recorder.take_events();
+        assert_eq!(events.len(), 4);
+        let start = &events[0];
+        let start_options = start.options.as_ref().unwrap();
+        assert_eq!(start.descriptor.opcode, WINEVENT_OPCODE_START);
+        assert_eq!(start.descriptor.task, HelloWorldProvider::TASK_CONNECT);
+        assert_eq!(start_options.related_activity_id, parent.activity_id);
+        assert_eq!(
+            events[1].options.as_ref().unwrap().activity_id,
+            start_options.activity_id
+        );
+        let stop = &events[2];
+        assert_eq!(stop.descriptor.opcode, WINEVENT_OPCODE_STOP);
+        assert_eq!(stop.descriptor.task, HelloWorldProvider::TASK_CONNECT);
+        let stop_options = stop.options.as_ref().unwrap();
+        assert_eq!(stop_options.activity_id, start_options.activity_id);
+        assert_eq!(
+            stop_options.related_activity_id,
+            start_options.related_activity_id
+        );
+        assert_eq!(stop.payload(), 200u32.to_le_bytes());
+        assert_eq!(
+            &stop.event_metadata.as_ref().unwrap()[3..16],
+            b"request_stop\0"
+        );
+
+        assert_eq!(events[3].descriptor.opcode, WINEVENT_OPCODE_START);
+    }
+
+    #[win_etw_macros::trace_logging_provider(guid = "6b0c4d0e-2a4b-4e52-9f3e-2d7c1e8a9b51")]
+    trait StopFieldEvents {
+        /// None of the stop field types implement `Default`.
+        #[activity(stop(peer: &std::net::SocketAddr, path: &std::path::Path, bytes: &[u8; 4]))]
+        fn transfer();
+    }
+
+    #[test]
+    fn activity_stop_fields_without_default() {
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = StopFieldEvents::with_provider(recorder.clone());
+        let transfer = provider.transfer_start(None);
+        provider.transfer_stop(
+            transfer,
+            &"127.0.0.1:80".parse().unwrap(),
+            std::path::Path::new("a"),
+            &[1, 2, 3, 4],
+        );
+        drop(provider.transfer_start(None));
+        assert_eq!(recorder.take_events().len(), 3);
+    }
+
+    #[test]
+    fn event_fields() {
+        use super::{ClientInfo, RequestInfo};
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+        use win_etw_provider::metadata::{InFlag, OutFlag};
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let request = RequestInfo {
+            url: "/index.html",
+            client: ClientInfo {
+                id: 1,
+                flags: 2,
+                address: [127, 0, 0, 1],
+            },
+            sizes: &[3, 4],
+        };
+        let client = ClientInfo {
+            id: 5,
+            flags: 6,
+            address: [0; 4],
+        };
+        provider.request_received(None, &request, client);
+
+        let events = recorder.take_events();
+        let event = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        assert_eq!(event.name, "request_received");
+        let request = match event.field("request") {
+            Some(Value::Struct(fields)) => fields,
+            other => panic!("unexpected value {:?}", other),
+        };
+        assert_eq!(request.len(), 3);
+        assert_eq!(request[0].name, "url");
+        assert_eq!(request[0].value, Value::String("/index.html".into()));
+        assert_eq!(request[1].in_type, InFlag::STRUCT);
+        match &request[1].value {
+            Value::Struct(client) => {
+                assert_eq!(client[0].value, Value::U64(1));
+                assert_eq!(client[1].value, Value::U32(2));
+                assert_eq!(client[1].out_type, OutFlag::HEX);
+                assert_eq!(
+                    client[2].value,
+                    Value::Array(vec![
+                        Value::U8(127),
+                        Value::U8(0),
+                        Value::U8(0),
+                        Value::U8(1)
+                    ])
+                );
+            }
+            other => panic!("unexpected value {:?}", other),
+        }
+        assert_eq!(
+            request[2].value,
+            Value::Array(vec![Value::U32(3), Value::U32(4)])
+        );
+        match event.field("client") {
+            Some(Value::Struct(client)) => assert_eq!(client[0].value, Value::U64(5)),
+            other => panic!("unexpected value {:?}", other),
+        }
+    }
+
+    #[test]
+    fn optional_values() {
+        use super::OptionalTypes;
+        use std::net::{Ipv4Addr, SocketAddrV4};
+        use std::time::SystemTime;
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+        use win_etw_provider::GUID;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let id = GUID {
+            data1: 1,
+            ..Default::default()
+        };
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80);
+        provider.optional_values(None, Some("hello"), Some(&id), Some(&address), Some(42));
+        provider.optional_values(None, None, None, None, None);
+
+        let events = recorder.take_events();
+        let metadata = events[0].event_metadata.as_ref().unwrap();
+        let some = decode_event(metadata, &events[0].payload()).unwrap();
+        assert_eq!(some.field("name"), Some(&Value::String("hello".into())));
+        assert_eq!(some.field("has_id"), Some(&Value::U8(1)));
+        assert!(matches!(some.field("id"), Some(Value::Guid(_))));
+        assert!(matches!(some.field("address"), Some(Value::Binary(_))));
+        assert_eq!(some.field("has_count"), Some(&Value::U8(1)));
+        assert_eq!(some.field("count"), Some(&Value::U32(42)));
+
+        // `None` is written as an empty string or address, and as zero for `id` and `count`,
+        // whose `has_id` and `has_count` fields are false. `count` has a fixed size, so it always
+        // has a `has_count` field.
+        let none = events[1].payload();
+        assert_eq!(
+            none,
+            [&[0u8, 0, 0][..], &[0; 16], &[0, 0, 0], &[0; 4]].concat()
+        );
+        let none = decode_event(metadata, &none).unwrap();
+        assert_eq!(none.field("name"), Some(&Value::String("".into())));
+        assert_eq!(none.field("has_count"), Some(&Value::U8(0)));
+        assert_eq!(none.field("count"), Some(&Value::U32(0)));
+
+        provider.optional_types(
+            None,
+            &OptionalTypes {
+                a: Some(true),
+                b: None,
+                c: Some(1),
+                d: None,
+                e: None,
+                f: None,
+                g: None,
+                h: None,
+                i: Some(SystemTime::now()),
+                j: None,
+                k: Some(2),
+                l: None,
+                m: Some(&[3, 4]),
+            },
+        );
+        let events = recorder.take_events();
+        let metadata = events[0].event_metadata.as_ref().unwrap();
+        let types = decode_event(metadata, &events[0].payload()).unwrap();
+        let Value::Struct(members) = types.field("types").unwrap() else {
+            panic!("types is not a struct");
+        };
+        let member = |name: &str| members.iter().find(|m| m.name == name).map(|m| &m.value);
+        assert_eq!(member("has_b"), Some(&Value::U8(0)));
+        assert_eq!(member("has_k"), Some(&Value::U8(1)));
+        assert_eq!(member("k"), Some(&Value::U32(2)));
+        assert_eq!(
+            member("m"),
+            Some(&Value::Array(vec![Value::U16(3), Value::U16(4)]))
+        );
+    }
+
+    #[test]
+    fn fixed_arrays() {
+        use win_etw_provider::metadata::decode::{decode_event, parse_event_metadata, Value};
+        use win_etw_provider::metadata::{InFlag, OutFlag};
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.fixed_arrays(None, &[7; 32], [1, 2, 3, 4, 5, 6], [8, 9]);
+
+        let events = recorder.take_events();
+        let metadata = parse_event_metadata(events[0].event_metadata.as_ref().unwrap()).unwrap();
+        assert_eq!(
+            metadata.fields[0].in_type,
+            InFlag::UINT8 | InFlag::CCOUNT_FLAG
+        );
+        assert_eq!(metadata.fields[0].count, Some(32));
+        assert_eq!(metadata.fields[2].count, Some(2));
+        assert_eq!(metadata.fields[2].out_type, OutFlag::HEX);
+
+        // The payload contains only the elements, without a length prefix.
+        let payload = events[0].payload();
+        assert_eq!(payload.len(), 32 + 6 + 8);
+        let event = decode_event(events[0].event_metadata.as_ref().unwrap(), &payload).unwrap();
+        assert_eq!(
+            event.field("mac"),
+            Some(&Value::Array((1..=6).map(Value::U8).collect()))
+        );
+        assert_eq!(
+            event.field("words"),
+            Some(&Value::Array(vec![Value::U32(8), Value::U32(9)]))
+        );
+    }
+
+    #[test]
+    fn string_arrays() {
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+        use win_etw_provider::GUID;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let ids = [
+            GUID {
+                data1: 1,
+                ..Default::default()
+            },
+            GUID {
+                data1: 2,
+                ..Default::default()
+            },
+        ];
+        provider.string_arrays(
+            None,
+            &["a.example", "b.example"],
+            &["fast".to_string()],
+            &ids,
+            None,
+        );
+
+        let events = recorder.take_events();
+        let event = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        assert_eq!(
+            event.field("hosts"),
+            Some(&Value::Array(vec![
+                Value::String("a.example".into()),
+                Value::String("b.example".into()),
+            ]))
+        );
+        assert_eq!(
+            event.field("features"),
+            Some(&Value::Array(vec![Value::String("fast".into())]))
+        );
+        match event.field("ids") {
+            Some(Value::Array(ids)) => {
+                assert_eq!(ids.len(), 2);
+                assert!(matches!(ids[1], Value::Guid(_)));
+            }
+            other => panic!("unexpected value {:?}", other),
+        }
+        assert_eq!(event.field("aliases"), Some(&Value::Array(vec![])));
+    }
+
+    #[test]
+    fn qualified_types_and_aliases() {
+        use std::net::{Ipv4Addr, SocketAddr};
+        use std::time::{Duration, UNIX_EPOCH};
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+        use win_etw_provider::GUID;
+
+        // The number of 100ns intervals between the FILETIME epoch and the Unix epoch.
+        const UNIX_EPOCH_FILETIME: u64 = 116_444_736_000_000_000;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+        provider.qualified_types(
+            None,
+            UNIX_EPOCH,
+            &addr,
+            &GUID::default(),
+            UNIX_EPOCH + Duration::from_secs(1),
+            443,
+        );
+
+        let events = recorder.take_events();
+        let event = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        assert_eq!(
+            event.field("time"),
+            Some(&Value::FileTime(UNIX_EPOCH_FILETIME))
+        );
+        assert!(matches!(event.field("addr"), Some(Value::Binary(b)) if b.len() == 16));
+        assert_eq!(event.field("id"), Some(&Value::Guid([0; 16])));
+        assert_eq!(
+            event.field("started"),
+            Some(&Value::FileTime(UNIX_EPOCH_FILETIME + 10_000_000))
+        );
+        assert_eq!(event.field("port"), Some(&Value::U16(443)));
+    }
+
+    #[test]
+    fn custom_field_types() {
+        use super::{Sha256, UserId};
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+        use win_etw_provider::metadata::OutFlag;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.user_logged_in(None, UserId(0x1234), &Sha256([0xab; 32]));
+
+        let events = recorder.take_events();
+        let event = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        assert_eq!(event.field("user"), Some(&Value::U64(0x1234)));
+        assert_eq!(event.fields[0].out_type, OutFlag::HEX);
+        assert_eq!(
+            event.field("password_hash"),
+            Some(&Value::Binary(vec![0xab; 32]))
+        );
+    }
+
+    #[test]
+    fn output_formats() {
+        use std::net::Ipv4Addr;
+        use std::time::UNIX_EPOCH;
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+        use win_etw_provider::metadata::OutFlag;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.listener_started(
+            None,
+            1234,
+            u32::from(Ipv4Addr::new(10, 0, 0, 1)),
+            443,
+            r#"{"threads":4}"#,
+            UNIX_EPOCH,
+        );
+
+        let events = recorder.take_events();
+        let event = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        let out_types: Vec<OutFlag> = event.fields.iter().map(|f| f.out_type).collect();
+        assert_eq!(
+            out_types,
+            [
+                OutFlag::PID,
+                OutFlag::IPV4,
+                OutFlag::PORT,
+                OutFlag::JSON,
+                OutFlag::DATETIME_UTC
+            ]
+        );
+        assert_eq!(event.field("process"), Some(&Value::U32(1234)));
+        // Addresses and ports are written in big-endian byte order.
+        assert_eq!(
+            event.field("address"),
+            Some(&Value::U32(u32::from_le_bytes([10, 0, 0, 1])))
+        );
+        assert_eq!(event.field("port"), Some(&Value::U16(443u16.to_be())));
+        assert_eq!(
+            event.field("config"),
+            Some(&Value::String(r#"{"threads":4}"#.into()))
+        );
+    }
+
+    #[test]
+    fn ip_addresses() {
+        use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+        use win_etw_provider::metadata::OutFlag;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let v4 = Ipv4Addr::new(10, 0, 0, 1);
+        provider.peer_addresses(None, v4, Ipv6Addr::LOCALHOST, IpAddr::V4(v4), None);
+
+        let events = recorder.take_events();
+        let event = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        let out_types: Vec<OutFlag> = event.fields.iter().map(|f| f.out_type).collect();
+        assert_eq!(
+            out_types,
+            [OutFlag::IPV4, OutFlag::IPV6, OutFlag::IPV6, OutFlag::IPV6]
+        );
+        // Addresses are written in big-endian byte order.
+        assert_eq!(
+            event.field("v4"),
+            Some(&Value::U32(u32::from_le_bytes([10, 0, 0, 1])))
+        );
+        assert_eq!(
+            event.field("v6"),
+            Some(&Value::Binary(Ipv6Addr::LOCALHOST.octets().to_vec()))
+        );
+        // IPv4 addresses in an `IpAddr` are written as IPv4-mapped IPv6 addresses.
+        assert_eq!(
+            event.field("mapped"),
+            Some(&Value::Binary(v4.to_ipv6_mapped().octets().to_vec()))
+        );
+        assert_eq!(event.field("missing"), Some(&Value::Binary(Vec::new())));
+    }
+
+    #[test]
+    fn owned_strings_and_paths() {
+        use super::FileInfo;
+        use std::borrow::Cow;
+        use std::path::{Path, PathBuf};
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.owned_strings(
+            None,
+            "a".to_string(),
+            &"b".to_string(),
+            Cow::Borrowed("c"),
+            "d".into(),
+            "e",
+        );
+        let file = FileInfo {
+            name: "hello.txt".into(),
+            path: PathBuf::from("/tmp/hello.txt"),
+            owner: Some("root".into()),
+        };
+        provider.file_opened(None, Path::new("/tmp"), PathBuf::from("/var"), &file);
+
+        let events = recorder.take_events();
+        let strings = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        for name in ["a", "b", "c", "d", "e"].iter() {
+            assert_eq!(strings.field(name), Some(&Value::String(name.to_string())));
+        }
+
+        // Paths are UTF-16 strings on Windows and UTF-8 strings elsewhere. Both decode as strings.
+        let paths = decode_event(
+            events[1].event_metadata.as_ref().unwrap(),
+            &events[1].payload(),
+        )
+        .unwrap();
+        assert_eq!(paths.field("path"), Some(&Value::String("/tmp".into())));
+        assert_eq!(paths.field("dir"), Some(&Value::String("/var".into())));
+        match paths.field("file") {
+            Some(Value::Struct(fields)) => {
+                assert_eq!(fields[0].value, Value::String("hello.txt".into()));
+                assert_eq!(fields[1].value, Value::String("/tmp/hello.txt".into()));
+                assert_eq!(fields[2].value, Value::String("root".into()));
+            }
+            other => panic!("unexpected value {:?}", other),
+        }
+    }
+
+    #[test]
+    fn optional_strings_and_paths() {
+        use std::borrow::Cow;
+        use std::path::{Path, PathBuf};
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.optional_strings(
+            None,
+            Some("a".to_string()),
+            Some(&"b".to_string()),
+            Some(Cow::Borrowed("c")),
+            Some(Path::new("/tmp")),
+            Some(PathBuf::from("/var")),
+        );
+        provider.optional_strings(None, None, None, None, None, None);
+
+        let events = recorder.take_events();
+        let some = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        for name in ["a", "b", "c"].iter() {
+            assert_eq!(some.field(name), Some(&Value::String(name.to_string())));
+        }
+        assert_eq!(some.field("has_c"), Some(&Value::U8(1)));
+        assert_eq!(some.field("path"), Some(&Value::String("/tmp".into())));
+        assert_eq!(some.field("dir"), Some(&Value::String("/var".into())));
+
+        // `None` is written as an empty string.
+        let none = decode_event(
+            events[1].event_metadata.as_ref().unwrap(),
+            &events[1].payload(),
+        )
+        .unwrap();
+        for name in ["a", "b", "c", "path", "dir"].iter() {
+            assert_eq!(none.field(name), Some(&Value::String(String::new())));
+        }
+        assert_eq!(none.field("has_c"), Some(&Value::U8(0)));
+    }
+
+    #[test]
+    fn event_enum() {
+        use super::ConnectionState;
+        use win_etw_provider::metadata::decode::{decode_event, Value};
+        use win_etw_provider::metadata::InFlag;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.connection_state_changed(
+            None,
+            ConnectionState::Closed,
+            ConnectionState::Connected,
+        );
+
+        let events = recorder.take_events();
+        let event = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        assert_eq!(event.fields.len(), 3);
+        assert_eq!(event.fields[0].in_type, InFlag::UINT16);
+        assert_eq!(event.field("state"), Some(&Value::U16(10)));
+        assert_eq!(
+            event.field("state_name"),
+            Some(&Value::String("Closed".into()))
+        );
+        assert_eq!(event.field("previous"), Some(&Value::U16(1)));
+    }
+
+    #[test]
+    fn activity_scope() {
+        use win_etw_provider::{with_activity_id, EventOptions, GUID};
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let scope_id = GUID {
+            data1: 7,
+            ..Default::default()
+        };
+        let other_id = GUID {
+            data1: 8,
+            ..Default::default()
+        };
+
+        provider.arg_u8(None, 1);
+        with_activity_id(&scope_id, || {
+            provider.arg_u8(None, 2);
+            provider.arg_u8(
+                Some(&EventOptions {
+                    activity_id: Some(other_id.clone()),
+                    ..Default::default()
+                }),
+                3,
+            );
+            provider.request_start(None, "/").finish();
+        });
+
+        let events = recorder.take_events();
+        let activity_ids: Vec<Option<GUID>> = events
+            .iter()
+            .map(|e| e.options.as_ref().and_then(|o| o.activity_id.clone()))
+            .collect();
+        assert_eq!(activity_ids[0], None);
+        assert_eq!(activity_ids[1], Some(scope_id.clone()));
+        assert_eq!(activity_ids[2], Some(other_id));
+        // A new activity started in the scope is related to the scoped activity.
+        assert_eq!(
+            events[3].options.as_ref().unwrap().related_activity_id,
+            Some(scope_id)
+        );
+    }
+
+    #[test]
+    fn capture_state() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use win_etw_provider::CaptureStateRequest;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let keywords = Arc::new(AtomicU64::new(0));
+        let k = keywords.clone();
+        provider.on_capture_state(move |request| {
+            k.store(request.match_any_keyword, Ordering::SeqCst);
+        });
+
+        recorder.request_capture_state(&CaptureStateRequest {
+            source_id: Default::default(),
+            level: Level::INFO,
+            match_any_keyword: 0x42,
+            match_all_keyword: 0,
+        });
+        assert_eq!(keywords.load(Ordering::SeqCst), 0x42);
+
+        // A null provider ignores the callback.
+        HelloWorldProvider::null().on_capture_state(|_| panic!());
+    }
+
+    #[test]
+    fn enable_state_change() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use win_etw_provider::EnableState;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        let sampling = Arc::new(AtomicBool::new(false));
+        let s = sampling.clone();
+        provider.on_enable_state_change(move |state| {
+            s.store(state.enabled, Ordering::SeqCst);
+        });
+
+        recorder.change_enable_state(&EnableState {
+            enabled: true,
+            level: Level::VERBOSE,
+            match_any_keyword: u64::MAX,
+            ..Default::default()
+        });
+        assert!(sampling.load(Ordering::SeqCst));
+        assert!(provider.arg_f32_is_enabled(None));
+
+        recorder.change_enable_state(&EnableState::default());
+        assert!(!sampling.load(Ordering::SeqCst));
+        assert!(!provider.arg_f32_is_enabled(None));
+    }
+
+    #[test]
+    fn decode_recorded_events() {
+        use win_etw_metadata::decode::{decode_event, parse_provider_metadata, Value};
+        use win_etw_metadata::OutFlag;
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = TestManyEvents::with_provider(recorder.clone());
+        provider.arg_none(None);
+        provider.arg_bool(None, true);
+        provider.arg_i16(None, -300);
+        provider.arg_u64(None, 1 << 40);
+        provider.arg_f64(None, 2.5);
+        provider.arg_slice_u16(None, &[1, 2, 3]);
+        provider.arg_str(None, "hello");
+        provider.arg_guid(None, &super::EXAMPLE_GUID);
+        provider.arg_filetime(None, win_etw_provider::FILETIME(1234));
+        provider.arg_u32_hex(None, 0xcafef00d);
+
+        let events = recorder.take_events();
+        let decoded: Vec<_> = events
+            .iter()
+            .map(|e| decode_event(e.event_metadata.as_ref().unwrap(), &e.payload()).unwrap())
+            .collect();
+
+        let provider_metadata =
+            parse_provider_metadata(events[0].provider_metadata.as_ref().unwrap()).unwrap();
+        assert_eq!(provider_metadata.name, "TestManyEvents");
+
+        assert_eq!(decoded[0].name, "arg_none");
+        assert!(decoded[0].fields.is_empty());
+        assert_eq!(decoded[1].field("a"), Some(&Value::U8(1)));
+        assert_eq!(decoded[1].fields[0].out_type, OutFlag::BOOLEAN);
+        assert_eq!(decoded[2].field("a"), Some(&Value::I16(-300)));
+        assert_eq!(decoded[3].field("a"), Some(&Value::U64(1 << 40)));
+        assert_eq!(decoded[4].field("a"), Some(&Value::F64(2.5)));
+        assert_eq!(
+            decoded[5].field("a"),
+            Some(&Value::Array(vec![
+                Value::U16(1),
+                Value::U16(2),
+                Value::U16(3)
+            ]))
+        );
+        assert_eq!(
+            decoded[6].field("arg"),
+            Some(&Value::String("hello".into()))
+        );
+        match decoded[7].field("arg") {
+            Some(Value::Guid(bytes)) => assert_eq!(&bytes[..4], &0x861a3948u32.to_le_bytes()),
+            other => panic!("unexpected value: {:?}", other),
+        }
+        assert_eq!(decoded[8].field("a"), Some(&Value::FileTime(1234)));
+        assert_eq!(decoded[9].field("a"), Some(&Value::U32(0xcafef00d)));
+        assert_eq!(decoded[9].fields[0].out_type, OutFlag::HEX);
+    }
+
+    #[test]
+    fn system_time_followed_by_other_fields() {
+        use std::time::{Duration, SystemTime};
+        use win_etw_metadata::decode::{decode_event, Value};
+
+        let recorder = Arc::new(RecordingProvider::new());
+        let provider = HelloWorldProvider::with_provider(recorder.clone());
+        provider.file_modified(None, SystemTime::UNIX_EPOCH + Duration::from_secs(1), 42);
+
+        let events = recorder.take_events();
+        let event = decode_event(
+            events[0].event_metadata.as_ref().unwrap(),
+            &events[0].payload(),
+        )
+        .unwrap();
+        // The Unix epoch is 11644473600 seconds after the FILETIME epoch.
+        assert_eq!(
+            event.field("modify_time"),
+            Some(&Value::FileTime((11644473600 + 1) * 10_000_000))
+        );
+        assert_eq!(event.field("size"), Some(&Value::U64(42)));
+    }
+
+    #[test]
+    fn generic_backend() {
+        let recorder = RecordingProvider::new();
+        let provider = GenericBackendProvider::with_provider(&recorder);
+        assert!(provider.provider().is_some());
+        provider.arg_u32(None, 7);
+        provider.arg_str(None, "hi");
+
+        provider.work_start(None).finish();
+
+        let events = recorder.take_events();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].payload(), 7u32.to_le_bytes());
+        assert_eq!(events[1].payload(), b"\x02\x00hi");
+        let provider_metadata = events[0].provider_metadata.as_ref().unwrap();
+        assert_eq!(&provider_metadata[2..], b"GenericBackendProvider\0");
+
+        // The default type parameter is EtwProvider.
+        let null: GenericBackendProvider = GenericBackendProvider::null();
+        null.arg_u32(None, 1);
+        assert_eq!(
+            <GenericBackendProvider>::PROVIDER_NAME,
+            "GenericBackendProvider"
+        );
+
+        // The constants are defined for every provider type.
+        assert_eq!(
+            GenericBackendProvider::<RecordingProvider>::PROVIDER_NAME,
+            "GenericBackendProvider"
+        );
+        assert_eq!(
+            GenericBackendProvider::<&RecordingProvider>::PROVIDER_GUID,
+            <GenericBackendProvider>::PROVIDER_GUID
+        );
+        assert_eq!(
+            GenericBackendProvider::<RecordingProvider>::KEYWORD_WORK,
+            0x10
+        );
+    }
 
     #[test]
     fn provider_with_autogenerated_guid() {
@@ -4,7 +4,8 @@
 //! framework.
 //!
 //! This macro is intended for use only when targeting Windows. When targeting other platforms,
-//! this macro will still work, but will generate code that does nothing.
+//! this macro will still work, but events are discarded unless the provider is created with
+//! `with_provider` (see below).
 //!
 //! This framework allows applications to log schematized events, rather than textual strings.
 //! ETW analysis tools can reliably identify fields within your events, and treat them as
@@ -92,10 +93,102 @@
 //! process. Typically, an instance is stored in static variable, using a lazy / atomic assignment.
 //! There are many crates and types which can support this usage pattern.
 //!
+//! ### Testing event providers
+//! `MyAppEvents::with_provider(...)` creates an instance that sends its events to any type that
+//! implements `win_etw_provider::Provider`, on any platform. Unit tests can use
+//! `win_etw_provider::RecordingProvider` (which requires the `std` feature of `win_etw_provider`)
+//! to capture events and check their field values:
+//!
+//! ```rust,ignore
+//! let recorder = std::sync::Arc::new(win_etw_provider::RecordingProvider::new());
+//! let my_app_events = MyAppEvents::with_provider(recorder.clone());
+//! my_app_events.database_connection_closed(None, 42);
+//! assert_eq!(recorder.events()[0].data[0], 42u64.to_le_bytes());
+//! ```
+//!
+//! ### Choosing a provider backend
+//! By default, the generated type stores its provider as a `win_etw_provider::AnyProvider`, so
+//! `with_provider` boxes the provider that you pass to it. Specify `backend = generic` to make the
+//! generated type generic over `P: win_etw_provider::Provider` instead:
+//!
+//! ```rust,ignore
+//! #[trace_logging_provider(backend = generic)]
+//! pub trait MyAppEvents {
+//!     fn database_connection_closed(connection_id: u64);
+//! }
+//!
+//! let recorder = win_etw_provider::RecordingProvider::new();
+//! let my_app_events = MyAppEvents::with_provider(&recorder);
+//! ```
+//!
+//! The type parameter defaults to `EtwProvider`, so `MyAppEvents::new()` and the `MyAppEvents` type
+//! continue to work as before. The `PROVIDER_GUID`, `PROVIDER_NAME`, task, and keyword constants
+//! are defined for every `P`, such as `MyAppEvents::<RecordingProvider>::PROVIDER_NAME`. Rust does
+//! not use the default type parameter in expressions, so write `<MyAppEvents>::PROVIDER_NAME` to
+//! use the default.
+//!
+//! ### Linux user_events
+//! On Linux, `win_etw_provider::UserEventsProvider` (which requires the `std` feature) writes events
+//! to the kernel's [`user_events`](https://docs.kernel.org/trace/user_events.html) facility, using
+//! the [EventHeader](https://github.com/microsoft/LinuxTracepoints) format. The field metadata
+//! of each event is converted to EventHeader metadata, so tools such as `perf` can decode the fields.
+//!
+//! ```rust,ignore
+//! let my_app_events = MyAppEvents::with_provider(
+//!     win_etw_provider::UserEventsProvider::new(MyAppEvents::PROVIDER_NAME)?,
+//! );
+//! ```
+//!
+//! Events are written to tracepoints named `<provider>_L<level>K<keyword>`, such as
+//! `user_events:MyAppEvents_L4K0`.
+//!
+//! ### Writing events to a file
+//! `win_etw_provider::FileProvider` (which requires the `std` feature) appends events to a portable
+//! trace file, on any platform and without an ETW session. Each record contains the event
+//! metadata, so `win_etw_provider::FileReader` and `win_etw_metadata::decode` can read the file
+//! without the provider definitions.
+//!
+//! ```rust,ignore
+//! let my_app_events = MyAppEvents::with_provider(FileProvider::create("events.trace")?);
+//! ```
+//!
+//! ### Capturing state
+//! ETW controllers can ask a provider to _capture its state_, for example to write a "rundown" of
+//! the objects that an application has open when a trace starts. Use `on_capture_state` to
+//! handle these requests:
+//!
+//! ```rust,ignore
+//! my_app_events.on_capture_state(|request| {
+//!     // Write events that describe the current state of the application.
+//! });
+//! ```
+//!
+//! The function is called on a thread that is owned by ETW. `RecordingProvider::request_capture_state`
+//! simulates a request in unit tests.
+//!
+//! ### Reacting to sessions
+//! Use `on_enable_state_change` to start expensive instrumentation, such as sampling threads, only
+//! while a session is listening. The function receives a `win_etw_provider::EnableState`, which
+//! describes the level and keywords that are enabled:
+//!
+//! ```rust,ignore
+//! my_app_events.on_enable_state_change(|state| {
+//!     if state.enabled {
+//!         // Start sampling.
+//!     } else {
+//!         // Stop sampling.
+//!     }
+//! });
+//! ```
+//!
+//! `RecordingProvider::change_enable_state` simulates a change in unit tests.
+//!
 //! ## Call event methods to report events
 //! To report an event, call one of the methods defined on the event provider. The method will
 //! call into ETW to report the event, but there is no guarantee that the event is stored or
-//! forwarded; events can be dropped if event buffer resources are scarce.
+//! forwarded; events can be dropped if event buffer resources are scarce. If no session has
+//! enabled the level and keyword of the event, then the method returns without encoding its
+//! fields.
 //!
 //! ```rust,ignore
 //! my_app_events.client_connected(&"192.168.0.42:6667".parse(), false, 100, "OK");
@@ -108,16 +201,191 @@
 //! * Floating point primitives: `f32`, `f64`
 //! * Architecture-dependent sizes: `usize`, `isize`.
 //! * Boolean: `bool`
+//! * Strings: `&str`, `String`, `&String`, `Box<str>`, `Cow<str>`, and `impl AsRef<str>`, which are
+//!   encoded as counted UTF-8 strings, and `&U16Str`, `&U16CStr`, and `&OsStr`, which are encoded as
+//!   counted UTF-16 strings.
+//! * Paths: `&Path`, `&PathBuf`, `PathBuf`, and `impl AsRef<Path>`. Paths are encoded as counted
+//!   UTF-16 strings on Windows, and as counted UTF-8 strings on other platforms.
+//! * GUIDs: `&GUID`
 //! * Slices of all of the supported primitives, except for bool: `&[u8]`, `&[u16]`, etc.
 //!   `&[bool]` is not supported because `bool` does not have a guaranteed stable representation.
+//! * Fixed-size arrays of the supported primitives: `[u8; 32]`, `&[u16; 4]`, etc. The length of
+//!   the array is stored in the event metadata, rather than in each event. It cannot be larger
+//!   than 65535.
+//! * Slices of strings and GUIDs: `&[&str]`, `&[String]`, and `&[GUID]`.
 //! * Windows `[FILETIME](https://docs.microsoft.com/en-us/windows/win32/api/minwinbase/ns-minwinbase-filetime)`.
-//!   The type must be declared _exactly_ as `FILETIME`; type aliases or fully-qualified paths
-//!   (such as `windows_sys::Win32::Foundation::FILETIME`) _will not work_. The parameter type in the
-//!   generated code will be `win_etw_provider::FILETIME`, which is a newtype over `u64`.
-//! * `std::time::SystemTime` is supported, but it must be declared _exactly_ as `SystemTime`;
-//!   type aliases or fully-qualified paths (such as `std::time::SystemTime`) _will not work_.
-//! * `SockAddr`, `SockAddrV4`, and `SockAddrV6` are supported. They must be declared exactly as
-//!   shown, not using fully-qualified names or type aliases.
+//!   The type can be declared as `FILETIME`, `win_etw_provider::FILETIME`, or
+//!   `windows_sys::Win32::Foundation::FILETIME`. The parameter type in the generated code will be
+//!   `win_etw_provider::FILETIME`, which is a newtype over `u64`.
+//! * `std::time::SystemTime`.
+//! * `&SocketAddr`, `&SocketAddrV4`, and `&SocketAddrV6`, from `std::net` or `core::net`.
+//! * `Ipv4Addr`, `Ipv6Addr`, and `IpAddr`, from `std::net` or `core::net`. `Ipv4Addr` is written as
+//!   a big-endian `u32`, and `Ipv6Addr` as binary data, with the `ipv4` and `ipv6` output formats.
+//!   `IpAddr` is always written as an IPv6 address; IPv4 addresses are converted to IPv4-mapped
+//!   IPv6 addresses, such as `::ffff:10.0.0.1`.
+//! * Structs that derive `EventFields`. See below.
+//! * Fieldless enums that derive `EventEnum`. See below.
+//! * `Option<T>` of the types above. See below.
+//! * Other types that implement `EventField`. See below.
+//!
+//! Well-known types are recognized by their names, such as `SystemTime`, or by their
+//! fully-qualified paths, such as `std::time::SystemTime`, `core::net::SocketAddr`,
+//! `win_etw_provider::GUID`, and `windows_sys::core::GUID`. The parameter type in the generated code
+//! for `windows_sys::core::GUID` is `win_etw_provider::GUID`, which can be converted from it.
+//!
+//! A macro cannot resolve type aliases, or other types that it does not recognize by name. Such
+//! types must implement the `win_etw_provider::EventField` trait, which provides the type metadata
+//! and the data of the field. `EventField` is implemented for the well-known types, so aliases of
+//! them, such as `type Timestamp = SystemTime;`, can be used. It is also implemented by
+//! `#[derive(EventFields)]` and `#[derive(EventEnum)]`.
+//!
+//! ## Output formats
+//!
+//! The `#[event(output = "...")]` attribute of a field specifies how trace consumers should display
+//! it. The format must be compatible with the type of the field; otherwise, a compile error is
+//! reported.
+//!
+//! | Format               | Field types                                                   |
+//! |----------------------|---------------------------------------------------------------|
+//! | `hex`                | Integers, and slices and arrays of integers                   |
+//! | `signed`, `unsigned` | Integers, and slices and arrays of integers                   |
+//! | `pid`, `tid`         | `u32` and `i32`, and slices and arrays of them                |
+//! | `port`               | `u16`                                                         |
+//! | `ipv4`               | `u32`                                                         |
+//! | `ipv6`               | `&[u8]`                                                       |
+//! | `json`, `xml`        | Strings, and slices of strings                                |
+//! | `utc`                | `SystemTime` and `FILETIME`                                   |
+//!
+//! Ports and IPv4 addresses are converted to big-endian (network) byte order when they are written,
+//! so pass them in native byte order, for example `u32::from(Ipv4Addr::LOCALHOST)`.
+//!
+//! ```ignore
+//! #[trace_logging_provider]
+//! trait MyAppEvents {
+//!     fn listening(#[event(output = "ipv4")] address: u32, #[event(output = "port")] port: u16);
+//! }
+//! ```
+//!
+//! ## Structs as event fields
+//!
+//! A struct with named fields can be used as an event field by deriving `EventFields`. The struct
+//! is encoded as a TraceLogging structure: a named group of fields, which trace consumers display
+//! as a nested object. Each member of the struct must have a supported field type, including other
+//! types that derive `EventFields`. A member may use `#[event(output = "hex")]` and other output
+//! formats, just like an event parameter.
+//!
+//! ```ignore
+//! use win_etw_macros::{trace_logging_provider, EventFields};
+//!
+//! #[derive(EventFields)]
+//! struct RequestInfo<'a> {
+//!     url: &'a str,
+//!     #[event(output = "hex")]
+//!     flags: u32,
+//! }
+//!
+//! #[trace_logging_provider]
+//! trait MyAppEvents {
+//!     fn request_received(request: &RequestInfo, size: u32);
+//! }
+//! ```
+//!
+//! An event parameter whose type is a struct may be declared as `T` or as `&T`. Arrays of structs
+//! are not supported.
+//!
+//! ## Enums as event fields
+//!
+//! A fieldless enum can be used as an event field by deriving `EventEnum`. The enum must have a
+//! `#[repr]` attribute that specifies an integer type, such as `#[repr(u32)]`, and it is encoded as
+//! a value of that type. To also write the name of the variant, add `#[event(variant_name)]` to the
+//! parameter. This writes a string field, whose name is the parameter name followed by `_name`,
+//! after the value.
+//!
+//! ```ignore
+//! use win_etw_macros::{trace_logging_provider, EventEnum};
+//!
+//! #[derive(Clone, Copy, EventEnum)]
+//! #[repr(u32)]
+//! enum ConnectionState {
+//!     Connecting,
+//!     Connected,
+//!     Closed,
+//! }
+//!
+//! #[trace_logging_provider]
+//! trait MyAppEvents {
+//!     // Writes the fields `state` and `state_name`.
+//!     fn state_changed(#[event(variant_name)] state: ConnectionState);
+//! }
+//! ```
+//!
+//! ## Optional fields
+//!
+//! `Option<T>` can be used for primitives, `&str`, `&U16Str`, `&U16CStr`, `&OsStr`, `&GUID`,
+//! `FILETIME`, `SystemTime`, socket addresses, IP addresses, slices, and the owned string and
+//! path types above, such as `Option<String>` and `Option<&Path>`. For strings, paths, slices,
+//! socket addresses, and IPv6 addresses, which are written with a length prefix, `None` is written
+//! as a value with a length of zero.
+//!
+//! Other types, such as integers and GUIDs, have a fixed size, so `None` cannot be written as an
+//! empty value. For these types, a `bool` field whose name is `has_` followed by the parameter
+//! name is written before the field. When the value is `None`, the field contains a placeholder
+//! value, such as zero. Add `#[event(has_field)]` to a parameter to write this field for the other
+//! types as well, for example to distinguish `None` from an empty string.
+//!
+//! ```ignore
+//! #[trace_logging_provider]
+//! trait MyAppEvents {
+//!     // Writes the fields `user`, `has_nickname`, `nickname`, `has_session_id`, and `session_id`.
+//!     fn login(
+//!         user: Option<&str>,
+//!         #[event(has_field)] nickname: Option<&str>,
+//!         session_id: Option<u64>,
+//!     );
+//! }
+//! ```
+//!
+//! ## Custom field types
+//!
+//! Other types can be used as event fields by implementing the `win_etw_provider::EventField`
+//! trait. `IN_TYPE` specifies how the data of the field is encoded, `OUT_TYPE` specifies how trace
+//! consumers should display it, and `data_descriptor` returns the data of the field. If the data
+//! must be converted or copied, such as data that is preceded by its length, then it is stored in
+//! `buffer`; `counted_bytes` does this for `InFlag::BINARY` data.
+//!
+//! ```ignore
+//! use win_etw_provider::metadata::{InFlag, OutFlag};
+//! use win_etw_provider::{counted_bytes, EventDataDescriptor, EventField};
+//!
+//! struct UserId(u64);
+//!
+//! impl EventField for UserId {
+//!     const IN_TYPE: InFlag = InFlag::UINT64;
+//!     const OUT_TYPE: OutFlag = OutFlag::HEX;
+//!
+//!     fn data_descriptor<'a>(&'a self, _buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+//!         EventDataDescriptor::from(&self.0)
+//!     }
+//! }
+//!
+//! struct Sha256([u8; 32]);
+//!
+//! impl EventField for Sha256 {
+//!     const IN_TYPE: InFlag = InFlag::BINARY;
+//!
+//!     fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+//!         counted_bytes(&self.0, buffer)
+//!     }
+//! }
+//!
+//! #[trace_logging_provider]
+//! trait MyAppEvents {
+//!     fn login(user: UserId, password_hash: &Sha256);
+//! }
+//! ```
+//!
+//! Output formats such as `#[event(output = "hex")]` cannot be used with these fields; use
+//! `OUT_TYPE` instead.
 //!
 //! # Provider groups
 //!
@@ -148,15 +416,95 @@
 //!   See [EVENT_DESCRIPTOR]::Level.
 //!   This can either be a numeric value, or one of the following literal strings:
 //!   `"critical"`, `"error"`, `"warn"`, `"info"`, `"verbose"`.
-//! * `#[event(opcode = NN)]` - Specifies the [EVENT_DESCRIPTOR]::Opcode field.
-//! * `#[event(task = NN)` - Specifies the [EVENT_DESCRIPTOR]::Task field.
+//! * `#[event(opcode = NN)]` or `#[event(opcode = "...")]` - Specifies the
+//!   [EVENT_DESCRIPTOR]::Opcode field. This can either be a numeric value, or one of the
+//!   following literal strings, which select the standard `WINEVENT_OPCODE_*` values:
+//!   `"info"`, `"start"`, `"stop"`, `"dc_start"`, `"dc_stop"`, `"extension"`, `"reply"`,
+//!   `"resume"`, `"suspend"`, `"send"`, `"receive"`.
+//! * `#[event(task = NN)]` or `#[event(task = "...")]` - Specifies the [EVENT_DESCRIPTOR]::Task
+//!   field. This can either be a numeric value, or the name of a task that is declared by the
+//!   provider (see below).
 //! * `#[event(keyword = NN)` - Specifies the [EVENT_DESCRIPTOR]::Keyword field.
+//! * `#[event(keywords("...", "..."))]` - Specifies the [EVENT_DESCRIPTOR]::Keyword field as the
+//!   combination of keywords that are declared by the provider (see below). This cannot be
+//!   combined with `keyword`.
+//! * `#[event(version = NN)]` - Specifies the [EVENT_DESCRIPTOR]::Version field. Increment the
+//!   version when you change the fields of an event. The default is 0.
+//! * `#[event(channel = NN)]` or `#[event(channel = "...")]` - Specifies the
+//!   [EVENT_DESCRIPTOR]::Channel field. This can either be a numeric value, or one of the
+//!   following literal strings: `"tracelogging"` (11), `"admin"` (16), `"operational"` (17),
+//!   `"analytic"` (18), `"debug"` (19). The default is 11, which identifies TraceLogging events;
+//!   only use another channel if the consumers of your events support it.
 //!
 //! [EVENT_DESCRIPTOR]: https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_descriptor
 //!
 //! You can use a single `#[event]` attribute with multiple values, or you can use
 //! multiple `#[event]` attributes.
 //!
+//! ## Tasks and keywords
+//!
+//! A provider can declare named tasks and keywords, and events can refer to them by name. For
+//! each task and keyword, the generated type has a constant, such as `MyEvents::TASK_DB_QUERY`
+//! or `MyEvents::KEYWORD_NETWORK`:
+//!
+//! ```no_test
+//! #[trace_logging_provider(
+//!     tasks(Connect = 1, DbQuery = 2),
+//!     keywords(Network = 0x1, Storage = 0x2)
+//! )]
+//! pub trait MyEvents {
+//!     #[event(task = "Connect", opcode = "start", keywords("Network"))]
+//!     fn connect_start(server: &str);
+//!     #[event(task = "Connect", opcode = "stop", keywords("Network", "Storage"))]
+//!     fn connect_stop(server: &str);
+//! }
+//! ```
+//!
+//! The bits of different keywords cannot overlap. The upper 19 bits of the keyword
+//! (`0xFFFF_E000_0000_0000`) are reserved by Windows, and cannot be used.
+//!
+//! ## Activities
+//!
+//! An _activity_ is an operation that is described by a start event and a stop event. Annotate a
+//! method with `#[activity]` to generate both events from a single declaration. The parameters
+//! of the method are the fields of the start event, and `stop(...)` declares the fields of the
+//! stop event:
+//!
+//! ```no_test
+//! #[trace_logging_provider]
+//! pub trait MyEvents {
+//!     #[activity(stop(status: u32))]
+//!     fn request(url: &str);
+//! }
+//!
+//! let request = my_events.request_start(None, "/index.html");
+//! my_events.some_event(Some(&request.event_options()), ...);
+//! my_events.request_stop(request, 200);
+//! ```
+//!
+//! `request_start` creates a new activity ID, writes the start event (using the `"start"`
+//! opcode), and returns a `win_etw_provider::ActivityGuard`. If `options` specifies an activity ID,
+//! then it becomes the related activity ID of the new activity. `request_stop` writes the stop
+//! event (using the `"stop"` opcode) with the same activity ID and related activity ID.
+//!
+//! If the stop event has no fields (for example, `#[activity]` without `stop(...)`), then
+//! dropping the guard, or calling `finish()`, also writes the stop event. This is not supported
+//! when the stop event has fields: the macro has no values for them, and their types need not
+//! implement `Default`. In that case the guard must be passed to the `_stop` method; dropping it
+//! or calling `finish()` ends the activity without writing a stop event.
+//!
+//! The other `#[event]` keys, such as `level` and `task`, apply to both events. If the events of
+//! the provider have explicit IDs, use `#[activity(stop_id = NN)]` to specify the ID of the stop
+//! event.
+//!
+//! ## Activity scopes
+//!
+//! When `options` is `None`, or does not specify an activity ID, the generated event methods use
+//! the activity ID of the innermost `win_etw_provider::ActivityScope` future (or
+//! `win_etw_provider::with_activity_id` call) that is running on the current thread. Unlike
+//! `win_etw_provider::with_activity`, this works for async tasks that move between threads.
+//! This requires the `std` feature of `win_etw_provider`.
+//!
 //! # How to capture and view events
 //!
 //! There are a variety of tools which can be used to capture and view ETW events.
@@ -209,7 +557,8 @@ use std::{collections::HashMap, iter::Extend};
 use syn::spanned::Spanned;
 use syn::{parse_quote, Error, Expr, ExprLit, FnArg, Ident, Lit, Token};
 use uuid::Uuid;
-use well_known_types::{WellKnownType, WellKnownTypes};
+use well_known_types::{canonical_type, WellKnownType, WellKnownTypeInfo, WellKnownTypes};
+use win_etw_metadata::{InFlag, OutFlag};
 
 #[cfg(test)]
 mod tests;
@@ -226,6 +575,260 @@ pub fn trace_logging_provider(
     output.into()
 }
 
+/// Implements `win_etw_provider::EventFields` for a struct with named fields, so that the struct
+/// can be used as the type of an event field. See the module docs for more details.
+#[proc_macro_derive(EventFields, attributes(event))]
+pub fn derive_event_fields(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    derive_event_fields_core(input.into()).into()
+}
+
+fn derive_event_fields_core(input: TokenStream) -> TokenStream {
+    let item: syn::DeriveInput = match syn::parse2(input) {
+        Ok(item) => item,
+        Err(e) => return e.to_compile_error(),
+    };
+
+    let fields = match &item.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => {
+            return err_spanned(
+                &item.ident,
+                "EventFields can only be derived for structs with named fields.",
+            )
+        }
+    };
+
+    let mut errors: Vec<Error> = Vec::new();
+    let wk = WellKnownTypes::new();
+    let mut field_count: usize = 0;
+    let mut field_metadata: Vec<MetadataItem> = Vec::new();
+    let mut bindings = TokenStream::new();
+    let mut statements = TokenStream::new();
+    let mut data_descriptor_array: Vec<TokenStream> = Vec::new();
+
+    for field in fields.iter() {
+        let field_name = field.ident.as_ref().unwrap();
+        let mut event_attr: Option<&syn::Attribute> = None;
+        for a in field.attrs.iter() {
+            if a.path().is_ident("event") {
+                if event_attr.is_some() {
+                    errors.push(Error::new_spanned(
+                        a,
+                        "The #[event] attribute cannot be specified more than once.",
+                    ));
+                }
+                event_attr = Some(a);
+            }
+        }
+
+        // Well-known types are recognized by their spelling, which does not include lifetimes.
+        let mut field_ty = field.ty.clone();
+        remove_reference_lifetime(&mut field_ty);
+
+        // Each field is copied (or borrowed) into a local variable, so that the field can be
+        // encoded in the same way as an event parameter.
+        let option_inner_ty = option_inner_type(&field_ty);
+        if wk.find(&field_ty).is_some()
+            || matches!(field_ty, syn::Type::Reference(_))
+            || option_inner_ty.as_ref().is_some_and(|inner_ty| {
+                wk.find(inner_ty).is_some() || matches!(inner_ty, syn::Type::Reference(_))
+            })
+        {
+            bindings.extend(quote_spanned! {
+                field.span() =>
+                let #field_name = ::core::clone::Clone::clone(&self.#field_name);
+            });
+        } else if let Some(inner_ty) = option_inner_ty {
+            // Owned values, such as `Option<String>`, are borrowed rather than cloned.
+            bindings.extend(quote_spanned! {
+                field.span() =>
+                let #field_name = ::core::option::Option::as_ref(&self.#field_name);
+            });
+            field_ty = parse_quote!(::core::option::Option<&#inner_ty>);
+        } else {
+            bindings.extend(quote_spanned! {
+                field.span() =>
+                let #field_name = &self.#field_name;
+            });
+            field_ty = parse_quote!(&#field_ty);
+        }
+
+        let field_attrs = FieldAttributes::parse(&mut errors, event_attr);
+        match parse_event_field(
+            &mut errors,
+            &wk,
+            &field_attrs,
+            field.span(),
+            field_name,
+            &mut field_ty,
+            &mut data_descriptor_array,
+            &mut field_metadata,
+            &mut statements,
+        ) {
+            Ok(n) => field_count += n,
+            Err(UnsupportedField) => {
+                errors.push(Error::new_spanned(
+                    &field.ty,
+                    "This type is not supported for event fields.",
+                ));
+            }
+        }
+    }
+
+    if fields.is_empty() || field_count > 127 {
+        errors.push(Error::new_spanned(
+            &item.ident,
+            "A struct that derives EventFields must have between 1 and 127 fields.",
+        ));
+    }
+
+    if !errors.is_empty() {
+        return errors.into_iter().map(|e| e.to_compile_error()).collect();
+    }
+
+    let ident = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    let field_count = field_count as u8;
+    let field_metadata = metadata_builder_expr(field_metadata);
+    quote! {
+        // We intentionally generate identifiers that are not snake-case.
+        #[allow(non_snake_case)]
+        impl #impl_generics ::win_etw_provider::EventFields for #ident #ty_generics #where_clause {
+            const FIELD_COUNT: u8 = #field_count;
+
+            const FIELD_METADATA: ::win_etw_provider::metadata::MetadataBuilder = #field_metadata;
+
+            fn write_fields(&self, __fields_data: &mut ::win_etw_provider::types::Vec<u8>) {
+                use ::win_etw_provider::EventDataDescriptor;
+                #bindings
+                #statements
+                let data_descriptors = [ #( #data_descriptor_array, )* ];
+                ::win_etw_provider::append_event_data(&data_descriptors, __fields_data);
+            }
+        }
+
+        impl #impl_generics ::win_etw_provider::EventField for #ident #ty_generics #where_clause {
+            const IN_TYPE: ::win_etw_provider::metadata::InFlag =
+                ::win_etw_provider::metadata::InFlag::STRUCT;
+
+            // For structures, the OutType byte contains the number of fields.
+            const TYPE_METADATA: ::win_etw_provider::metadata::MetadataBuilder =
+                ::win_etw_provider::metadata::MetadataBuilder::new()
+                    .bytes(&[
+                        ::win_etw_provider::metadata::InFlag::STRUCT.bits()
+                            | ::win_etw_provider::metadata::InFlag::CHAIN_FLAG.bits(),
+                        #field_count,
+                    ])
+                    .append(&<Self as ::win_etw_provider::EventFields>::FIELD_METADATA);
+
+            fn data_descriptor<'__a>(
+                &'__a self,
+                buffer: &'__a mut ::win_etw_provider::types::Vec<u8>,
+            ) -> ::win_etw_provider::EventDataDescriptor<'__a> {
+                <Self as ::win_etw_provider::EventFields>::write_fields(self, buffer);
+                ::win_etw_provider::EventDataDescriptor::for_bytes(buffer)
+            }
+        }
+    }
+}
+
+/// Implements `win_etw_provider::EventEnum` for a fieldless enum that has a `#[repr]` attribute,
+/// so that the enum can be used as the type of an event field. See the module docs for more
+/// details.
+#[proc_macro_derive(EventEnum)]
+pub fn derive_event_enum(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    derive_event_enum_core(input.into()).into()
+}
+
+fn derive_event_enum_core(input: TokenStream) -> TokenStream {
+    let item: syn::DeriveInput = match syn::parse2(input) {
+        Ok(item) => item,
+        Err(e) => return e.to_compile_error(),
+    };
+
+    let variants = match &item.data {
+        syn::Data::Enum(data)
+            if data
+                .variants
+                .iter()
+                .all(|v| matches!(v.fields, syn::Fields::Unit)) =>
+        {
+            &data.variants
+        }
+        _ => {
+            return err_spanned(
+                &item.ident,
+                "EventEnum can only be derived for enums whose variants do not have fields.",
+            )
+        }
+    };
+
+    // The enum is encoded as the integer type that is specified by its #[repr] attribute.
+    let mut repr: Option<(Ident, InFlag)> = None;
+    for attr in item.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        // Other representation hints, such as `C`, are ignored.
+        let _ = attr.parse_nested_meta(|meta| {
+            if let Some(ident) = meta.path.get_ident() {
+                let in_type = match ident.to_string().as_str() {
+                    "i8" => InFlag::INT8,
+                    "u8" => InFlag::UINT8,
+                    "i16" => InFlag::INT16,
+                    "u16" => InFlag::UINT16,
+                    "i32" => InFlag::INT32,
+                    "u32" => InFlag::UINT32,
+                    "i64" => InFlag::INT64,
+                    "u64" => InFlag::UINT64,
+                    _ => return Ok(()),
+                };
+                repr = Some((ident.clone(), in_type));
+            }
+            Ok(())
+        });
+    }
+    let (repr_ty, in_type) = match repr {
+        Some(repr) => repr,
+        None => {
+            return err_spanned(
+                &item.ident,
+                "An enum that derives EventEnum must have a #[repr] attribute that specifies an integer type, such as #[repr(u32)].",
+            )
+        }
+    };
+
+    let ident = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    let in_type = in_type.bits();
+    let variant_idents: Vec<&Ident> = variants.iter().map(|v| &v.ident).collect();
+    let variant_names: Vec<String> = variant_idents.iter().map(|v| v.to_string()).collect();
+    quote! {
+        impl #impl_generics ::win_etw_provider::EventEnum for #ident #ty_generics #where_clause {
+            fn variant_name(&self) -> &'static str {
+                match self {
+                    #( Self::#variant_idents => #variant_names, )*
+                }
+            }
+        }
+
+        impl #impl_generics ::win_etw_provider::EventField for #ident #ty_generics #where_clause {
+            const IN_TYPE: ::win_etw_provider::metadata::InFlag =
+                ::win_etw_provider::metadata::InFlag::from_bits_retain(#in_type);
+
+            fn data_descriptor<'__a>(
+                &'__a self,
+                _buffer: &'__a mut ::win_etw_provider::types::Vec<u8>,
+            ) -> ::win_etw_provider::EventDataDescriptor<'__a> {
+                // Each discriminant is a constant, so the reference to it has a static lifetime.
+                match self {
+                    #( Self::#variant_idents => ::win_etw_provider::EventDataDescriptor::from(&(Self::#variant_idents as #repr_ty)), )*
+                }
+            }
+        }
+    }
+}
+
 fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> TokenStream {
     let mut errors: Vec<Error> = Vec::new();
 
@@ -288,19 +891,11 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
     let mut event_ids_auto_generated = true;
     let mut event_id_mappings = HashMap::new();
 
-    for (method_index, method) in logging_trait
-        .items
-        .iter()
-        .filter_map(|item| {
-            if let syn::TraitItem::Fn(m) = item {
-                Some(m)
-            } else {
-                None
-            }
-        })
-        .enumerate()
-    {
+    let event_specs = expand_event_specs(&mut errors, &logging_trait);
+
+    for (method_index, spec) in event_specs.iter().enumerate() {
         let event_index = method_index as u16;
+        let method = &spec.method;
 
         // Check requirements for the method signature. If the requirements are not met, we
         // emit an error but keep going. This allows us to report as many errors as possible in
@@ -348,15 +943,19 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
         //
         // For self-describing events (TraceLogging), the first two items in the data descriptor
         // array identify the provider metadata and the event metadata.
-        let mut data_descriptor_array = TokenStream::new();
+        let mut data_descriptor_array: Vec<TokenStream> = Vec::new();
 
         // See comments in traceloggingprovider.h, around line 2300, which describe the
         // encoding of the event mdata.
-        let mut event_metadata: Vec<Expr> = Vec::new();
-        event_metadata.push(parse_quote! { 0 }); // reserve space for the size (byte 0)
-        event_metadata.push(parse_quote! { 0 }); // reserve space for the size (byte 1)
-        event_metadata.push(parse_quote! { 0 }); // no extensions
-        append_utf8_str_chars(&mut event_metadata, &event_name);
+        let mut event_metadata_header: Vec<Expr> = Vec::new();
+        event_metadata_header.push(parse_quote! { 0 }); // reserve space for the size (byte 0)
+        event_metadata_header.push(parse_quote! { 0 }); // reserve space for the size (byte 1)
+        event_metadata_header.push(parse_quote! { 0 }); // no extensions
+        append_utf8_str_chars(&mut event_metadata_header, &event_name);
+        let mut event_metadata: Vec<MetadataItem> = event_metadata_header
+            .into_iter()
+            .map(MetadataItem::Byte)
+            .collect();
 
         // Some fields require running some code before building the data descriptors, so we
         // collect statements here.
@@ -403,10 +1002,11 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
                         }
                     };
 
+                    let field_attrs = FieldAttributes::parse(&mut errors, event_attr.as_ref());
                     if parse_event_field(
                         &mut errors,
                         &wk,
-                        event_attr.as_ref(),
+                        &field_attrs,
                         param_span,
                         param_name,
                         &mut param_typed.ty,
@@ -430,28 +1030,133 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
             sig.inputs.insert(0, parse_quote!(&self));
         }
 
-        // Insert the "options" argument.
-        sig.inputs.insert(
-            1,
-            parse_quote!(options: core::option::Option<&::win_etw_provider::EventOptions>),
-        );
+        // Insert the "options" argument. Activities take their options from the guard instead.
+        // `prologue` and `epilogue` wrap the body of the method.
+        let prologue;
+        let mut epilogue = TokenStream::new();
+        match &spec.kind {
+            EventKind::Event => {
+                sig.inputs.insert(
+                    1,
+                    parse_quote!(options: core::option::Option<&::win_etw_provider::EventOptions>),
+                );
+                prologue = quote! {
+                    let mut scoped_options = None;
+                    let options = ::win_etw_provider::apply_activity_scope(options, &mut scoped_options);
+                };
+            }
+            EventKind::ActivityStart {
+                stop_ident,
+                stop_field_count,
+            } => {
+                sig.inputs.insert(
+                    1,
+                    parse_quote!(options: core::option::Option<&::win_etw_provider::EventOptions>),
+                );
+                sig.output = parse_quote!(-> ::win_etw_provider::ActivityGuard<'_, Self>);
+                prologue = quote! {
+                    let mut scoped_options = None;
+                    let options = ::win_etw_provider::apply_activity_scope(options, &mut scoped_options);
+                    let activity_options = ::win_etw_provider::EventOptions::for_new_activity(options);
+                    let options = Some(&activity_options);
+                };
+                // The guard can only write the stop event when it is dropped if the stop event has
+                // no fields. There are no values for the fields otherwise, and the field types do
+                // not necessarily implement `Default`.
+                epilogue = if *stop_field_count == 0 {
+                    quote! {
+                        let stop: fn(&Self, &::win_etw_provider::EventOptions) = |provider, options| {
+                            provider.#stop_ident(
+                                ::win_etw_provider::ActivityGuard::new(provider, options.clone(), None),
+                            );
+                        };
+                        ::win_etw_provider::ActivityGuard::new(self, activity_options, Some(stop))
+                    }
+                } else {
+                    quote! {
+                        ::win_etw_provider::ActivityGuard::new(self, activity_options, None)
+                    }
+                };
+            }
+            EventKind::ActivityStop { .. } => {
+                sig.inputs.insert(
+                    1,
+                    parse_quote!(activity: ::win_etw_provider::ActivityGuard<'_, Self>),
+                );
+                prologue = quote! {
+                    let activity_options = activity.into_options();
+                    let options = Some(&activity_options);
+                };
+            }
+        }
 
         // Now that we have processed all parameters ("fields"), we can finish constructing
         // the per-event metadata.
-        let event_metadata_len = event_metadata.len();
-        if event_metadata_len > 0xffff {
-            errors.push(Error::new(
-                method.span(),
-                "Event metadata is too large to encode; reduce the complexity of this event.",
-            ));
-            continue;
-        }
-        let event_metadata_len_b0 = (event_metadata_len & 0xff) as u8;
-        let event_metadata_len_b1 = (event_metadata_len >> 8) as u8;
-        event_metadata[0] = parse_quote! { #event_metadata_len_b0 };
-        event_metadata[1] = parse_quote! { #event_metadata_len_b1 };
+        //
+        // This places the EVENT_METADATA into a read-only linker section, properly ordered with
+        // respect to TRACE_LOGGING_METADATA and other related sections.
+        let event_metadata_attrs = quote! {
+            #[cfg_attr(target_os = "windows", link_section = ".rdata$etw1")]
+            #[used]
+        };
+        let event_metadata_static = if event_metadata
+            .iter()
+            .all(|item| matches!(item, MetadataItem::Byte(_)))
+        {
+            let mut event_metadata: Vec<Expr> = event_metadata
+                .into_iter()
+                .filter_map(|item| match item {
+                    MetadataItem::Byte(b) => Some(b),
+                    MetadataItem::Type(_) => None,
+                })
+                .collect();
+            let event_metadata_len = event_metadata.len();
+            if event_metadata_len > 0xffff {
+                errors.push(Error::new(
+                    method.span(),
+                    "Event metadata is too large to encode; reduce the complexity of this event.",
+                ));
+                continue;
+            }
+            let event_metadata_len_b0 = (event_metadata_len & 0xff) as u8;
+            let event_metadata_len_b1 = (event_metadata_len >> 8) as u8;
+            event_metadata[0] = parse_quote! { #event_metadata_len_b0 };
+            event_metadata[1] = parse_quote! { #event_metadata_len_b1 };
+            quote! {
+                #event_metadata_attrs
+                static EVENT_METADATA: [u8; #event_metadata_len] = [ #( #event_metadata, )* ];
+            }
+        } else {
+            // The length of the metadata of some fields is not known yet, so the metadata is
+            // concatenated (and its size is checked) when the event is compiled.
+            let builder = metadata_builder_expr(event_metadata);
+            quote! {
+                const EVENT_METADATA_BUILDER: ::win_etw_provider::metadata::MetadataBuilder =
+                    #builder.with_size_prefix();
+                #event_metadata_attrs
+                static EVENT_METADATA: [u8; EVENT_METADATA_BUILDER.len()] =
+                    EVENT_METADATA_BUILDER.to_array();
+            }
+        };
 
-        let event_attrs = parse_event_attributes(&mut errors, &method.sig.ident, &method.attrs);
+        let mut event_attrs = parse_event_attributes(
+            &mut errors,
+            &method.sig.ident,
+            &method.attrs,
+            &provider_attrs,
+        );
+        match &spec.kind {
+            EventKind::Event => {}
+            EventKind::ActivityStart { .. } => {
+                event_attrs.opcode =
+                    parse_quote!(::win_etw_provider::metadata::WINEVENT_OPCODE_START);
+            }
+            EventKind::ActivityStop { stop_id } => {
+                event_attrs.opcode =
+                    parse_quote!(::win_etw_provider::metadata::WINEVENT_OPCODE_STOP);
+                event_attrs.event_id = *stop_id;
+            }
+        }
 
         // Generate the event descriptor for this event.
         // This is a static variable. The name is exactly the name of the event.
@@ -459,6 +1164,8 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
         let event_opcode = event_attrs.opcode;
         let event_task = event_attrs.task;
         let potential_event_id = event_attrs.event_id;
+        let event_version = event_attrs.version.unwrap_or(0);
+        let event_channel = event_attrs.channel.unwrap_or(11);
         let event_keyword = event_attrs
             .keyword
             .as_ref()
@@ -499,8 +1206,8 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
         let event_descriptor = quote! {
             ::win_etw_provider::EventDescriptor {
                 id: #event_id,
-                version: 0,
-                channel: 11,
+                version: #event_version,
+                channel: #event_channel,
                 level: #event_level,
                 opcode: #event_opcode,
                 task: #event_task,
@@ -516,37 +1223,48 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
             &format!("{}_is_enabled", method.sig.ident),
             method.sig.ident.span(),
         );
+        let event_is_enabled_doc = format!(
+            "Checks whether the `{}` event is enabled, at its declared level or at `level`.\n\n\
+             If the most recent enable request carried an event ID filter, then this also checks \
+             the event ID against that filter. ETW only passes the filter of the session that \
+             sent the request, so a later request from one session can hide this event from, or \
+             re-enable it for, other sessions in the result of this method. The event method \
+             itself does not check the filter; ETW applies it to each session.",
+            method.sig.ident
+        );
 
         // Build the method that implements this event.
         provider_impl_items.extend(quote!{
             #( #event_attrs_method_attrs )*
-            #[cfg_attr(not(target_os = "windows"), allow(unused_variables))]
             pub #sig
             {
-                #[cfg(target_os = "windows")]
-                {
-                    use ::win_etw_provider::EventDataDescriptor;
+                use ::win_etw_provider::EventDataDescriptor;
 
-                    // This places the EVENT_METADATA into a read-only linker section, properly
-                    // ordered with respect to TRACE_LOGGING_METADATA and other related sections.
-                    #[link_section = ".rdata$etw1"]
-                    #[used]
-                    static EVENT_METADATA: [u8; #event_metadata_len] = [ #( #event_metadata, )* ];
+                #prologue
 
-                    let mut event_descriptor: ::win_etw_provider::EventDescriptor = #event_descriptor;
+                #event_metadata_static
 
-                    if let Some(opts) = options {
-                        if let Some(level) = opts.level {
-                            event_descriptor.level = level;
-                        }
+                let mut event_descriptor: ::win_etw_provider::EventDescriptor = #event_descriptor;
+
+                if let Some(opts) = options {
+                    if let Some(level) = opts.level {
+                        event_descriptor.level = level;
                     }
+                }
 
+                // Converting and copying the fields can be expensive, for example for structures
+                // and paths, so it is only done if the level and keyword of the event are enabled.
+                if ::win_etw_provider::Provider::is_enabled(
+                    &self.provider,
+                    event_descriptor.level.0,
+                    event_descriptor.keyword,
+                ) {
                     #statements
 
                     let data_descriptors = [
                         EventDataDescriptor::for_provider_metadata(&#provider_metadata_ident[..]),
                         EventDataDescriptor::for_event_metadata(&EVENT_METADATA[..]),
-                        #data_descriptor_array
+                        #( #data_descriptor_array, )*
                     ];
                     ::win_etw_provider::Provider::write(&self.provider,
                         options,
@@ -554,28 +1272,62 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
                         &data_descriptors,
                     );
                 }
+
+                #epilogue
             }
 
+            #[doc = #event_is_enabled_doc]
             pub fn #event_is_enabled_name(&self, level: ::core::option::Option<::win_etw_provider::Level>) -> bool {
-                #[cfg(target_os = "windows")]
-                {
-                    let mut event_descriptor: ::win_etw_provider::EventDescriptor = #event_descriptor;
-                    if let Some(level) = level {
-                        event_descriptor.level = level;
-                    }
+                let mut event_descriptor: ::win_etw_provider::EventDescriptor = #event_descriptor;
+                if let Some(level) = level {
+                    event_descriptor.level = level;
+                }
 
-                    ::win_etw_provider::Provider::is_event_enabled(
+                ::win_etw_provider::Provider::is_event_enabled(
+                    &self.provider,
+                    &event_descriptor)
+                    && ::win_etw_provider::Provider::is_event_id_enabled(
                         &self.provider,
-                        &event_descriptor)
-                }
-                #[cfg(not(target_os = "windows"))]
-                {
-                    false
-                }
+                        event_descriptor.id)
             }
         });
     }
 
+    provider_impl_items.extend(quote! {
+        /// Sets the function that is called when an ETW controller asks this provider to capture
+        /// its state (`EVENT_CONTROL_CODE_CAPTURE_STATE`), for example to write events that
+        /// describe the current state of the application. This replaces any previous function.
+        ///
+        /// The function is called on a thread that is owned by ETW. It has no effect on a "null"
+        /// provider.
+        pub fn on_capture_state<F>(&self, callback: F)
+        where
+            F: Fn(&::win_etw_provider::CaptureStateRequest) + ::core::marker::Send + ::core::marker::Sync + 'static,
+        {
+            ::win_etw_provider::Provider::set_capture_state_callback(
+                &self.provider,
+                Some(::win_etw_provider::CaptureStateCallback::new(callback)),
+            );
+        }
+
+        /// Sets the function that is called when an ETW session enables or disables this
+        /// provider, or changes the level or keywords that it has enabled. This replaces any
+        /// previous function.
+        ///
+        /// This can be used to start expensive instrumentation only while a session is listening.
+        /// The function is called on a thread that is owned by ETW. It has no effect on a "null"
+        /// provider.
+        pub fn on_enable_state_change<F>(&self, callback: F)
+        where
+            F: Fn(&::win_etw_provider::EnableState) + ::core::marker::Send + ::core::marker::Sync + 'static,
+        {
+            ::win_etw_provider::Provider::set_enable_state_callback(
+                &self.provider,
+                Some(::win_etw_provider::EnableStateCallback::new(callback)),
+            );
+        }
+    });
+
     // We propagate the visibility of the trait definition to the structure definition.
     let vis = logging_trait.vis.clone();
     let provider_guid = match provider_attrs.uuid {
@@ -596,71 +1348,187 @@ fn trace_logging_events_core(attr: TokenStream, item_tokens: TokenStream) -> Tok
     let register_traits: TokenStream =
         create_register_provider_traits(provider_name, provider_attrs.provider_group_guid.as_ref());
 
-    output.extend(quote! {
-        #( #provider_doc_attrs )*
-        #vis struct #provider_ident {
-            provider: ::core::option::Option<::win_etw_provider::EtwProvider>,
+    let new_docs = quote! {
+        /// Creates (registers) a new instance of this provider. If registration fails,
+        /// returns a "null" provider. This prevents problems with event logging from
+        /// disrupting the normal operation of applications.
+        ///
+        /// On non-Windows platforms, this function always returns a null provider.
+        ///
+        /// Creating an event source is a costly operation, because it requires contacting the
+        /// event manager, allocating event buffers, potentially receiving callbacks from
+        /// event consumers, etc. Applications should only create event sources during process
+        /// initialization, and should always reuse them, never re-creating them.
+    };
+    let new_err_docs = quote! {
+        /// Creates (registers) a new instance of this provider. If registration fails, then
+        /// this method returns a "null" provider.
+        ///
+        /// On non-Windows platforms, this function always returns `Ok`, containing a null
+        /// provider.
+        ///
+        /// Creating an event source is a costly operation, because it requires contacting the
+        /// event manager, allocating event buffers, potentially receiving callbacks from
+        /// event consumers, etc. Applications should only create event sources during process
+        /// initialization, and should always reuse them, never re-creating them.
+    };
+    let null_docs = quote! {
+        /// Creates a new "null" instance of the provider. All events written to this provider
+        /// are discarded.
+    };
+    let task_consts = provider_attrs.tasks.iter().map(|(name, value)| {
+        let const_ident = Ident::new(
+            &format!("TASK_{}", to_upper_snake_case(&name.to_string())),
+            name.span(),
+        );
+        let doc = format!("The value of the `{name}` task, for use in `EventDescriptor::task`.");
+        quote! {
+            #[doc = #doc]
+            pub const #const_ident: u16 = #value;
         }
+    });
+    let keyword_consts = provider_attrs.keywords.iter().map(|(name, value)| {
+        let const_ident = Ident::new(
+            &format!("KEYWORD_{}", to_upper_snake_case(&name.to_string())),
+            name.span(),
+        );
+        let doc =
+            format!("The bits of the `{name}` keyword, for use in `EventDescriptor::keyword`.");
+        let value = syn::LitInt::new(&format!("0x{value:x}"), name.span());
+        quote! {
+            #[doc = #doc]
+            pub const #const_ident: u64 = #value;
+        }
+    });
+    let provider_consts = quote! {
+        #[allow(unused_variable)]
+        pub const PROVIDER_GUID: ::win_etw_provider::GUID = #provider_guid_const;
+        pub const PROVIDER_NAME: &'static str = #provider_name;
+        #( #task_consts )*
+        #( #keyword_consts )*
+    };
 
-        impl #provider_ident {
-            /// Creates (registers) a new instance of this provider. If registration fails,
-            /// returns a "null" provider. This prevents problems with event logging from
-            /// disrupting the normal operation of applications.
-            ///
-            /// On non-Windows platforms, this function always returns a null provider.
-            ///
-            /// Creating an event source is a costly operation, because it requires contacting the
-            /// event manager, allocating event buffers, potentially receiving callbacks from
-            /// event consumers, etc. Applications should only create event sources during process
-            /// initialization, and should always reuse them, never re-creating them.
-            pub fn new() -> Self {
-                let provider = match ::win_etw_provider::EtwProvider::new(&Self::PROVIDER_GUID) {
-                    Ok(mut provider) => {
-                        #[cfg(target_os = "windows")]
-                        {
-                            #register_traits
-                        }
-
-                        Some(provider)
-                    }
-                    Err(_) => None,
-                };
-                Self { provider }
+    match provider_attrs.backend {
+        Backend::Etw => output.extend(quote! {
+            #( #provider_doc_attrs )*
+            #vis struct #provider_ident {
+                provider: ::core::option::Option<::win_etw_provider::AnyProvider>,
             }
 
-            /// Creates (registers) a new instance of this provider. If registration fails, then
-            /// this method returns a "null" provider.
-            ///
-            /// On non-Windows platforms, this function always returns `Ok`, containing a null
-            /// provider.
-            ///
-            /// Creating an event source is a costly operation, because it requires contacting the
-            /// event manager, allocating event buffers, potentially receiving callbacks from
-            /// event consumers, etc. Applications should only create event sources during process
-            /// initialization, and should always reuse them, never re-creating them.
-            pub fn new_err() -> ::core::result::Result<Self, ::win_etw_provider::Error> {
-                Ok(Self {
-                    provider: Some(::win_etw_provider::EtwProvider::new(&Self::PROVIDER_GUID)?),
-                })
-            }
+            impl #provider_ident {
+                #new_docs
+                pub fn new() -> Self {
+                    let provider = match ::win_etw_provider::EtwProvider::new(&Self::PROVIDER_GUID) {
+                        Ok(mut provider) => {
+                            #[cfg(target_os = "windows")]
+                            {
+                                #register_traits
+                            }
 
-            /// Creates a new "null" instance of the provider. All events written to this provider
-            /// are discarded.
-            pub fn null() -> Self {
-                Self { provider: None }
-            }
+                            Some(::win_etw_provider::AnyProvider::Etw(provider))
+                        }
+                        Err(_) => None,
+                    };
+                    Self { provider }
+                }
 
-            #[allow(unused_variable)]
-            pub const PROVIDER_GUID: ::win_etw_provider::GUID = #provider_guid_const;
-            pub const PROVIDER_NAME: &'static str = #provider_name;
-        }
+                #new_err_docs
+                pub fn new_err() -> ::core::result::Result<Self, ::win_etw_provider::Error> {
+                    Ok(Self {
+                        provider: Some(::win_etw_provider::AnyProvider::Etw(
+                            ::win_etw_provider::EtwProvider::new(&Self::PROVIDER_GUID)?,
+                        )),
+                    })
+                }
 
-        // We intentionally generate identifiers that are not snake-case.
-        #[allow(non_snake_case)]
-        impl #provider_ident {
-            #provider_impl_items
-        }
-    });
+                #null_docs
+                pub fn null() -> Self {
+                    Self { provider: None }
+                }
+
+                /// Creates an instance of this provider that writes all events to `provider`, rather
+                /// than to ETW. This works on all platforms. For example, unit tests can pass a
+                /// `win_etw_provider::RecordingProvider` and then inspect the events that were written.
+                pub fn with_provider<P>(provider: P) -> Self
+                where
+                    P: ::win_etw_provider::Provider + ::core::marker::Send + ::core::marker::Sync + 'static,
+                {
+                    Self {
+                        provider: Some(::win_etw_provider::AnyProvider::other(provider)),
+                    }
+                }
+
+                #provider_consts
+            }
+
+            // We intentionally generate identifiers that are not snake-case.
+            #[allow(non_snake_case)]
+            impl #provider_ident {
+                #provider_impl_items
+            }
+        }),
+
+        Backend::Generic => output.extend(quote! {
+            #( #provider_doc_attrs )*
+            #vis struct #provider_ident<P = ::win_etw_provider::EtwProvider> {
+                provider: ::core::option::Option<P>,
+            }
+
+            impl #provider_ident<::win_etw_provider::EtwProvider> {
+                #new_docs
+                pub fn new() -> Self {
+                    let provider = match ::win_etw_provider::EtwProvider::new(&Self::PROVIDER_GUID) {
+                        Ok(mut provider) => {
+                            #[cfg(target_os = "windows")]
+                            {
+                                #register_traits
+                            }
+
+                            Some(provider)
+                        }
+                        Err(_) => None,
+                    };
+                    Self { provider }
+                }
+
+                #new_err_docs
+                pub fn new_err() -> ::core::result::Result<Self, ::win_etw_provider::Error> {
+                    Ok(Self {
+                        provider: Some(::win_etw_provider::EtwProvider::new(&Self::PROVIDER_GUID)?),
+                    })
+                }
+            }
+
+            impl<P: ::win_etw_provider::Provider> #provider_ident<P> {
+                #provider_consts
+
+                #null_docs
+                pub fn null() -> Self {
+                    Self { provider: None }
+                }
+
+                /// Creates an instance of this provider that writes all events to `provider`.
+                /// This works on all platforms.
+                pub fn with_provider(provider: P) -> Self {
+                    Self {
+                        provider: Some(provider),
+                    }
+                }
+
+                /// Returns a reference to the provider that events are written to, or `None` if
+                /// this is a "null" provider.
+                pub fn provider(&self) -> ::core::option::Option<&P> {
+                    self.provider.as_ref()
+                }
+            }
+
+            // We intentionally generate identifiers that are not snake-case.
+            #[allow(non_snake_case)]
+            impl<P: ::win_etw_provider::Provider> #provider_ident<P> {
+                #provider_impl_items
+            }
+        }),
+    }
 
     output.extend(errors.into_iter().map(|e| e.to_compile_error()));
     output
@@ -727,10 +1595,9 @@ fn create_provider_metadata(provider_name: &str, provider_metadata_ident: &Ident
     provider_metadata.push(0);
 
     quote! {
-        #[link_section = ".rdata$etw2"]
+        #[cfg_attr(target_os = "windows", link_section = ".rdata$etw2")]
         #[used]
         #[allow(non_upper_case_globals)]
-        #[cfg(target_os = "windows")]
         static #provider_metadata_ident: [u8; #provider_metadata_len] = [
             #(
                 #provider_metadata,
@@ -749,21 +1616,115 @@ fn append_utf8_str_chars(output: &mut Vec<Expr>, s: &str) {
 
 struct UnsupportedField;
 
-/// Parses one event field. Event fields are declared as function parameters.
+/// One item of the metadata of an event, or of a type that derives `EventFields`.
+enum MetadataItem {
+    /// A single byte. This can be a literal or a symbolic expression.
+    Byte(Expr),
+    /// The type metadata of a field whose type implements `EventField`, such as a type that
+    /// derives `EventFields` or `EventEnum`. Its length is not known until the type is compiled.
+    Type(syn::Type),
+}
+
+/// Generates an expression that builds `items` with a `MetadataBuilder`.
+fn metadata_builder_expr(items: Vec<MetadataItem>) -> TokenStream {
+    let mut pieces = TokenStream::new();
+    let mut bytes: Vec<Expr> = Vec::new();
+    for item in items {
+        match item {
+            MetadataItem::Byte(b) => bytes.push(b),
+            MetadataItem::Type(ty) => {
+                if !bytes.is_empty() {
+                    pieces.extend(quote! { .bytes(&[ #( #bytes, )* ]) });
+                    bytes.clear();
+                }
+                pieces.extend(quote! {
+                    .append(&<#ty as ::win_etw_provider::EventField>::TYPE_METADATA)
+                });
+            }
+        }
+    }
+    if !bytes.is_empty() {
+        pieces.extend(quote! { .bytes(&[ #( #bytes, )* ]) });
+    }
+    quote! {
+        ::win_etw_provider::metadata::MetadataBuilder::new() #pieces
+    }
+}
+
+/// Parses one event field. Event fields are declared as function parameters, or as the fields of
+/// a struct that derives `EventFields`.
 ///
+/// * `data_descriptor_array`: Receives one expression for each data descriptor of the field, in
+///   order. Each expression is kept separate, so that callers can wrap them individually.
 /// * `event_metadata`: This builds the static [u8; N] array that contains the metadata for this
 ///   event. It can contain literals, symbolic expressions, etc.
+///
+/// Returns the number of fields that were written, which is more than one if the field has a
+/// companion field.
 fn parse_event_field(
     errors: &mut Vec<Error>,
     well_known_types: &WellKnownTypes,
-    event_attr: Option<&syn::Attribute>,
+    field_attrs: &FieldAttributes,
     field_span: proc_macro2::Span,
     field_name: &Ident,
     field_ty: &mut syn::Type,
-    data_descriptor_array: &mut TokenStream,
-    event_metadata: &mut Vec<syn::Expr>,
+    data_descriptor_array: &mut Vec<TokenStream>,
+    event_metadata: &mut Vec<MetadataItem>,
     statements: &mut TokenStream,
-) -> Result<(), UnsupportedField> {
+) -> Result<usize, UnsupportedField> {
+    if let Some(inner_ty) = option_inner_type(field_ty) {
+        return parse_option_event_field(
+            errors,
+            well_known_types,
+            field_attrs,
+            field_span,
+            field_name,
+            field_ty,
+            inner_ty,
+            data_descriptor_array,
+            event_metadata,
+            statements,
+        );
+    }
+    if field_attrs.has_field {
+        errors.push(Error::new(
+            field_span,
+            "The has_field option can only be used with fields whose type is Option<T>.",
+        ));
+    }
+
+    // Other string types are converted to `&str`, and are encoded in the same way. The type of
+    // the parameter is not changed.
+    let as_ref_target = as_ref_target_type(field_ty);
+    if as_ref_target == Some(AsRefTarget::Str) {
+        statements.extend(quote_spanned! {
+            field_span =>
+            let #field_name: &str = ::core::convert::AsRef::<str>::as_ref(&#field_name);
+        });
+        return parse_event_field(
+            errors,
+            well_known_types,
+            field_attrs,
+            field_span,
+            field_name,
+            &mut parse_quote!(&str),
+            data_descriptor_array,
+            event_metadata,
+            statements,
+        );
+    }
+    if as_ref_target == Some(AsRefTarget::Path) {
+        return parse_path_event_field(
+            errors,
+            field_attrs,
+            field_span,
+            field_name,
+            data_descriptor_array,
+            event_metadata,
+            statements,
+        );
+    }
+
     // Write the field metadata.
     // // FieldMetadata:
     // struct FieldMetadata // Variable-length pseudo-structure, byte-aligned, tightly-packed.
@@ -778,36 +1739,22 @@ fn parse_event_field(
     // };
 
     let param_name_string = field_name.to_string();
-    append_utf8_str_chars(event_metadata, &param_name_string);
-    // We will append more data to event_metadata, below.
-
-    // The user can annotate fields with #[event(...)] in order to specify output formats.
-    let mut output_hex = false;
-    if let Some(event_attr) = event_attr {
-        if let Err(e) = event_attr.parse_nested_meta(|meta| {
-            if meta.path.is_ident("output") {
-                let value: syn::LitStr = meta.value()?.parse()?;
-                match value.value().as_str() {
-                    "hex" => {
-                        output_hex = true;
-                    }
-                    _ => {
-                        return Err(meta.error("Output format is not recognized."));
-                    }
-                }
-            } else {
-                return Err(meta.error("This metadata key is not recognized."));
-            }
-            Ok(())
-        }) {
-            errors.push(e);
-        }
-    }
+    let mut field_metadata: Vec<Expr> = Vec::new();
+    append_utf8_str_chars(&mut field_metadata, &param_name_string);
+    // We will append more data to field_metadata, below.
+
+    let output = field_attrs.output;
 
     let mut field_metadata_intype: Expr;
     let mut field_metadata_out_type: Option<Expr> = None;
+    // The number of elements of a fixed-size array.
+    let mut field_metadata_count: Option<Expr> = None;
+    // The InType and OutType of the field (or of its elements), and its count flag. These
+    // determine which output formats can be used with the field.
+    let field_format: FieldFormat;
 
     if let Some(t) = well_known_types.find(&*field_ty) {
+        field_format = FieldFormat::of(t, InFlag::NULL);
         field_metadata_intype = if let Some(in_type_expr) = t.opts.in_type_expr.as_ref() {
             in_type_expr.clone()
         } else {
@@ -835,10 +1782,8 @@ fn parse_event_field(
                     field_span =>
                     let #field_len: u16 = #field_name.len() as u16;
                 });
-                data_descriptor_array.extend(quote! {
-                    EventDataDescriptor::from(&#field_len),
-                    EventDataDescriptor::from(#field_name),
-                });
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(&#field_len)));
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(#field_name)));
             }
             WellKnownType::u16str => {
                 // UCS-2 string without NUL terminator.
@@ -850,10 +1795,8 @@ fn parse_event_field(
                     // Which is easy, because there is no NUL.
                     let #field_len: u16 = (#field_len * 2).min(0xffff) as u16;
                 });
-                data_descriptor_array.extend(quote! {
-                    EventDataDescriptor::from(&#field_len),
-                    EventDataDescriptor::from(#field_name),
-                });
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(&#field_len)));
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(#field_name)));
             }
             WellKnownType::u16cstr => {
                 // UCS-2 string without NUL terminator.
@@ -864,10 +1807,8 @@ fn parse_event_field(
                     // want the length in bytes of the string, excluding the NUL.
                     let #field_len: u16 = (#field_len * 2).min(0xffff) as u16;
                 });
-                data_descriptor_array.extend(quote! {
-                    EventDataDescriptor::from(&#field_len),
-                    EventDataDescriptor::from(#field_name),
-                });
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(&#field_len)));
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(#field_name)));
             }
             WellKnownType::osstr => {
                 let field_len = ident_suffix(field_name, "len");
@@ -892,10 +1833,8 @@ fn parse_event_field(
                         }
                     }
                 });
-                data_descriptor_array.extend(quote! {
-                    EventDataDescriptor::from(&#field_len),
-                    #field_desc,
-                });
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(&#field_len)));
+                data_descriptor_array.push(quote!(#field_desc));
             }
             WellKnownType::SocketAddrV4 => {
                 // We cannot simply pass a copy of std::net::SocketAddrV4 to ETW because it does
@@ -907,10 +1846,8 @@ fn parse_event_field(
                     let #field_name = ::win_etw_provider::SocketAddrV4::from(#field_name);
                     let #field_len: u16 = (::core::mem::size_of::<::win_etw_provider::SocketAddrV4>()) as u16;
                 });
-                data_descriptor_array.extend(quote! {
-                    EventDataDescriptor::from(&#field_len),
-                    EventDataDescriptor::from(&#field_name),
-                });
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(&#field_len)));
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(&#field_name)));
             }
             WellKnownType::SocketAddrV6 => {
                 // We cannot simply pass a copy of std::net::SocketAddrV6 to ETW because it does
@@ -922,10 +1859,13 @@ fn parse_event_field(
                     let #field_name = ::win_etw_provider::SocketAddrV6::from(#field_name);
                     let #field_len: u16 = (::core::mem::size_of::<::win_etw_provider::SocketAddrV6>()) as u16;
                 });
-                data_descriptor_array.extend(quote_spanned! {
+                data_descriptor_array.push(quote_spanned! {
                     field_span =>
-                    EventDataDescriptor::from(&#field_len),
-                    EventDataDescriptor::from(&#field_name),
+                    EventDataDescriptor::from(&#field_len)
+                });
+                data_descriptor_array.push(quote_spanned! {
+                    field_span =>
+                    EventDataDescriptor::from(&#field_name)
                 });
             }
             WellKnownType::SocketAddr => {
@@ -952,10 +1892,44 @@ fn parse_event_field(
                         }
                     }
                 });
-                data_descriptor_array.extend(quote_spanned! {
+                data_descriptor_array.push(quote_spanned! {
+                    field_span =>
+                    EventDataDescriptor::from(&#field_len_ident)
+                });
+                data_descriptor_array.push(quote_spanned! {
+                    field_span =>
+                    #field_desc
+                });
+            }
+            WellKnownType::Ipv4Addr => {
+                // std::net::Ipv4Addr does not have a guaranteed memory layout, so it is converted
+                // to win_etw_provider::types::Ipv4Addr, which stores the address in big-endian
+                // form.
+                statements.extend(quote_spanned! {
+                    field_span =>
+                    let #field_name = ::win_etw_provider::types::Ipv4Addr::from(&#field_name);
+                });
+                data_descriptor_array.push(quote_spanned! {
+                    field_span =>
+                    EventDataDescriptor::from(&#field_name)
+                });
+            }
+            WellKnownType::Ipv6Addr | WellKnownType::IpAddr => {
+                // IPv6 addresses are binary data, which is preceded by its length. IpAddr values
+                // are always written as IPv6 addresses; see win_etw_provider::types::Ipv6Addr.
+                let field_len = ident_suffix(field_name, "len");
+                statements.extend(quote_spanned! {
+                    field_span =>
+                    let #field_name = ::win_etw_provider::types::Ipv6Addr::from(&#field_name);
+                    let #field_len: u16 = ::core::mem::size_of::<::win_etw_provider::types::Ipv6Addr>() as u16;
+                });
+                data_descriptor_array.push(quote_spanned! {
+                    field_span =>
+                    EventDataDescriptor::from(&#field_len)
+                });
+                data_descriptor_array.push(quote_spanned! {
                     field_span =>
-                    EventDataDescriptor::from(&#field_len_ident),
-                    #field_desc,
+                    EventDataDescriptor::from(&#field_name)
                 });
             }
             WellKnownType::SystemTime => {
@@ -967,7 +1941,7 @@ fn parse_event_field(
                     let #field_name = <::win_etw_provider::FILETIME as ::core::convert::TryFrom<::std::time::SystemTime>>
                     ::try_from(#field_name);
                 });
-                data_descriptor_array.extend(quote_spanned! {
+                data_descriptor_array.push(quote_spanned! {
                     field_span =>
                     match &#field_name {
                         Ok(ref t) => EventDataDescriptor::from(&t.0),
@@ -976,9 +1950,9 @@ fn parse_event_field(
                 });
             }
             WellKnownType::FILETIME => {
-                data_descriptor_array.extend(quote_spanned! {
+                data_descriptor_array.push(quote_spanned! {
                     field_span =>
-                    EventDataDescriptor::from(&#field_name.0),
+                    EventDataDescriptor::from(&#field_name.0)
                 });
             }
             WellKnownType::bool => {
@@ -986,81 +1960,730 @@ fn parse_event_field(
                     field_span =>
                     let #field_name: i8 = #field_name as i8;
                 });
-                data_descriptor_array.extend(quote! {
-                    EventDataDescriptor::from(&#field_name),
-                });
+                data_descriptor_array.push(quote!(EventDataDescriptor::from(&#field_name)));
             }
             _ => {
                 if t.is_ref {
-                    data_descriptor_array.extend(quote_spanned! {
+                    data_descriptor_array.push(quote_spanned! {
                         field_span =>
-                        EventDataDescriptor::from(#field_name),
+                        EventDataDescriptor::from(#field_name)
                     });
                 } else {
-                    data_descriptor_array.extend(quote_spanned! {
+                    data_descriptor_array.push(quote_spanned! {
                         field_span =>
-                        EventDataDescriptor::from(&#field_name),
+                        EventDataDescriptor::from(&#field_name)
                     });
                 }
             }
         }
     } else {
-        match &*field_ty {
-            syn::Type::Reference(ref_ty) => {
-                match &*ref_ty.elem {
+        // Any other named type, including a type alias, must implement `EventField`, which is
+        // implemented by deriving `EventFields` or `EventEnum`, and for the well-known types.
+        // The type provides the metadata that follows the field name, and a data descriptor for
+        // the value.
+        let encoded_ty = match &*field_ty {
+            syn::Type::Path(_) => Some(field_ty.clone()),
+            syn::Type::Reference(ref_ty) => match &*ref_ty.elem {
+                syn::Type::Path(_) => Some((*ref_ty.elem).clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(encoded_ty) = encoded_ty {
+            let field_buffer = ident_suffix(field_name, "buffer");
+            let field_desc = ident_suffix(field_name, "desc");
+            let field_ref = if let syn::Type::Reference(_) = &*field_ty {
+                quote!(#field_name)
+            } else {
+                quote!(&#field_name)
+            };
+            statements.extend(quote_spanned! {
+                field_span =>
+                let mut #field_buffer = ::win_etw_provider::types::Vec::new();
+                let #field_desc = <#encoded_ty as ::win_etw_provider::EventField>::data_descriptor(#field_ref, &mut #field_buffer);
+            });
+            data_descriptor_array.push(quote!(#field_desc));
+            if output.is_some() {
+                errors.push(Error::new(
+                    field_span,
+                    "Output formats cannot be used with fields whose type implements EventField. Use EventField::OUT_TYPE instead.",
+                ));
+            }
+            event_metadata.extend(field_metadata.into_iter().map(MetadataItem::Byte));
+            event_metadata.push(MetadataItem::Type(encoded_ty.clone()));
+
+            if !field_attrs.variant_name {
+                return Ok(1);
+            }
+            // The companion field is encoded in the same way as a &str parameter.
+            let name_field = Ident::new(&format!("{field_name}_name"), field_name.span());
+            statements.extend(quote_spanned! {
+                field_span =>
+                let #name_field: &str = <#encoded_ty as ::win_etw_provider::EventEnum>::variant_name(#field_ref);
+            });
+            parse_event_field(
+                errors,
+                well_known_types,
+                &FieldAttributes::default(),
+                field_span,
+                &name_field,
+                &mut parse_quote!(&str),
+                data_descriptor_array,
+                event_metadata,
+                statements,
+            )?;
+            return Ok(2);
+        } else if let Some(array_ty) = match &*field_ty {
+            syn::Type::Array(array_ty) => Some(array_ty.clone()),
+            syn::Type::Reference(ref_ty) => match &*ref_ty.elem {
+                syn::Type::Array(array_ty) => Some(array_ty.clone()),
+                _ => None,
+            },
+            _ => None,
+        } {
+            // Arrays have a fixed length, which is stored in the field metadata (CCOUNT_FLAG),
+            // so only the elements are written, using a single data descriptor.
+            let t = match well_known_types.find(&array_ty.elem) {
+                Some(t) if t.primitive => t,
+                _ => return Err(UnsupportedField),
+            };
+            field_format = FieldFormat::of(t, InFlag::CCOUNT_FLAG);
+            data_descriptor_array.push(quote_spanned! {
+                field_span =>
+                EventDataDescriptor::from(&#field_name[..])
+            });
+            field_metadata_intype = element_in_type_expr(t);
+            field_metadata_intype = parse_quote!(#field_metadata_intype | ::win_etw_provider::metadata::InFlag::CCOUNT_FLAG.bits());
+            let array_len = &array_ty.len;
+            field_metadata_count = Some(parse_quote!(#array_len));
+        } else {
+            let mut slice_replacement_type: Option<syn::Type> = None;
+            match &*field_ty {
+                syn::Type::Reference(ref_ty) => match &*ref_ty.elem {
+                    syn::Type::Slice(slice_ty) if is_string_type(&slice_ty.elem) => {
+                        // Arrays of strings are encoded into a buffer, because each string needs
+                        // its own length field. See `append_str_array`.
+                        let field_buffer = ident_suffix(field_name, "buffer");
+                        statements.extend(quote_spanned! {
+                            field_span =>
+                            let mut #field_buffer = ::win_etw_provider::types::Vec::new();
+                            ::win_etw_provider::append_str_array(#field_name, &mut #field_buffer);
+                        });
+                        data_descriptor_array
+                            .push(quote!(EventDataDescriptor::for_bytes(&#field_buffer)));
+                        field_metadata_intype = parse_quote!(
+                            ::win_etw_provider::metadata::InFlag::COUNTED_ANSI_STRING.bits()
+                                | ::win_etw_provider::metadata::InFlag::VCOUNT_FLAG.bits()
+                        );
+                        field_metadata_out_type = Some(parse_quote!(
+                            ::win_etw_provider::metadata::OutFlag::UTF8.bits()
+                        ));
+                        field_format = FieldFormat {
+                            in_type: InFlag::COUNTED_ANSI_STRING,
+                            out_type: Some(OutFlag::UTF8),
+                            count: InFlag::VCOUNT_FLAG,
+                        };
+                    }
                     syn::Type::Slice(slice_ty) => {
-                        if let Some(t) = well_known_types.find(&slice_ty.elem) {
-                            if !t.primitive {
-                                return Err(UnsupportedField);
-                            }
-                            // Slices are encoded using two data descriptors.
-                            // The first is for the length field, the second for the data.
-                            let field_len_ident = ident_suffix(field_name, "len");
-                            statements.extend(quote_spanned! {
-                                field_span =>
-                                let #field_name = &#field_name[..#field_name.len().min(0xffff)];
-                                let #field_len_ident: u16 = #field_name.len() as u16;
-                            });
-                            data_descriptor_array.extend(quote! {
-                                EventDataDescriptor::from(&#field_len_ident),
-                                EventDataDescriptor::from(#field_name),
-                            });
-                            // 0x40 is VCOUNT flag
-                            let in_type_u8 = t.in_type.bits();
-                            field_metadata_intype = parse_quote!(#in_type_u8);
-                            field_metadata_intype = parse_quote!(#field_metadata_intype | ::win_etw_provider::metadata::InFlag::VCOUNT_FLAG.bits());
+                        let element_in_type: Expr = if is_guid_type(&slice_ty.elem) {
+                            slice_replacement_type =
+                                Some(parse_quote!(&[::win_etw_provider::GUID]));
+                            field_format = FieldFormat {
+                                in_type: InFlag::GUID,
+                                out_type: None,
+                                count: InFlag::VCOUNT_FLAG,
+                            };
+                            parse_quote!(::win_etw_provider::metadata::InFlag::GUID.bits())
                         } else {
-                            return Err(UnsupportedField);
-                        }
+                            match well_known_types.find(&slice_ty.elem) {
+                                Some(t) if t.primitive => {
+                                    field_format = FieldFormat::of(t, InFlag::VCOUNT_FLAG);
+                                    element_in_type_expr(t)
+                                }
+                                _ => return Err(UnsupportedField),
+                            }
+                        };
+                        // Slices are encoded using two data descriptors.
+                        // The first is for the length field, the second for the data.
+                        let field_len_ident = ident_suffix(field_name, "len");
+                        statements.extend(quote_spanned! {
+                            field_span =>
+                            let #field_name = &#field_name[..#field_name.len().min(0xffff)];
+                            let #field_len_ident: u16 = #field_name.len() as u16;
+                        });
+                        data_descriptor_array
+                            .push(quote!(EventDataDescriptor::from(&#field_len_ident)));
+                        data_descriptor_array.push(quote!(EventDataDescriptor::from(#field_name)));
+                        // 0x40 is VCOUNT flag
+                        field_metadata_intype = parse_quote!(#element_in_type | ::win_etw_provider::metadata::InFlag::VCOUNT_FLAG.bits());
                     }
                     _ => {
                         return Err(UnsupportedField);
                     }
+                },
+                _ => {
+                    return Err(UnsupportedField);
                 }
             }
-            _ => {
-                return Err(UnsupportedField);
+            if let Some(replacement_type) = slice_replacement_type {
+                *field_ty = replacement_type;
             }
         }
     }
 
-    if output_hex {
-        let hex: Expr = parse_quote!(::win_etw_provider::metadata::OutFlag::HEX.bits());
-        field_metadata_out_type = Some(if let Some(out_type) = field_metadata_out_type {
-            parse_quote!(#out_type | #hex)
+    if field_attrs.variant_name {
+        errors.push(Error::new(
+            field_span,
+            "The variant_name option can only be used with fields whose type derives EventEnum.",
+        ));
+    }
+
+    if let Some((output_name, output)) = output {
+        if field_format.allows_output(output) {
+            let out_type = output.bits();
+            field_metadata_out_type = Some(parse_quote!(#out_type));
+            if output == OutFlag::PORT || output == OutFlag::IPV4 {
+                // Ports and IPv4 addresses are displayed from big-endian values.
+                statements.extend(quote_spanned! {
+                    field_span =>
+                    let #field_name = #field_name.to_be();
+                });
+            } else if output == OutFlag::IPV6 && field_format.in_type == InFlag::UINT8 {
+                // A counted array of bytes has the same encoding as binary data.
+                field_metadata_intype =
+                    parse_quote!(::win_etw_provider::metadata::InFlag::BINARY.bits());
+            }
         } else {
-            hex
-        });
+            errors.push(Error::new(
+                field_span,
+                format!(
+                    "The output format \"{output_name}\" cannot be used with fields of this type."
+                ),
+            ));
+        }
     }
 
     if let Some(out_type) = field_metadata_out_type {
         field_metadata_intype = parse_quote!(#field_metadata_intype | ::win_etw_provider::metadata::InFlag::CHAIN_FLAG.bits());
-        event_metadata.push(field_metadata_intype);
-        event_metadata.push(out_type);
+        field_metadata.push(field_metadata_intype);
+        field_metadata.push(out_type);
+    } else {
+        field_metadata.push(field_metadata_intype);
+    }
+    if let Some(count) = field_metadata_count {
+        // The count is a little-endian u16.
+        field_metadata.push(parse_quote!({
+            let count: usize = #count;
+            assert!(
+                count <= 0xffff,
+                "Fixed-size arrays in events cannot have more than 65535 elements."
+            );
+            count as u8
+        }));
+        field_metadata.push(parse_quote!(((#count) >> 8) as u8));
+    }
+    event_metadata.extend(field_metadata.into_iter().map(MetadataItem::Byte));
+    Ok(1)
+}
+
+/// If `ty` is a path type, returns the name of its last segment and its generic type arguments.
+/// Lifetime arguments are ignored, so `Cow<'a, str>` gives `("Cow", [str])`.
+fn path_type_parts(ty: &syn::Type) -> Option<(&Ident, Vec<&syn::Type>)> {
+    let path_ty = match ty {
+        syn::Type::Path(path_ty) if path_ty.qself.is_none() => path_ty,
+        _ => return None,
+    };
+    let segment = path_ty.path.segments.last()?;
+    let mut type_args = Vec::new();
+    match &segment.arguments {
+        syn::PathArguments::None => {}
+        syn::PathArguments::AngleBracketed(args) => {
+            for arg in args.args.iter() {
+                match arg {
+                    syn::GenericArgument::Type(arg_ty) => type_args.push(arg_ty),
+                    syn::GenericArgument::Lifetime(_) => {}
+                    _ => return None,
+                }
+            }
+        }
+        syn::PathArguments::Parenthesized(_) => return None,
+    }
+    Some((&segment.ident, type_args))
+}
+
+/// Returns `true` if `ty` is the path type `name`, with the given generic type arguments.
+fn is_path_type(ty: &syn::Type, name: &str, type_args: &[&str]) -> bool {
+    match path_type_parts(ty) {
+        Some((ident, args)) => {
+            ident == name
+                && args.len() == type_args.len()
+                && args
+                    .iter()
+                    .zip(type_args.iter())
+                    .all(|(arg, expected)| is_path_type(arg, expected, &[]))
+        }
+        None => false,
+    }
+}
+
+/// Returns `true` if `ty` is `GUID`, or one of the fully-qualified paths of `GUID`.
+fn is_guid_type(ty: &syn::Type) -> bool {
+    let canonical = canonical_type(ty);
+    *canonical.as_ref().unwrap_or(ty) == parse_quote!(GUID)
+}
+
+/// If `ty` is a shared reference, returns the type that it refers to.
+fn referenced_type(ty: &syn::Type) -> Option<&syn::Type> {
+    match ty {
+        syn::Type::Reference(ref_ty) if ref_ty.mutability.is_none() => Some(&ref_ty.elem),
+        _ => None,
+    }
+}
+
+/// If `ty` is `impl AsRef<T>`, returns `T`.
+fn impl_as_ref_type(ty: &syn::Type) -> Option<&syn::Type> {
+    let impl_ty = match ty {
+        syn::Type::ImplTrait(impl_ty) if impl_ty.bounds.len() == 1 => impl_ty,
+        _ => return None,
+    };
+    let bound = match &impl_ty.bounds[0] {
+        syn::TypeParamBound::Trait(bound) => bound,
+        _ => return None,
+    };
+    let segment = bound.path.segments.last()?;
+    if segment.ident != "AsRef" {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            syn::GenericArgument::Type(target_ty) => Some(target_ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns `true` if `ty` is `&str`, `String`, `&String`, `Box<str>`, or `Cow<str>`. These types
+/// implement `AsRef<str>`, and are encoded in the same way as `&str`.
+fn is_string_type(ty: &syn::Type) -> bool {
+    if let Some(elem) = referenced_type(ty) {
+        return is_path_type(elem, "str", &[]) || is_path_type(elem, "String", &[]);
+    }
+    is_path_type(ty, "String", &[])
+        || is_path_type(ty, "Box", &["str"])
+        || is_path_type(ty, "Cow", &["str"])
+}
+
+/// Returns `true` if `ty` is `&Path`, `&PathBuf`, or `PathBuf`. These types implement
+/// `AsRef<Path>`.
+fn is_path_buf_type(ty: &syn::Type) -> bool {
+    if let Some(elem) = referenced_type(ty) {
+        return is_path_type(elem, "Path", &[]) || is_path_type(elem, "PathBuf", &[]);
+    }
+    is_path_type(ty, "PathBuf", &[])
+}
+
+/// The type that an event parameter is converted to with `AsRef`, before it is encoded.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum AsRefTarget {
+    /// The parameter is a string type other than `&str`, and is converted to `&str`.
+    Str,
+    /// The parameter is a path type, and is converted to `&Path`.
+    Path,
+}
+
+/// Returns the type that `ty` is converted to with `AsRef`, if `ty` is a string type other than
+/// `&str`, a path type, or `impl AsRef<str>` or `impl AsRef<Path>`.
+fn as_ref_target_type(ty: &syn::Type) -> Option<AsRefTarget> {
+    let is_str_ref = referenced_type(ty).is_some_and(|elem| is_path_type(elem, "str", &[]));
+    let impl_as_ref = impl_as_ref_type(ty);
+    if (is_string_type(ty) && !is_str_ref)
+        || impl_as_ref.is_some_and(|t| is_path_type(t, "str", &[]))
+    {
+        Some(AsRefTarget::Str)
+    } else if is_path_buf_type(ty) || impl_as_ref.is_some_and(|t| is_path_type(t, "Path", &[])) {
+        Some(AsRefTarget::Path)
+    } else {
+        None
+    }
+}
+
+/// Returns the InType of the elements of an array or slice of `t`.
+fn element_in_type_expr(t: &WellKnownTypeInfo) -> Expr {
+    if let Some(in_type_expr) = t.opts.in_type_expr.as_ref() {
+        in_type_expr.clone()
     } else {
-        event_metadata.push(field_metadata_intype);
+        let in_type_u8 = t.in_type.bits();
+        parse_quote!(#in_type_u8)
     }
-    Ok(())
+}
+
+/// The output formats that can be specified by `#[event(output = "...")]`.
+const OUTPUT_FORMATS: &[(&str, OutFlag)] = &[
+    ("hex", OutFlag::HEX),
+    ("signed", OutFlag::SIGNED),
+    ("unsigned", OutFlag::UNSIGNED),
+    ("pid", OutFlag::PID),
+    ("tid", OutFlag::TID),
+    ("port", OutFlag::PORT),
+    ("ipv4", OutFlag::IPV4),
+    ("ipv6", OutFlag::IPV6),
+    ("json", OutFlag::JSON),
+    ("xml", OutFlag::XML),
+    ("utc", OutFlag::DATETIME_UTC),
+];
+
+/// Describes how a field is encoded, for checking which output formats can be used with it.
+struct FieldFormat {
+    /// The InType of the field, or of its elements, without the count flags.
+    in_type: InFlag,
+    /// The OutType of the field, if its type specifies one.
+    out_type: Option<OutFlag>,
+    /// `InFlag::NULL` for a single value, or the count flag of an array.
+    count: InFlag,
+}
+
+impl FieldFormat {
+    fn of(t: &WellKnownTypeInfo, count: InFlag) -> Self {
+        // Pointer-sized integers are checked as 64-bit integers, so that the output formats that
+        // need 32-bit integers cannot be used with them on any target.
+        let in_type = match t.code {
+            WellKnownType::usize => InFlag::UINT64,
+            WellKnownType::isize => InFlag::INT64,
+            _ => t.in_type,
+        };
+        Self {
+            in_type,
+            out_type: t.opts.out_type,
+            count,
+        }
+    }
+
+    /// Returns `true` if the output format `output` can be used with this field.
+    fn allows_output(&self, output: OutFlag) -> bool {
+        // Types such as `bool` and `HRESULT` have their own output format, which cannot be
+        // replaced. JSON and XML can replace the UTF-8 format of strings, because they imply it.
+        if self
+            .out_type
+            .is_some_and(|out_type| out_type != OutFlag::UTF8)
+        {
+            return false;
+        }
+        let is_single = self.count == InFlag::NULL;
+        match output {
+            OutFlag::HEX | OutFlag::SIGNED | OutFlag::UNSIGNED => matches!(
+                self.in_type,
+                InFlag::INT8
+                    | InFlag::UINT8
+                    | InFlag::INT16
+                    | InFlag::UINT16
+                    | InFlag::INT32
+                    | InFlag::UINT32
+                    | InFlag::INT64
+                    | InFlag::UINT64
+            ),
+            OutFlag::PID | OutFlag::TID => {
+                matches!(self.in_type, InFlag::INT32 | InFlag::UINT32)
+            }
+            // These values are converted to big-endian, which is only done for single values.
+            OutFlag::PORT => self.in_type == InFlag::UINT16 && is_single,
+            OutFlag::IPV4 => self.in_type == InFlag::UINT32 && is_single,
+            OutFlag::IPV6 => {
+                (self.in_type == InFlag::BINARY && is_single)
+                    || (self.in_type == InFlag::UINT8 && self.count == InFlag::VCOUNT_FLAG)
+            }
+            OutFlag::JSON | OutFlag::XML => matches!(
+                self.in_type,
+                InFlag::COUNTED_ANSI_STRING | InFlag::COUNTED_UNICODE_STRING
+            ),
+            OutFlag::DATETIME_UTC => self.in_type == InFlag::FILETIME && is_single,
+            _ => false,
+        }
+    }
+}
+
+/// The options that are specified by the `#[event(...)]` attribute of an event field.
+#[derive(Clone, Default)]
+struct FieldAttributes {
+    /// `output = "..."`: The name and the value of an output format. See `OUTPUT_FORMATS`.
+    output: Option<(&'static str, OutFlag)>,
+    /// `variant_name`: Also write the name of an `EventEnum` variant, as a string field.
+    variant_name: bool,
+    /// `has_field`: Write a `bool` field before an `Option<T>` field, which indicates whether the
+    /// value is present.
+    has_field: bool,
+}
+
+impl FieldAttributes {
+    fn parse(errors: &mut Vec<Error>, event_attr: Option<&syn::Attribute>) -> Self {
+        let mut attrs = Self::default();
+        if let Some(event_attr) = event_attr {
+            if let Err(e) = event_attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("has_field") {
+                    attrs.has_field = true;
+                } else if meta.path.is_ident("variant_name") {
+                    attrs.variant_name = true;
+                } else if meta.path.is_ident("output") {
+                    let value: syn::LitStr = meta.value()?.parse()?;
+                    match OUTPUT_FORMATS
+                        .iter()
+                        .find(|(name, _)| *name == value.value())
+                    {
+                        Some(output) => attrs.output = Some(*output),
+                        None => return Err(meta.error("Output format is not recognized.")),
+                    }
+                } else {
+                    return Err(meta.error("This metadata key is not recognized."));
+                }
+                Ok(())
+            }) {
+                errors.push(e);
+            }
+        }
+        attrs
+    }
+}
+
+/// Removes the lifetime of a reference type, or of the reference type in `Option<&T>`.
+fn remove_reference_lifetime(ty: &mut syn::Type) {
+    match ty {
+        syn::Type::Reference(ref_ty) => ref_ty.lifetime = None,
+        syn::Type::Path(path_ty) => {
+            if let Some(segment) = path_ty.path.segments.last_mut() {
+                if segment.ident == "Option" {
+                    if let syn::PathArguments::AngleBracketed(args) = &mut segment.arguments {
+                        for arg in args.args.iter_mut() {
+                            if let syn::GenericArgument::Type(inner_ty) = arg {
+                                remove_reference_lifetime(inner_ty);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Parses an event field whose type implements `AsRef<Path>`. Paths are encoded as counted
+/// UTF-16 strings on Windows, where paths are natively UTF-16, and as counted UTF-8 strings on
+/// other platforms. Paths that are not valid UTF-8 are converted lossily.
+fn parse_path_event_field(
+    errors: &mut Vec<Error>,
+    field_attrs: &FieldAttributes,
+    field_span: proc_macro2::Span,
+    field_name: &Ident,
+    data_descriptor_array: &mut Vec<TokenStream>,
+    event_metadata: &mut Vec<MetadataItem>,
+    statements: &mut TokenStream,
+) -> Result<usize, UnsupportedField> {
+    if field_attrs.output.is_some() || field_attrs.variant_name {
+        errors.push(Error::new(
+            field_span,
+            "This option cannot be used with fields whose type is a path.",
+        ));
+    }
+
+    let field_path = ident_suffix(field_name, "path");
+    let field_buffer = ident_suffix(field_name, "buffer");
+    let field_data = ident_suffix(field_name, "data");
+    let field_len = ident_suffix(field_name, "len");
+    statements.extend(quote_spanned! {
+        field_span =>
+        let #field_path: &::std::path::Path = ::core::convert::AsRef::<::std::path::Path>::as_ref(&#field_name);
+        #[cfg(target_os = "windows")]
+        let #field_buffer: ::win_etw_provider::types::Vec<u16> =
+            ::std::os::windows::ffi::OsStrExt::encode_wide(#field_path.as_os_str())
+                .take(0xffff / 2)
+                .collect();
+        #[cfg(target_os = "windows")]
+        let #field_data: &[u16] = &#field_buffer[..];
+        #[cfg(not(target_os = "windows"))]
+        let #field_buffer = #field_path.to_string_lossy();
+        #[cfg(not(target_os = "windows"))]
+        let #field_data: &[u8] = &#field_buffer.as_bytes()[..#field_buffer.len().min(0xffff)];
+        // The length is in bytes.
+        let #field_len: u16 = ::core::mem::size_of_val(#field_data) as u16;
+    });
+    data_descriptor_array.push(quote!(EventDataDescriptor::from(&#field_len)));
+    data_descriptor_array.push(quote!(EventDataDescriptor::from(#field_data)));
+
+    let mut field_metadata: Vec<Expr> = Vec::new();
+    append_utf8_str_chars(&mut field_metadata, &field_name.to_string());
+    field_metadata.push(parse_quote!(
+        (if cfg!(target_os = "windows") {
+            ::win_etw_provider::metadata::InFlag::COUNTED_UNICODE_STRING.bits()
+        } else {
+            ::win_etw_provider::metadata::InFlag::COUNTED_ANSI_STRING.bits()
+        }) | ::win_etw_provider::metadata::InFlag::CHAIN_FLAG.bits()
+    ));
+    field_metadata.push(parse_quote!(if cfg!(target_os = "windows") {
+        ::win_etw_provider::metadata::OutFlag::NULL.bits()
+    } else {
+        ::win_etw_provider::metadata::OutFlag::UTF8.bits()
+    }));
+    event_metadata.extend(field_metadata.into_iter().map(MetadataItem::Byte));
+    Ok(1)
+}
+
+/// If `ty` is `Option<T>`, returns `T`.
+fn option_inner_type(ty: &syn::Type) -> Option<syn::Type> {
+    match path_type_parts(ty) {
+        Some((ident, args)) if ident == "Option" && args.len() == 1 => Some(args[0].clone()),
+        _ => None,
+    }
+}
+
+/// Parses an event field whose type is `Option<T>`, where `T` is a well-known type, a slice, or a
+/// string or path type that is converted with `AsRef`. The field is encoded as `T`. The generated
+/// code substitutes a placeholder value for `None`, so that the code that encodes `T` can run.
+///
+/// For types that are encoded with a length prefix, such as strings and slices, `None` is encoded
+/// as a zero length by default, so that the value is an empty string or array. For other types,
+/// or if the field has the `has_field` option, a `bool` field named `has_<field>` is written
+/// before the field, and `None` is encoded as the placeholder value. Other types cannot encode
+/// `None` as empty data, because that would shift the data of the fields that follow.
+fn parse_option_event_field(
+    errors: &mut Vec<Error>,
+    well_known_types: &WellKnownTypes,
+    field_attrs: &FieldAttributes,
+    field_span: proc_macro2::Span,
+    field_name: &Ident,
+    field_ty: &mut syn::Type,
+    mut inner_ty: syn::Type,
+    data_descriptor_array: &mut Vec<TokenStream>,
+    event_metadata: &mut Vec<MetadataItem>,
+    statements: &mut TokenStream,
+) -> Result<usize, UnsupportedField> {
+    // Other string types and paths are converted to `Option<&str>` and `Option<&Path>`, and are
+    // encoded in the same way. The type of the parameter is not changed.
+    let is_path_ref =
+        referenced_type(&inner_ty).is_some_and(|elem| is_path_type(elem, "Path", &[]));
+    if let Some(target) = as_ref_target_type(&inner_ty) {
+        if !is_path_ref {
+            let target_ty: syn::Type = match target {
+                AsRefTarget::Str => parse_quote!(str),
+                AsRefTarget::Path => parse_quote!(::std::path::Path),
+            };
+            statements.extend(quote_spanned! {
+                field_span =>
+                let #field_name: ::core::option::Option<&#target_ty> = ::core::option::Option::map(
+                    ::core::option::Option::as_ref(&#field_name),
+                    ::core::convert::AsRef::<#target_ty>::as_ref,
+                );
+            });
+            return parse_option_event_field(
+                errors,
+                well_known_types,
+                field_attrs,
+                field_span,
+                field_name,
+                &mut parse_quote!(::core::option::Option<&#target_ty>),
+                parse_quote!(&#target_ty),
+                data_descriptor_array,
+                event_metadata,
+                statements,
+            );
+        }
+    }
+
+    let (placeholder, has_length_prefix): (Expr, bool) =
+        if let Some(t) = well_known_types.find(&inner_ty) {
+            (
+                t.opts
+                    .none_value
+                    .clone()
+                    .unwrap_or_else(|| parse_quote!(::core::default::Default::default())),
+                matches!(
+                    t.in_type,
+                    InFlag::COUNTED_ANSI_STRING | InFlag::COUNTED_UNICODE_STRING | InFlag::BINARY
+                ),
+            )
+        } else {
+            match &inner_ty {
+                syn::Type::Reference(ref_ty) if matches!(&*ref_ty.elem, syn::Type::Slice(_)) => {
+                    (parse_quote!(::core::default::Default::default()), true)
+                }
+                _ if as_ref_target_type(&inner_ty) == Some(AsRefTarget::Path) => {
+                    (parse_quote!(::std::path::Path::new("")), true)
+                }
+                _ => return Err(UnsupportedField),
+            }
+        };
+
+    let field_is_some = ident_suffix(field_name, "is_some");
+    statements.extend(quote_spanned! {
+        field_span =>
+        let #field_is_some: bool = #field_name.is_some();
+        let #field_name = match #field_name {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => #placeholder,
+        };
+    });
+
+    let inner_attrs = FieldAttributes {
+        has_field: false,
+        ..field_attrs.clone()
+    };
+    let field_count = if field_attrs.has_field || !has_length_prefix {
+        let has_field = Ident::new(&format!("has_{field_name}"), field_name.span());
+        statements.extend(quote_spanned! {
+            field_span =>
+            let #has_field: bool = #field_is_some;
+        });
+        let has_field_count = parse_event_field(
+            errors,
+            well_known_types,
+            &FieldAttributes::default(),
+            field_span,
+            &has_field,
+            &mut parse_quote!(bool),
+            data_descriptor_array,
+            event_metadata,
+            statements,
+        )?;
+        has_field_count
+            + parse_event_field(
+                errors,
+                well_known_types,
+                &inner_attrs,
+                field_span,
+                field_name,
+                &mut inner_ty,
+                data_descriptor_array,
+                event_metadata,
+                statements,
+            )?
+    } else {
+        let mut inner_descriptors: Vec<TokenStream> = Vec::new();
+        let field_count = parse_event_field(
+            errors,
+            well_known_types,
+            &inner_attrs,
+            field_span,
+            field_name,
+            &mut inner_ty,
+            &mut inner_descriptors,
+            event_metadata,
+            statements,
+        )?;
+        // The first descriptor is the length prefix. With a length of zero, there is no data.
+        for (i, descriptor) in inner_descriptors.into_iter().enumerate() {
+            let none_descriptor = if i == 0 {
+                quote!(EventDataDescriptor::from(&0u16))
+            } else {
+                quote!(EventDataDescriptor::empty())
+            };
+            data_descriptor_array.push(quote! {
+                if #field_is_some { #descriptor } else { #none_descriptor }
+            });
+        }
+        field_count
+    };
+
+    // The parameter type uses the replacement type (if any) of `T`.
+    *field_ty = parse_quote!(::core::option::Option<#inner_ty>);
+    Ok(field_count)
 }
 
 /// Represents the "attribute" parameter of the `#[trace_logging_provider]` proc macro.
@@ -1069,6 +2692,19 @@ struct ProviderAttributes {
     uuid: Option<Uuid>,
     provider_name: Option<String>,
     provider_group_guid: Option<Uuid>,
+    backend: Backend,
+    tasks: Vec<(Ident, u16)>,
+    keywords: Vec<(Ident, u64)>,
+}
+
+/// Selects the type that the generated provider uses to write events.
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+enum Backend {
+    /// The generated type contains an `AnyProvider`. This is the default.
+    #[default]
+    Etw,
+    /// The generated type is generic over `P: Provider`, and contains an `Option<P>`.
+    Generic,
 }
 
 impl syn::parse::Parse for ProviderAttributes {
@@ -1076,6 +2712,9 @@ impl syn::parse::Parse for ProviderAttributes {
         let mut uuid_opt = None;
         let mut provider_group_guid: Option<Uuid> = None;
         let mut provider_name = None;
+        let mut backend: Option<Backend> = None;
+        let mut tasks: Option<Vec<(Ident, u16)>> = None;
+        let mut keywords: Option<Vec<(Ident, u64)>> = None;
 
         let parse_guid_value = |lit_str: &syn::LitStr| -> Result<Uuid, syn::Error> {
             let guid_str = lit_str.value();
@@ -1159,6 +2798,24 @@ impl syn::parse::Parse for ProviderAttributes {
                                 "The attribute value is required to be a GUID in string form.",
                             ));
                         }
+                    } else if nv.path.is_ident("backend") {
+                        let value = match &nv.value {
+                            syn::Expr::Path(p) if p.path.is_ident("etw") => Backend::Etw,
+                            syn::Expr::Path(p) if p.path.is_ident("generic") => Backend::Generic,
+                            _ => {
+                                return Err(syn::Error::new_spanned(
+                                    &nv.value,
+                                    "The 'backend' attribute value must be either 'etw' or 'generic'.",
+                                ));
+                            }
+                        };
+                        if backend.is_some() {
+                            return Err(syn::Error::new_spanned(
+                                &nv.path,
+                                "The 'backend' attribute key cannot be specified more than once.",
+                            ));
+                        }
+                        backend = Some(value);
                     } else {
                         return Err(syn::Error::new_spanned(
                             &nv.path,
@@ -1166,6 +2823,47 @@ impl syn::parse::Parse for ProviderAttributes {
                         ));
                     }
                 }
+                syn::Meta::List(list) if list.path.is_ident("tasks") => {
+                    if tasks.is_some() {
+                        return Err(syn::Error::new_spanned(
+                            &list.path,
+                            "The 'tasks' attribute key cannot be specified more than once.",
+                        ));
+                    }
+                    tasks = Some(parse_named_values(list, "tasks")?);
+                }
+                syn::Meta::List(list) if list.path.is_ident("keywords") => {
+                    if keywords.is_some() {
+                        return Err(syn::Error::new_spanned(
+                            &list.path,
+                            "The 'keywords' attribute key cannot be specified more than once.",
+                        ));
+                    }
+                    let values: Vec<(Ident, u64)> = parse_named_values(list, "keywords")?;
+                    for (i, (name, value)) in values.iter().enumerate() {
+                        if *value == 0 {
+                            return Err(syn::Error::new_spanned(
+                                name,
+                                format!("The keyword '{name}' must have at least one bit set."),
+                            ));
+                        }
+                        if value & RESERVED_KEYWORD_BITS != 0 {
+                            return Err(syn::Error::new_spanned(
+                                name,
+                                format!("The keyword '{name}' uses bits that are reserved by Windows (0x{RESERVED_KEYWORD_BITS:016x})."),
+                            ));
+                        }
+                        if let Some((other, _)) = values[..i].iter().find(|(_, v)| v & value != 0) {
+                            return Err(syn::Error::new_spanned(
+                                name,
+                                format!(
+                                    "The keyword '{name}' overlaps with the keyword '{other}'."
+                                ),
+                            ));
+                        }
+                    }
+                    keywords = Some(values);
+                }
                 syn::Meta::Path(path) if path.is_ident("static_mode") => {
                     // eprintln!("Found 'static'");
                 }
@@ -1182,10 +2880,102 @@ impl syn::parse::Parse for ProviderAttributes {
             uuid: uuid_opt,
             provider_name,
             provider_group_guid,
+            backend: backend.unwrap_or_default(),
+            tasks: tasks.unwrap_or_default(),
+            keywords: keywords.unwrap_or_default(),
         })
     }
 }
 
+/// The keyword bits that applications cannot use. The upper 16 bits are reserved for the
+/// `WINEVENT_KEYWORD_*` values, and the next 3 bits for the `MICROSOFT_KEYWORD_*` values
+/// (critical data, measures, telemetry).
+const RESERVED_KEYWORD_BITS: u64 = 0xFFFF_E000_0000_0000;
+
+/// Parses a list of named values, such as `tasks(Connect = 1, Query = 2)`. The names and the
+/// values must be unique.
+fn parse_named_values<T>(list: &syn::MetaList, key: &str) -> syn::Result<Vec<(Ident, T)>>
+where
+    T: core::str::FromStr + PartialEq,
+    T::Err: core::fmt::Display,
+{
+    let items = list.parse_args_with(
+        syn::punctuated::Punctuated::<syn::MetaNameValue, Token![,]>::parse_terminated,
+    )?;
+    let mut values: Vec<(Ident, T)> = Vec::new();
+    for item in items.iter() {
+        let name = match item.path.get_ident() {
+            Some(name) => name.clone(),
+            None => {
+                return Err(syn::Error::new_spanned(
+                    &item.path,
+                    format!("The names in the '{key}' attribute must be identifiers."),
+                ));
+            }
+        };
+        let value: T = match &item.value {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Int(lit_int),
+                ..
+            }) => match lit_int.base10_parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    return Err(syn::Error::new_spanned(
+                        lit_int,
+                        format!("The value of '{name}' is out of range for the '{key}' attribute."),
+                    ));
+                }
+            },
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &item.value,
+                    format!("The values in the '{key}' attribute must be integers."),
+                ));
+            }
+        };
+        if values.iter().any(|(n, _)| *n == name) {
+            return Err(syn::Error::new_spanned(
+                &name,
+                format!("The name '{name}' is specified more than once in the '{key}' attribute."),
+            ));
+        }
+        if values.iter().any(|(_, v)| *v == value) {
+            return Err(syn::Error::new_spanned(
+                &item.value,
+                format!(
+                    "The value of '{name}' is specified more than once in the '{key}' attribute."
+                ),
+            ));
+        }
+        values.push((name, value));
+    }
+    Ok(values)
+}
+
+/// Converts a name such as `DbQuery` to `DB_QUERY`, for use in the name of a constant. An acronym
+/// is a separate word, so `HTTPRequest` is converted to `HTTP_REQUEST`.
+fn to_upper_snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len() + 4);
+    let mut chars = name.chars().peekable();
+    let mut prev: Option<char> = None;
+    while let Some(c) = chars.next() {
+        if c.is_uppercase() {
+            if let Some(prev) = prev {
+                let prev_lower = prev.is_lowercase() || prev.is_ascii_digit();
+                // The last capital letter of an acronym starts the next word.
+                let ends_acronym =
+                    prev.is_uppercase() && chars.peek().is_some_and(|next| next.is_lowercase());
+                if prev_lower || ends_acronym {
+                    result.push('_');
+                }
+            }
+        }
+        prev = Some(c);
+        result.extend(c.to_uppercase());
+    }
+    result
+}
+
 const ETW_EVENT_SOURCE_NAMESPACE: Uuid = uuid::uuid!("482c2db2-c390-47c8-87f8-1a15bfc130fb");
 
 /// Generates a Uuid from a provider name using the same algorithm as .NET's EventSource class.
@@ -1248,12 +3038,118 @@ fn uuid_to_expr(uuid: &Uuid) -> syn::Expr {
     }
 }
 
+/// Describes one event method that is generated. Most trait methods generate a single event, but
+/// an `#[activity]` method generates a start event and a stop event.
+struct EventSpec {
+    method: syn::TraitItemFn,
+    kind: EventKind,
+}
+
+enum EventKind {
+    Event,
+    ActivityStart {
+        stop_ident: Ident,
+        stop_field_count: usize,
+    },
+    ActivityStop {
+        stop_id: Option<u16>,
+    },
+}
+
+/// Finds the methods of the trait, and expands each `#[activity]` method into its start and stop
+/// events.
+fn expand_event_specs(errors: &mut Vec<Error>, logging_trait: &syn::ItemTrait) -> Vec<EventSpec> {
+    let mut specs = Vec::new();
+    for item in logging_trait.items.iter() {
+        let method = match item {
+            syn::TraitItem::Fn(m) => m,
+            _ => continue,
+        };
+        let (activity_attrs, other_attrs): (Vec<syn::Attribute>, Vec<syn::Attribute>) = method
+            .attrs
+            .iter()
+            .cloned()
+            .partition(|a| a.path().is_ident("activity"));
+        if activity_attrs.is_empty() {
+            specs.push(EventSpec {
+                method: method.clone(),
+                kind: EventKind::Event,
+            });
+            continue;
+        }
+        if let Some(extra) = activity_attrs.get(1) {
+            errors.push(Error::new_spanned(
+                extra,
+                "The #[activity] attribute cannot be specified more than once.",
+            ));
+        }
+
+        let mut stop_fields = syn::punctuated::Punctuated::<FnArg, Token![,]>::new();
+        let mut stop_id: Option<u16> = None;
+        if let syn::Meta::List(_) = &activity_attrs[0].meta {
+            if let Err(e) = activity_attrs[0].parse_nested_meta(|meta| {
+                if meta.path.is_ident("stop") {
+                    let content;
+                    syn::parenthesized!(content in meta.input);
+                    stop_fields = syn::punctuated::Punctuated::parse_terminated(&content)?;
+                } else if meta.path.is_ident("stop_id") {
+                    let lit_int: syn::LitInt = meta.value()?.parse()?;
+                    match lit_int.base10_parse() {
+                        Ok(value) => stop_id = Some(value),
+                        Err(_) => return Err(meta.error("Event id must be a u16.")),
+                    }
+                } else {
+                    return Err(meta.error("Unrecognized attribute."));
+                }
+                Ok(())
+            }) {
+                errors.push(e);
+            }
+        }
+
+        let name = &method.sig.ident;
+        let start_ident = Ident::new(&format!("{name}_start"), name.span());
+        let stop_ident = Ident::new(&format!("{name}_stop"), name.span());
+
+        let mut start = method.clone();
+        start.attrs = other_attrs.clone();
+        start.sig.ident = start_ident.clone();
+
+        let stop_doc = format!(
+            "Writes the stop event of the `{name}` activity, which was started by `{start_ident}`."
+        );
+        let mut stop = method.clone();
+        stop.attrs = other_attrs
+            .into_iter()
+            .filter(|a| !a.path().is_ident("doc"))
+            .collect();
+        stop.attrs.insert(0, parse_quote!(#[doc = #stop_doc]));
+        stop.sig.ident = stop_ident.clone();
+        stop.sig.inputs = stop_fields;
+
+        specs.push(EventSpec {
+            method: start,
+            kind: EventKind::ActivityStart {
+                stop_ident,
+                stop_field_count: stop.sig.inputs.len(),
+            },
+        });
+        specs.push(EventSpec {
+            method: stop,
+            kind: EventKind::ActivityStop { stop_id },
+        });
+    }
+    specs
+}
+
 struct EventAttributes {
     level: syn::Expr,
     opcode: syn::Expr,
     task: syn::Expr,
     keyword: Option<syn::Expr>,
     event_id: Option<u16>,
+    version: Option<u8>,
+    channel: Option<u8>,
     method_attrs: Vec<syn::Attribute>,
 }
 
@@ -1261,6 +3157,7 @@ fn parse_event_attributes(
     errors: &mut Vec<Error>,
     method_ident: &Ident,
     input_method_attrs: &[syn::Attribute],
+    provider_attrs: &ProviderAttributes,
 ) -> EventAttributes {
     let mut level: Expr = parse_quote!(::win_etw_provider::Level::VERBOSE);
     let mut opcode: Expr = parse_quote!(0);
@@ -1270,6 +3167,8 @@ fn parse_event_attributes(
     // I am not aware of how to convert from Expr to actual value
     // so going to handle this here.
     let mut event_id: Option<u16> = None;
+    let mut version: Option<u8> = None;
+    let mut channel: Option<u8> = None;
 
     let mut method_attrs: Vec<syn::Attribute> = Vec::new();
 
@@ -1309,27 +3208,95 @@ fn parse_event_attributes(
                         level = parse_quote!(::win_etw_provider::Level(#lit));
                     }
                 } else if meta.path.is_ident("opcode") {
-                    let lit: Lit = meta.value()?.parse()?;
-                    opcode = Expr::Lit(ExprLit {
-                        lit,
-                        attrs: Vec::new(),
-                    });
+                    let value = &meta.value()?;
+                    let lookahead = value.lookahead1();
+                    if lookahead.peek(syn::LitStr) {
+                        let lit_str: syn::LitStr = value.parse()?;
+                        let opcode_ident = match lit_str.value().as_str() {
+                            "info" => quote!(WINEVENT_OPCODE_INFO),
+                            "start" => quote!(WINEVENT_OPCODE_START),
+                            "stop" => quote!(WINEVENT_OPCODE_STOP),
+                            "dc_start" => quote!(WINEVENT_OPCODE_DC_START),
+                            "dc_stop" => quote!(WINEVENT_OPCODE_DC_STOP),
+                            "extension" => quote!(WINEVENT_OPCODE_EXTENSION),
+                            "reply" => quote!(WINEVENT_OPCODE_REPLY),
+                            "resume" => quote!(WINEVENT_OPCODE_RESUME),
+                            "suspend" => quote!(WINEVENT_OPCODE_SUSPEND),
+                            "send" => quote!(WINEVENT_OPCODE_SEND),
+                            "receive" => quote!(WINEVENT_OPCODE_RECEIVE),
+                            _ => {
+                                return Err(meta.error(
+                                    "The value specified for 'opcode' is not a valid string.",
+                                ));
+                            }
+                        };
+                        opcode = parse_quote!(::win_etw_provider::metadata::#opcode_ident);
+                    } else {
+                        let lit: Lit = value.parse()?;
+                        opcode = Expr::Lit(ExprLit {
+                            lit,
+                            attrs: Vec::new(),
+                        });
+                    }
                 } else if meta.path.is_ident("task") {
-                    let lit: Lit = meta.value()?.parse()?;
-                    task = Expr::Lit(ExprLit {
-                        lit,
-                        attrs: Vec::new(),
-                    });
+                    let value = &meta.value()?;
+                    let lookahead = value.lookahead1();
+                    if lookahead.peek(syn::LitStr) {
+                        let lit_str: syn::LitStr = value.parse()?;
+                        let name = lit_str.value();
+                        match provider_attrs.tasks.iter().find(|(n, _)| *n == name) {
+                            Some((_, value)) => task = parse_quote!(#value),
+                            None => {
+                                return Err(meta.error(format!(
+                                    "The task '{name}' is not declared in the 'tasks' attribute of the provider."
+                                )));
+                            }
+                        }
+                    } else {
+                        let lit: Lit = value.parse()?;
+                        task = Expr::Lit(ExprLit {
+                            lit,
+                            attrs: Vec::new(),
+                        });
+                    }
                 } else if meta.path.is_ident("keyword") {
                     if keyword.is_some() {
-                        return Err(meta
-                            .error("The 'keyword' attribute cannot be specified more than once."));
+                        return Err(meta.error(
+                            "The 'keyword' attribute cannot be specified more than once, or together with 'keywords'.",
+                        ));
                     }
                     let lit: Lit = meta.value()?.parse()?;
                     keyword = Some(Expr::Lit(ExprLit {
                         lit,
                         attrs: Vec::new(),
                     }));
+                } else if meta.path.is_ident("keywords") {
+                    if keyword.is_some() {
+                        return Err(meta.error(
+                            "The 'keyword' and 'keywords' attributes cannot be specified together, or more than once.",
+                        ));
+                    }
+                    let content;
+                    syn::parenthesized!(content in meta.input);
+                    let names =
+                        syn::punctuated::Punctuated::<syn::LitStr, Token![,]>::parse_terminated(
+                            &content,
+                        )?;
+                    let mut bits: u64 = 0;
+                    for name in names.iter() {
+                        let name_str = name.value();
+                        match provider_attrs.keywords.iter().find(|(n, _)| *n == name_str) {
+                            Some((_, value)) => bits |= value,
+                            None => {
+                                return Err(syn::Error::new_spanned(
+                                    name,
+                                    format!("The keyword '{name_str}' is not declared in the 'keywords' attribute of the provider."),
+                                ));
+                            }
+                        }
+                    }
+                    let bits = syn::LitInt::new(&format!("0x{bits:x}u64"), meta.path.span());
+                    keyword = Some(parse_quote!(#bits));
                 } else if meta.path.is_ident("id") {
                     if event_id.is_some() {
                         return Err(meta.error("Event id has already been defined."));
@@ -1340,6 +3307,46 @@ fn parse_event_attributes(
                     } else {
                         return Err(meta.error("Event id must be a u16."));
                     }
+                } else if meta.path.is_ident("version") {
+                    if version.is_some() {
+                        return Err(meta
+                            .error("The 'version' attribute cannot be specified more than once."));
+                    }
+                    let lit_int: syn::LitInt = meta.value()?.parse()?;
+                    if let Ok(int_value) = lit_int.base10_parse() {
+                        version = Some(int_value);
+                    } else {
+                        return Err(meta.error("Event version must be a u8."));
+                    }
+                } else if meta.path.is_ident("channel") {
+                    if channel.is_some() {
+                        return Err(meta
+                            .error("The 'channel' attribute cannot be specified more than once."));
+                    }
+                    let value = &meta.value()?;
+                    let lookahead = value.lookahead1();
+                    if lookahead.peek(syn::LitStr) {
+                        let lit_str: syn::LitStr = value.parse()?;
+                        channel = Some(match lit_str.value().as_str() {
+                            "tracelogging" => 11,
+                            "admin" => 16,
+                            "operational" => 17,
+                            "analytic" => 18,
+                            "debug" => 19,
+                            _ => {
+                                return Err(meta.error(
+                                    "The value specified for 'channel' is not a valid string.",
+                                ));
+                            }
+                        });
+                    } else {
+                        let lit_int: syn::LitInt = value.parse()?;
+                        if let Ok(int_value) = lit_int.base10_parse() {
+                            channel = Some(int_value);
+                        } else {
+                            return Err(meta.error("Event channel must be a u8."));
+                        }
+                    }
                 } else {
                     return Err(meta.error("Unrecognized attribute."));
                 }
@@ -1367,6 +3374,8 @@ fn parse_event_attributes(
         task,
         event_id,
         keyword,
+        version,
+        channel,
     }
 }
 
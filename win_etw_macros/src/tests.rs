@@ -47,11 +47,20 @@ impl syn::parse::Parse for CompileErrors {
 }
 
 fn test_worker(attrs: TokenStream, input: TokenStream, expected_errors: &[&'static str]) {
-    let output = trace_logging_events_core(attrs, input);
+    check_macro_output(trace_logging_events_core(attrs, input), expected_errors);
+}
+
+fn test_derive_worker(input: TokenStream, expected_errors: &[&'static str]) {
+    check_macro_output(derive_event_fields_core(input), expected_errors);
+}
+
+fn test_derive_enum_worker(input: TokenStream, expected_errors: &[&'static str]) {
+    check_macro_output(derive_event_enum_core(input), expected_errors);
+}
 
+fn check_macro_output(output: TokenStream, expected_errors: &[&'static str]) {
     // Set WIN_ETW_SHOW_OUTPUT=1 (or = anything at all) to see the output of
-    // the trace_logging_provider macro for unit tests. This is useful during
-    // development.
+    // the macros for unit tests. This is useful during development.
     if std::env::var("WIN_ETW_SHOW_OUTPUT").is_ok() {
         let output_str = format!("{}", output);
         use std::io::Write;
@@ -199,6 +208,15 @@ test_case! {
             #[event(opcode = 10)]
             fn arg_with_opcode(a: u8);
 
+            #[event(version = 2)]
+            fn arg_with_version(a: u8);
+
+            #[event(channel = "operational")]
+            fn arg_with_channel_name(a: u8);
+
+            #[event(channel = 16, version = 1)]
+            fn arg_with_channel_number(a: u8);
+
             fn arg_u32_hex(#[event(output = "hex")] a: u32);
 
             fn arg_hresult(a: HRESULT);
@@ -462,6 +480,468 @@ test_case! {
     ]
 }
 
+test_case! {
+    #[test]
+    fn test_invalid_version_and_channel();
+    input: {
+        #[trace_logging_provider(guid = "00000000-0000-0000-0000-000000000001")]
+        trait Events {
+            #[event(version = 256)]
+            fn event_one();
+
+            #[event(version = 1, version = 2)]
+            fn event_two();
+
+            #[event(channel = "bogus")]
+            fn event_three();
+
+            #[event(channel = 300)]
+            fn event_four();
+
+            #[event(channel = "admin", channel = "debug")]
+            fn event_five();
+        }
+    }
+    expected_errors: [
+        "Event version must be a u8.",
+        "The 'version' attribute cannot be specified more than once.",
+        "The value specified for 'channel' is not a valid string.",
+        "Event channel must be a u8.",
+        "The 'channel' attribute cannot be specified more than once.",
+    ]
+}
+
+test_case! {
+    #[test]
+    fn test_named_opcodes_and_tasks();
+    input: {
+        #[trace_logging_provider(guid = "00000000-0000-0000-0000-000000000001", tasks(Connect = 1))]
+        trait Events {
+            #[event(task = "Connect", opcode = "start")]
+            fn connect_start();
+
+            #[event(task = "Connect", opcode = "stop")]
+            fn connect_stop();
+
+            #[event(opcode = "begin")]
+            fn bad_opcode();
+
+            #[event(task = "Query")]
+            fn bad_task();
+        }
+    }
+    expected_errors: [
+        "The value specified for 'opcode' is not a valid string.",
+        "The task 'Query' is not declared in the 'tasks' attribute of the provider.",
+    ]
+}
+
+test_case! {
+    #[test]
+    fn test_named_keywords();
+    input: {
+        #[trace_logging_provider(guid = "00000000-0000-0000-0000-000000000001", keywords(Network = 1, Storage = 2))]
+        trait Events {
+            #[event(keywords("Network", "Storage"))]
+            fn good();
+
+            #[event(keywords("Memory"))]
+            fn undeclared();
+
+            #[event(keyword = 1, keywords("Network"))]
+            fn both();
+        }
+    }
+    expected_errors: [
+        "The keyword 'Memory' is not declared in the 'keywords' attribute of the provider.",
+        "The 'keyword' and 'keywords' attributes cannot be specified together, or more than once.",
+    ]
+}
+
+test_case! {
+    #[test]
+    fn test_activities();
+    input: {
+        #[trace_logging_provider(guid = "00000000-0000-0000-0000-000000000001")]
+        trait Events {
+            #[activity(stop(status: u32, #[event(output = "hex")] code: u32))]
+            fn request(url: &str);
+
+            #[activity]
+            fn no_stop_fields();
+
+            #[activity(stop(a: ()))]
+            fn bad_stop_field();
+
+            #[activity(finish(a: u32))]
+            fn bad_key();
+
+            #[activity]
+            #[activity]
+            fn twice();
+        }
+    }
+    expected_errors: [
+        "This type is not supported for event parameters.",
+        "Unrecognized attribute.",
+        "The #[activity] attribute cannot be specified more than once.",
+    ]
+}
+
+test_case! {
+    #[test]
+    fn test_event_fields();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn by_ref(a: &RequestInfo);
+            fn by_value(a: RequestInfo, b: u32);
+            fn with_lifetime(a: &requests::RequestInfo<'_>);
+            fn hex(#[event(output = "hex")] a: &RequestInfo);
+            fn tuple(a: (u32, u32));
+        }
+    }
+    expected_errors: [
+        "Output formats cannot be used with fields whose type implements EventField.",
+        "This type is not supported for event parameters.",
+    ]
+}
+
+test_case! {
+    #[test]
+    fn test_event_enum();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn state(a: State, #[event(variant_name)] b: &State);
+            fn not_enum(#[event(variant_name)] a: u32);
+        }
+    }
+    expected_errors: [
+        "The variant_name option can only be used with fields whose type derives EventEnum.",
+    ]
+}
+
+#[test]
+fn derive_event_fields() {
+    test_derive_worker(
+        quote! {
+            struct RequestInfo<'a, T: EventFields> {
+                url: &'a str,
+                #[event(output = "hex")]
+                flags: u32,
+                sizes: &'a [u16],
+                id: &'a GUID,
+                inner: T,
+                inner_ref: &'a Inner,
+            }
+        },
+        &[],
+    );
+}
+
+#[test]
+fn derive_event_fields_errors() {
+    for input in [
+        quote! { enum E { A } },
+        quote! { struct S(u32); },
+        quote! { struct S; },
+    ] {
+        test_derive_worker(
+            input,
+            &["EventFields can only be derived for structs with named fields."],
+        );
+    }
+    test_derive_worker(
+        quote! { struct S {} },
+        &["A struct that derives EventFields must have between 1 and 127 fields."],
+    );
+    test_derive_worker(
+        quote! {
+            struct S {
+                a: (),
+                #[event(output = "octal")]
+                b: u32,
+                #[event(output = "hex")]
+                #[event(output = "hex")]
+                c: u32,
+            }
+        },
+        &[
+            "This type is not supported for event fields.",
+            "Output format is not recognized.",
+            "The #[event] attribute cannot be specified more than once.",
+        ],
+    );
+}
+
+test_case! {
+    #[test]
+    fn test_option();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn options(
+                a: Option<u32>,
+                b: Option<&str>,
+                #[event(has_field)] c: Option<&GUID>,
+                #[event(output = "hex")] d: core::option::Option<u64>,
+                e: Option<&[u8]>,
+            );
+            fn not_option(#[event(has_field)] a: u32);
+            fn nested(a: Option<Option<u32>>);
+            fn user_type(a: Option<Info>);
+        }
+    }
+    expected_errors: [
+        "The has_field option can only be used with fields whose type is Option<T>.",
+        "This type is not supported for event parameters.",
+        "This type is not supported for event parameters.",
+    ]
+}
+
+test_case! {
+    #[test]
+    fn test_fixed_size_arrays();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn arrays(a: [u8; 32], b: &[u16; 4], #[event(output = "hex")] c: [u32; LEN], d: &[usize; 2]);
+            fn strings(a: [&str; 2]);
+            fn nested(a: [[u8; 2]; 2]);
+        }
+    }
+    expected_errors: [
+        "This type is not supported for event parameters.",
+        "This type is not supported for event parameters.",
+    ]
+}
+
+test_case! {
+    #[test]
+    fn test_string_and_guid_arrays();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn arrays(a: &[&str], b: &[String], c: &[GUID], d: Option<&[&str]>);
+            fn wide_strings(a: &[&U16Str]);
+            fn guid_refs(a: &[&GUID]);
+        }
+    }
+    expected_errors: [
+        "This type is not supported for event parameters.",
+        "This type is not supported for event parameters.",
+    ]
+}
+
+#[test]
+fn derive_event_fields_arrays() {
+    test_derive_worker(
+        quote! {
+            struct Hosts<'a> {
+                names: &'a [&'a str],
+                aliases: &'a [String],
+                ids: &'a [GUID],
+            }
+        },
+        &[],
+    );
+}
+
+test_case! {
+    #[test]
+    fn test_owned_strings_and_paths();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn strings(a: String, b: &String, c: Cow<'_, str>, d: Box<str>, e: impl AsRef<str>);
+            fn paths(a: &Path, b: &PathBuf, c: PathBuf, d: impl AsRef<Path>);
+            fn optional_strings(a: Option<String>, b: Option<Cow<'_, str>>, c: Option<impl AsRef<str>>);
+            fn optional_paths(a: Option<&Path>, #[event(has_field)] b: Option<PathBuf>);
+            fn hex_path(#[event(output = "hex")] a: &Path);
+            fn other_impl(a: impl AsRef<[u8]>);
+        }
+    }
+    expected_errors: [
+        "This option cannot be used with fields whose type is a path.",
+        "This type is not supported for event parameters.",
+    ]
+}
+
+test_case! {
+    #[test]
+    fn test_qualified_type_names();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn times(a: std::time::SystemTime, b: ::std::time::SystemTime, c: windows_sys::Win32::Foundation::FILETIME);
+            fn addresses(a: &core::net::SocketAddr, b: &std::net::SocketAddrV4, c: Option<&std::net::SocketAddrV6>);
+            fn guids(a: &win_etw_provider::GUID, b: &windows_sys::core::GUID, c: &[windows_sys::core::GUID]);
+        }
+    }
+    expected_errors: []
+}
+
+#[test]
+fn qualified_type_names() {
+    let wk = WellKnownTypes::new();
+    let find = |ty: syn::Type| wk.find(&ty).map(|t| t.code);
+    assert!(find(parse_quote!(std::time::SystemTime)) == Some(WellKnownType::SystemTime));
+    assert!(find(parse_quote!(::core::net::SocketAddr)).is_none());
+    assert!(find(parse_quote!(&::core::net::SocketAddr)) == Some(WellKnownType::SocketAddr));
+    assert!(find(parse_quote!(&windows_sys::core::GUID)) == Some(WellKnownType::guid));
+    assert!(find(parse_quote!(my_crate::SystemTime)).is_none());
+    assert!(find(parse_quote!(Timestamp)).is_none());
+}
+
+test_case! {
+    #[test]
+    fn test_output_formats();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn ids(#[event(output = "pid")] a: u32, #[event(output = "tid")] b: i32);
+            fn addresses(
+                #[event(output = "port")] a: u16,
+                #[event(output = "ipv4")] b: u32,
+                #[event(output = "ipv6")] c: &[u8],
+                #[event(output = "port")] d: Option<u16>,
+            );
+            fn integers(
+                #[event(output = "signed")] a: u64,
+                #[event(output = "unsigned")] b: i8,
+                #[event(output = "hex")] c: &[usize],
+            );
+            fn text(
+                #[event(output = "json")] a: &str,
+                #[event(output = "xml")] b: String,
+                #[event(output = "json")] c: &U16Str,
+                #[event(output = "xml")] d: &[&str],
+            );
+            fn times(#[event(output = "utc")] a: SystemTime, #[event(output = "utc")] b: FILETIME);
+        }
+    }
+    expected_errors: []
+}
+
+test_case! {
+    #[test]
+    fn test_ip_addresses();
+    input: {
+        #[trace_logging_provider(guid = "610259b8-9270-46f2-ad94-2f805721b287")]
+        trait Events {
+            fn addresses(a: Ipv4Addr, b: Ipv6Addr, c: IpAddr, d: core::net::Ipv4Addr);
+            fn optional(a: Option<Ipv4Addr>, b: Option<std::net::Ipv6Addr>, #[event(has_field)] c: Option<IpAddr>);
+        }
+    }
+    expected_errors: []
+}
+
+#[test]
+fn output_format_errors() {
+    for (output, ty) in [
+        ("pid", quote!(u64)),
+        ("pid", quote!(usize)),
+        ("tid", quote!(u16)),
+        ("port", quote!(u32)),
+        ("port", quote!(&[u16])),
+        ("ipv4", quote!([u32; 2])),
+        ("ipv4", quote!(i32)),
+        ("ipv6", quote!([u8; 16])),
+        ("ipv6", quote!(u64)),
+        ("json", quote!(u32)),
+        ("xml", quote!(&GUID)),
+        ("hex", quote!(&str)),
+        ("hex", quote!(bool)),
+        ("hex", quote!(HRESULT)),
+        ("signed", quote!(f64)),
+        ("utc", quote!(u64)),
+        ("utc", quote!(&SocketAddr)),
+        ("ipv4", quote!(Ipv4Addr)),
+        ("hex", quote!(IpAddr)),
+    ] {
+        let expected_error =
+            format!("The output format \"{output}\" cannot be used with fields of this type.");
+        test_worker(
+            quote!(guid = "610259b8-9270-46f2-ad94-2f805721b287"),
+            quote! {
+                trait Events {
+                    fn event(#[event(output = #output)] a: #ty);
+                }
+            },
+            &[Box::leak(expected_error.into_boxed_str())],
+        );
+    }
+}
+
+#[test]
+fn derive_event_enum() {
+    for repr in [quote!(u8), quote!(i16), quote!(C, u32), quote!(u64)] {
+        test_derive_enum_worker(
+            quote! {
+                #[repr(#repr)]
+                enum State {
+                    A,
+                    B = 10,
+                }
+            },
+            &[],
+        );
+    }
+}
+
+#[test]
+fn derive_event_enum_errors() {
+    for input in [
+        quote! { #[repr(u8)] enum E { A(u8) } },
+        quote! { #[repr(u8)] enum E { A { a: u8 } } },
+        quote! { struct S { a: u8 } },
+    ] {
+        test_derive_enum_worker(
+            input,
+            &["EventEnum can only be derived for enums whose variants do not have fields."],
+        );
+    }
+    for input in [
+        quote! { enum E { A } },
+        quote! { #[repr(C)] enum E { A } },
+        quote! { #[repr(usize)] enum E { A } },
+    ] {
+        test_derive_enum_worker(
+            input,
+            &["An enum that derives EventEnum must have a #[repr] attribute that specifies an integer type"],
+        );
+    }
+}
+
+test_case! {
+    #[test]
+    fn test_activity_ids();
+    input: {
+        #[trace_logging_provider(guid = "00000000-0000-0000-0000-000000000001")]
+        trait Events {
+            #[event(id = 1)]
+            fn event();
+
+            #[event(id = 2)]
+            #[activity(stop_id = 3)]
+            fn good();
+
+            #[event(id = 4)]
+            #[activity(stop_id = 1)]
+            fn duplicate();
+
+            #[event(id = 5)]
+            #[activity]
+            fn missing();
+        }
+    }
+    expected_errors: [
+        "Event id 1 has already been defined on event.",
+        "Event ids must be set for all events, or for none.",
+    ]
+}
+
 test_case! {
     #[test]
     fn test_event_attributes_others_forbidden();
@@ -553,6 +1033,19 @@ test_case! {
     ]
 }
 
+test_case! {
+    #[test]
+    fn generic_backend();
+    input: {
+        #[trace_logging_provider(guid = "00000000-0000-0000-0000-000000000001", backend = generic)]
+        trait Events
+        {
+            fn foo(a: u32, b: &str);
+        }
+    }
+    expected_errors: []
+}
+
 test_case! {
     #[test]
     fn event_with_keyword();
@@ -653,6 +1146,114 @@ fn provider_attributes_valid_static() {
     assert!(result.is_ok(), "Result: {:?}", result);
 }
 
+#[test]
+fn provider_attributes_backend() {
+    let result = syn::parse2::<ProviderAttributes>(quote! {
+        guid = "610259b8-9270-46f2-ad94-2f805721b287", backend = generic
+    });
+    assert_eq!(result.unwrap().backend, Backend::Generic);
+
+    let result = syn::parse2::<ProviderAttributes>(quote! { backend = etw });
+    assert_eq!(result.unwrap().backend, Backend::Etw);
+}
+
+#[test]
+fn provider_attributes_tasks() {
+    let result = syn::parse2::<ProviderAttributes>(quote! {
+        tasks(Connect = 1, DbQuery = 0x20)
+    })
+    .unwrap();
+    let tasks: Vec<(String, u16)> = result
+        .tasks
+        .iter()
+        .map(|(name, value)| (name.to_string(), *value))
+        .collect();
+    assert_eq!(
+        tasks,
+        vec![("Connect".to_string(), 1), ("DbQuery".to_string(), 0x20)]
+    );
+    assert_eq!(to_upper_snake_case("DbQuery"), "DB_QUERY");
+    assert_eq!(to_upper_snake_case("HTTPRequest2"), "HTTP_REQUEST2");
+    assert_eq!(to_upper_snake_case("GetHTTP"), "GET_HTTP");
+    assert_eq!(to_upper_snake_case("IOError"), "IO_ERROR");
+    assert_eq!(to_upper_snake_case("Io2Db"), "IO2_DB");
+    assert_eq!(to_upper_snake_case("A"), "A");
+}
+
+#[test]
+fn provider_attributes_invalid_tasks() {
+    test_provider_attributes_error(
+        quote!(tasks(A = 1), tasks(B = 2)),
+        &["The 'tasks' attribute key cannot be specified more than once."],
+    );
+    test_provider_attributes_error(
+        quote!(tasks(A = 1, A = 2)),
+        &["The name 'A' is specified more than once in the 'tasks' attribute."],
+    );
+    test_provider_attributes_error(
+        quote!(tasks(A = 1, B = 1)),
+        &["The value of 'B' is specified more than once in the 'tasks' attribute."],
+    );
+    test_provider_attributes_error(
+        quote!(tasks(A = 70000)),
+        &["The value of 'A' is out of range for the 'tasks' attribute."],
+    );
+    test_provider_attributes_error(
+        quote!(tasks(A = "one")),
+        &["The values in the 'tasks' attribute must be integers."],
+    );
+    test_provider_attributes_error(
+        quote!(tasks(a::b = 1)),
+        &["The names in the 'tasks' attribute must be identifiers."],
+    );
+}
+
+#[test]
+fn provider_attributes_keywords() {
+    let result = syn::parse2::<ProviderAttributes>(quote! {
+        keywords(Network = 0x1, Storage = 0x6, Last = 0x0000_1000_0000_0000)
+    })
+    .unwrap();
+    let keywords: Vec<u64> = result.keywords.iter().map(|(_, value)| *value).collect();
+    assert_eq!(keywords, vec![0x1, 0x6, 0x0000_1000_0000_0000]);
+}
+
+#[test]
+fn provider_attributes_invalid_keywords() {
+    test_provider_attributes_error(
+        quote!(keywords(A = 1), keywords(B = 2)),
+        &["The 'keywords' attribute key cannot be specified more than once."],
+    );
+    test_provider_attributes_error(
+        quote!(keywords(A = 0x3, B = 0x6)),
+        &["The keyword 'B' overlaps with the keyword 'A'."],
+    );
+    test_provider_attributes_error(
+        quote!(keywords(A = 0)),
+        &["The keyword 'A' must have at least one bit set."],
+    );
+    test_provider_attributes_error(
+        quote!(keywords(Telemetry = 0x0000_2000_0000_0000)),
+        &["The keyword 'Telemetry' uses bits that are reserved by Windows"],
+    );
+    test_provider_attributes_error(
+        quote!(keywords(Winevent = 0x8000_0000_0000_0000)),
+        &["The keyword 'Winevent' uses bits that are reserved by Windows"],
+    );
+}
+
+#[test]
+fn provider_attributes_invalid_backend() {
+    test_provider_attributes_error(
+        quote!(backend = "generic"),
+        &["The 'backend' attribute value must be either 'etw' or 'generic'."],
+    );
+    test_provider_attributes_error(
+        quote!(backend = generic, backend = etw),
+        &["The 'backend' attribute key cannot be specified more than once."],
+    );
+}
+
 fn check_errors(error: &Error, expected_errors: &[&str]) {
     let mut error_strings: Vec<String> = error.into_iter().map(|e| format!("{}", e)).collect();
 
@@ -674,7 +1275,7 @@ fn check_errors(error: &Error, expected_errors: &[&str]) {
         }
     }
 
-    for error_string in error_strings.iter() {
+    if let Some(error_string) = error_strings.first() {
         panic!("Unexpected error: {}", error_string);
     }
 }
@@ -17,8 +17,71 @@ pub struct WellKnownTypeOptions {
     pub out_type: Option<OutFlag>,
     pub in_type_expr: Option<syn::Expr>,
     pub replacement_type: Option<syn::Type>,
-    #[allow(unused)]
-    pub can_output_hex: bool,
+    /// The value that is encoded in place of `None`, for an `Option<T>` field. If this is not
+    /// specified, then `T::default()` is used.
+    pub none_value: Option<syn::Expr>,
+}
+
+/// Fully-qualified paths of well-known types, and the names that they are declared with in the
+/// table below. A path may also begin with `::`.
+const QUALIFIED_NAMES: &[(&str, &str)] = &[
+    ("std::time::SystemTime", "SystemTime"),
+    ("std::net::SocketAddr", "SocketAddr"),
+    ("std::net::SocketAddrV4", "SocketAddrV4"),
+    ("std::net::SocketAddrV6", "SocketAddrV6"),
+    ("core::net::SocketAddr", "SocketAddr"),
+    ("core::net::SocketAddrV4", "SocketAddrV4"),
+    ("core::net::SocketAddrV6", "SocketAddrV6"),
+    ("std::net::IpAddr", "IpAddr"),
+    ("std::net::Ipv4Addr", "Ipv4Addr"),
+    ("std::net::Ipv6Addr", "Ipv6Addr"),
+    ("core::net::IpAddr", "IpAddr"),
+    ("core::net::Ipv4Addr", "Ipv4Addr"),
+    ("core::net::Ipv6Addr", "Ipv6Addr"),
+    ("std::ffi::OsStr", "OsStr"),
+    ("widestring::U16Str", "U16Str"),
+    ("widestring::U16CStr", "U16CStr"),
+    ("win_etw_provider::GUID", "GUID"),
+    ("win_etw_provider::FILETIME", "FILETIME"),
+    ("windows_sys::core::GUID", "GUID"),
+    ("windows_sys::core::HRESULT", "HRESULT"),
+    ("windows_sys::Win32::Foundation::FILETIME", "FILETIME"),
+];
+
+/// If `ty` (or the type that `ty` refers to) is a fully-qualified path of a well-known type,
+/// returns the same type, using the name that the well-known type is declared with. Type aliases
+/// cannot be resolved by a macro; they are handled by the `EventField` trait instead.
+pub fn canonical_type(ty: &syn::Type) -> Option<syn::Type> {
+    match ty {
+        syn::Type::Reference(ref_ty) => {
+            let mut ref_ty = ref_ty.clone();
+            *ref_ty.elem = canonical_type(&ref_ty.elem)?;
+            Some(syn::Type::Reference(ref_ty))
+        }
+        syn::Type::Path(path_ty) if path_ty.qself.is_none() => {
+            let path = &path_ty.path;
+            if path.segments.len() < 2
+                || path
+                    .segments
+                    .iter()
+                    .any(|segment| !segment.arguments.is_empty())
+            {
+                return None;
+            }
+            let path_string = path
+                .segments
+                .iter()
+                .map(|segment| segment.ident.to_string())
+                .collect::<Vec<String>>()
+                .join("::");
+            let (_, name) = QUALIFIED_NAMES
+                .iter()
+                .find(|(qualified, _)| *qualified == path_string)?;
+            let name = syn::Ident::new(name, path.segments.last()?.ident.span());
+            Some(parse_quote!(#name))
+        }
+        _ => None,
+    }
 }
 
 macro_rules! well_known_types{
@@ -66,6 +129,8 @@ macro_rules! well_known_types{
             }
 
             pub fn find(&self, ty: &syn::Type) -> Option<&WellKnownTypeInfo> {
+                let canonical = canonical_type(ty);
+                let ty = canonical.as_ref().unwrap_or(ty);
                 $(
                     if *ty == self.$t.ty {
                         return Some(&self.$t);
@@ -84,27 +149,25 @@ well_known_types! {
         in_type: InFlag::UINT8,
         out_type: Some(OutFlag::BOOLEAN),
     }
-    u8: u8 => { is_ref: false, primitive: true, in_type: InFlag::UINT8, can_output_hex: true, }
-    u16: u16 => { is_ref: false, primitive: true, in_type: InFlag::UINT16, can_output_hex: true, }
-    u32: u32 => { is_ref: false, primitive: true, in_type: InFlag::UINT32, can_output_hex: true, }
-    u64: u64 => { is_ref: false, primitive: true, in_type: InFlag::UINT64, can_output_hex: true, }
-    i8: i8 => { is_ref: false, primitive: true, in_type: InFlag::INT8, can_output_hex: true, }
-    i16: i16 => { is_ref: false, primitive: true, in_type: InFlag::INT16, can_output_hex: true, }
-    i32: i32 => { is_ref: false, primitive: true, in_type: InFlag::INT32, can_output_hex: true, }
-    i64: i64 => { is_ref: false, primitive: true, in_type: InFlag::INT64, can_output_hex: true, }
+    u8: u8 => { is_ref: false, primitive: true, in_type: InFlag::UINT8, }
+    u16: u16 => { is_ref: false, primitive: true, in_type: InFlag::UINT16, }
+    u32: u32 => { is_ref: false, primitive: true, in_type: InFlag::UINT32, }
+    u64: u64 => { is_ref: false, primitive: true, in_type: InFlag::UINT64, }
+    i8: i8 => { is_ref: false, primitive: true, in_type: InFlag::INT8, }
+    i16: i16 => { is_ref: false, primitive: true, in_type: InFlag::INT16, }
+    i32: i32 => { is_ref: false, primitive: true, in_type: InFlag::INT32, }
+    i64: i64 => { is_ref: false, primitive: true, in_type: InFlag::INT64, }
     f32: f32 => { is_ref: false, primitive: true, in_type: InFlag::FLOAT, }
     f64: f64 => { is_ref: false, primitive: true, in_type: InFlag::DOUBLE, }
     usize: usize => { is_ref: false, primitive: true, in_type: InFlag::NULL,
         in_type_expr: Some(parse_quote!{
             ::win_etw_provider::metadata::InFlag::USIZE.bits()
         }),
-        can_output_hex: true,
     }
     isize: isize => { is_ref: false, primitive: true, in_type: InFlag::NULL,
         in_type_expr: Some(parse_quote!{
             ::win_etw_provider::metadata::InFlag::ISIZE.bits()
         }),
-        can_output_hex: true,
     }
     ref_str: &str => {
         is_ref: true,
@@ -134,6 +197,12 @@ well_known_types! {
         is_ref: true, primitive: false,
         in_type: InFlag::GUID,
         replacement_type: Some(parse_quote!(&::win_etw_provider::GUID)),
+        none_value: Some(parse_quote!(&::win_etw_provider::GUID {
+            data1: 0,
+            data2: 0,
+            data3: 0,
+            data4: [0; 8],
+        })),
     }
     SocketAddrV4: &SocketAddrV4 => {
         is_ref: false,
@@ -141,6 +210,11 @@ well_known_types! {
         in_type: InFlag::BINARY,
         out_type: Some(OutFlag::SOCKETADDRESS),
         replacement_type: Some(parse_quote!(&::std::net::SocketAddrV4)),
+        none_value: Some(parse_quote!({
+            const NONE: ::std::net::SocketAddrV4 =
+                ::std::net::SocketAddrV4::new(::std::net::Ipv4Addr::UNSPECIFIED, 0);
+            &NONE
+        })),
     }
     SocketAddrV6: &SocketAddrV6 => {
         is_ref: false,
@@ -148,6 +222,11 @@ well_known_types! {
         in_type: InFlag::BINARY,
         out_type: Some(OutFlag::SOCKETADDRESS),
         replacement_type: Some(parse_quote!(&::std::net::SocketAddrV6)),
+        none_value: Some(parse_quote!({
+            const NONE: ::std::net::SocketAddrV6 =
+                ::std::net::SocketAddrV6::new(::std::net::Ipv6Addr::UNSPECIFIED, 0, 0, 0);
+            &NONE
+        })),
     }
     SocketAddr: &SocketAddr => {
         is_ref: false,
@@ -155,18 +234,50 @@ well_known_types! {
         in_type: InFlag::BINARY,
         out_type: Some(OutFlag::SOCKETADDRESS),
         replacement_type: Some(parse_quote!(&::std::net::SocketAddr)),
+        none_value: Some(parse_quote!({
+            const NONE: ::std::net::SocketAddr = ::std::net::SocketAddr::V4(
+                ::std::net::SocketAddrV4::new(::std::net::Ipv4Addr::UNSPECIFIED, 0),
+            );
+            &NONE
+        })),
+    }
+    Ipv4Addr: Ipv4Addr => {
+        is_ref: false,
+        primitive: false,
+        in_type: InFlag::UINT32,
+        out_type: Some(OutFlag::IPV4),
+        replacement_type: Some(parse_quote!(::std::net::Ipv4Addr)),
+        none_value: Some(parse_quote!(::std::net::Ipv4Addr::UNSPECIFIED)),
+    }
+    Ipv6Addr: Ipv6Addr => {
+        is_ref: false,
+        primitive: false,
+        in_type: InFlag::BINARY,
+        out_type: Some(OutFlag::IPV6),
+        replacement_type: Some(parse_quote!(::std::net::Ipv6Addr)),
+        none_value: Some(parse_quote!(::std::net::Ipv6Addr::UNSPECIFIED)),
+    }
+    IpAddr: IpAddr => {
+        is_ref: false,
+        primitive: false,
+        in_type: InFlag::BINARY,
+        out_type: Some(OutFlag::IPV6),
+        replacement_type: Some(parse_quote!(::std::net::IpAddr)),
+        none_value: Some(parse_quote!(::std::net::IpAddr::V6(::std::net::Ipv6Addr::UNSPECIFIED))),
     }
     SystemTime: SystemTime => {
         is_ref: false,
         primitive: false,
         in_type: InFlag::FILETIME,
         replacement_type: Some(parse_quote!(::std::time::SystemTime)),
+        none_value: Some(parse_quote!(::std::time::UNIX_EPOCH)),
     }
     FILETIME: FILETIME => {
         is_ref: true,
         primitive: false,
         in_type: InFlag::FILETIME,
         replacement_type: Some(parse_quote!(::win_etw_provider::FILETIME)),
+        none_value: Some(parse_quote!(::win_etw_provider::FILETIME(0))),
     }
     HRESULT: HRESULT => {
         is_ref: false,
@@ -0,0 +1,120 @@
+/// The maximum number of bytes that a `MetadataBuilder` can hold.
+pub const MAX_METADATA_BUILDER_LEN: usize = 4096;
+
+/// Builds event metadata in a `const` context.
+///
+/// Most event metadata is generated by `win_etw_macros` as a literal byte array. However, the
+/// metadata of a field whose type implements `EventFields` is defined by that type, so its length
+/// is not known when the macro runs. In that case, the macro concatenates the pieces of the
+/// metadata with a `MetadataBuilder`, and then copies the result into a `static` array.
+///
+/// The builder has a fixed capacity of `MAX_METADATA_BUILDER_LEN` bytes. Exceeding the capacity
+/// causes a compile-time error, when the builder is used in a `const` context.
+#[derive(Clone, Copy)]
+pub struct MetadataBuilder {
+    bytes: [u8; MAX_METADATA_BUILDER_LEN],
+    len: usize,
+}
+
+impl MetadataBuilder {
+    /// Creates an empty builder.
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; MAX_METADATA_BUILDER_LEN],
+            len: 0,
+        }
+    }
+
+    /// Appends `bytes`.
+    pub const fn bytes(mut self, bytes: &[u8]) -> Self {
+        let mut i = 0;
+        while i < bytes.len() {
+            if self.len == MAX_METADATA_BUILDER_LEN {
+                panic!(
+                    "Event metadata is too large to encode; reduce the complexity of this event."
+                );
+            }
+            self.bytes[self.len] = bytes[i];
+            self.len += 1;
+            i += 1;
+        }
+        self
+    }
+
+    /// Appends the contents of another builder.
+    pub const fn append(self, other: &MetadataBuilder) -> Self {
+        self.bytes(other.as_bytes())
+    }
+
+    /// Stores the length of the metadata in its first two bytes, in little-endian order. Event
+    /// metadata begins with its own length; the caller must reserve the first two bytes.
+    pub const fn with_size_prefix(mut self) -> Self {
+        if self.len < 2 || self.len > 0xffff {
+            panic!("Event metadata is too large to encode; reduce the complexity of this event.");
+        }
+        self.bytes[0] = self.len as u8;
+        self.bytes[1] = (self.len >> 8) as u8;
+        self
+    }
+
+    /// The number of bytes in the builder.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the builder is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The contents of the builder.
+    pub const fn as_bytes(&self) -> &[u8] {
+        self.bytes.split_at(self.len).0
+    }
+
+    /// Copies the contents of the builder into an array. `N` must be equal to `self.len()`.
+    pub const fn to_array<const N: usize>(&self) -> [u8; N] {
+        if N != self.len {
+            panic!("The array length must be equal to the length of the metadata.");
+        }
+        let mut array = [0; N];
+        let mut i = 0;
+        while i < N {
+            array[i] = self.bytes[i];
+            i += 1;
+        }
+        array
+    }
+}
+
+impl Default for MetadataBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for MetadataBuilder {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("MetadataBuilder")
+            .field(&self.as_bytes())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build() {
+        const INNER: MetadataBuilder = MetadataBuilder::new().bytes(b"b\x00\x08");
+        const OUTER: MetadataBuilder = MetadataBuilder::new()
+            .bytes(&[0, 0, 0])
+            .bytes(b"ev\x00")
+            .append(&INNER)
+            .with_size_prefix();
+        static ARRAY: [u8; OUTER.len()] = OUTER.to_array();
+        assert_eq!(&ARRAY, b"\x09\x00\x00ev\x00b\x00\x08");
+        assert!(MetadataBuilder::default().is_empty());
+    }
+}
@@ -0,0 +1,726 @@
+//! Decodes TraceLogging metadata and event payloads.
+//!
+//! TraceLogging events are self-describing. Each event carries two blocks of metadata: the
+//! provider metadata (which contains the provider name and traits) and the event metadata
+//! (which contains the event name and the name and type of each field). This module parses
+//! both kinds of metadata, and uses the event metadata to split an event payload into typed
+//! values. It does not depend on any Windows APIs, such as `tdh.dll`.
+//!
+//! The layout of the metadata is described in `traceloggingprovider.h`, in the Windows SDK.
+//!
+//! ```
+//! use win_etw_metadata::decode::{decode_event, Value};
+//!
+//! // Metadata for an event named "hello" with one field, "a: u32".
+//! let metadata = b"\x0c\x00\x00hello\x00a\x00\x08";
+//! let event = decode_event(metadata, &42u32.to_le_bytes()).unwrap();
+//! assert_eq!(event.name, "hello");
+//! assert_eq!(event.field("a"), Some(&Value::U32(42)));
+//! ```
+
+use crate::{InFlag, OutFlag};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Describes why metadata or an event payload could not be decoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum DecodeError {
+    /// The input ended before a complete item could be read.
+    UnexpectedEnd,
+    /// The size that is stored at the start of a metadata block is not valid.
+    InvalidSize,
+    /// A field uses an `InFlag` type that this decoder does not support.
+    UnsupportedType(u8),
+    /// The payload contains data after the last field.
+    TrailingData,
+    /// Structures are nested more deeply than `MAX_STRUCT_DEPTH`.
+    NestingTooDeep,
+    /// The size of a counted UTF-16 string is an odd number of bytes.
+    OddStringSize,
+}
+
+/// The maximum nesting depth of structure fields. A field at the top level of an event has depth
+/// 0, and the members of a structure are one level deeper than the structure. This protects the
+/// decoder's stack from metadata that nests structures very deeply.
+pub const MAX_STRUCT_DEPTH: usize = 32;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => f.write_str("unexpected end of data"),
+            DecodeError::InvalidSize => f.write_str("invalid metadata size"),
+            DecodeError::UnsupportedType(t) => write!(f, "unsupported field type {}", t),
+            DecodeError::TrailingData => f.write_str("unexpected data after the last field"),
+            DecodeError::NestingTooDeep => f.write_str("structures are nested too deeply"),
+            DecodeError::OddStringSize => f.write_str("odd size of a counted UTF-16 string"),
+        }
+    }
+}
+
+/// Provider metadata, as passed in the provider metadata data descriptor or registered as
+/// provider traits.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProviderMetadata {
+    /// The name of the provider.
+    pub name: String,
+    /// The provider traits that follow the provider name.
+    pub traits: Vec<ProviderTrait>,
+}
+
+/// A single provider trait. See
+/// [Provider Traits](https://docs.microsoft.com/en-us/windows/win32/etw/provider-traits).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProviderTrait {
+    /// The type of the trait. For example, `1` identifies the provider group GUID.
+    pub trait_type: u8,
+    /// The trait data.
+    pub data: Vec<u8>,
+}
+
+/// Event metadata, which describes the name of an event and the names and types of its fields.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EventMetadata {
+    /// The name of the event.
+    pub name: String,
+    /// The extension bytes that precede the event name. The last byte does not have its high
+    /// bit set.
+    pub extensions: Vec<u8>,
+    /// The fields of the event, in payload order. The members of a structure (`InFlag::STRUCT`)
+    /// immediately follow the field that describes the structure.
+    pub fields: Vec<FieldMetadata>,
+}
+
+/// Describes one field of an event.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldMetadata {
+    /// The name of the field.
+    pub name: String,
+    /// The input type of the field, including the `VCOUNT_FLAG` / `CCOUNT_FLAG` bits. The
+    /// `CHAIN_FLAG` bit is removed; use `out_type` instead.
+    pub in_type: InFlag,
+    /// The output type of the field. This is `OutFlag::NULL` if the metadata did not specify one.
+    /// For structures, this contains the number of fields in the structure.
+    pub out_type: OutFlag,
+    /// Extension bytes that follow the output type, if any.
+    pub out_extensions: Vec<u8>,
+    /// The number of elements, for fields that are arrays of constant length.
+    pub count: Option<u16>,
+    /// Type information, for fields that use a custom serializer.
+    pub type_info: Option<Vec<u8>>,
+}
+
+impl FieldMetadata {
+    /// Returns the type of the field (or the type of the array elements), without any flags.
+    pub fn base_type(&self) -> InFlag {
+        self.in_type & InFlag::TYPE_MASK
+    }
+
+    /// Returns `true` if the field is an array (of either constant or variable length).
+    pub fn is_array(&self) -> bool {
+        let count = self.in_type & InFlag::COUNT_MASK;
+        count == InFlag::VCOUNT_FLAG || count == InFlag::CCOUNT_FLAG
+    }
+}
+
+/// The value of a decoded field.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// A string. ANSI strings are decoded as UTF-8 and UTF-16 strings are converted to UTF-8;
+    /// invalid sequences are replaced with U+FFFD.
+    String(String),
+    /// `InFlag::INT8`
+    I8(i8),
+    /// `InFlag::UINT8`
+    U8(u8),
+    /// `InFlag::INT16`
+    I16(i16),
+    /// `InFlag::UINT16`
+    U16(u16),
+    /// `InFlag::INT32`
+    I32(i32),
+    /// `InFlag::UINT32`
+    U32(u32),
+    /// `InFlag::INT64`
+    I64(i64),
+    /// `InFlag::UINT64`
+    U64(u64),
+    /// `InFlag::FLOAT`
+    F32(f32),
+    /// `InFlag::DOUBLE`
+    F64(f64),
+    /// `InFlag::BOOL32`
+    Bool32(i32),
+    /// `InFlag::HEXINT32`
+    HexInt32(u32),
+    /// `InFlag::HEXINT64`
+    HexInt64(u64),
+    /// `InFlag::BINARY`, or a field that uses a custom serializer.
+    Binary(Vec<u8>),
+    /// `InFlag::GUID`, in the byte order in which it was stored in the payload.
+    Guid([u8; 16]),
+    /// `InFlag::FILETIME`, in 100ns units since the `FILETIME` epoch.
+    FileTime(u64),
+    /// `InFlag::SYSTEMTIME`, as its eight `u16` fields.
+    SystemTime([u16; 8]),
+    /// `InFlag::SID`, as the raw bytes of the SID.
+    Sid(Vec<u8>),
+    /// An array of values.
+    Array(Vec<Value>),
+    /// `InFlag::STRUCT`, as the fields of the structure.
+    Struct(Vec<DecodedField>),
+}
+
+/// One field of a decoded event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedField {
+    /// The name of the field.
+    pub name: String,
+    /// The input type of the field. See `FieldMetadata::in_type`.
+    pub in_type: InFlag,
+    /// The output type of the field.
+    pub out_type: OutFlag,
+    /// The value of the field.
+    pub value: Value,
+}
+
+/// An event whose payload has been decoded using its metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedEvent {
+    /// The name of the event.
+    pub name: String,
+    /// The fields of the event, in payload order.
+    pub fields: Vec<DecodedField>,
+}
+
+impl DecodedEvent {
+    /// Returns the value of the first field with the given name.
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        self.fields
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| &f.value)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < n {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut a = [0; N];
+        a.copy_from_slice(self.bytes(N)?);
+        Ok(a)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    /// Reads a NUL-terminated UTF-8 string.
+    fn str_z(&mut self) -> Result<String, DecodeError> {
+        let len = self
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        let s = String::from_utf8_lossy(&self.data[..len]).into_owned();
+        self.data = &self.data[len + 1..];
+        Ok(s)
+    }
+
+    /// Reads a NUL-terminated UTF-16 string.
+    fn wstr_z(&mut self) -> Result<String, DecodeError> {
+        let mut units = Vec::new();
+        loop {
+            match self.u16()? {
+                0 => break,
+                u => units.push(u),
+            }
+        }
+        Ok(String::from_utf16_lossy(&units))
+    }
+
+    /// Reads bytes until one is found that does not have its high bit set.
+    fn extension(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let mut ext = Vec::new();
+        loop {
+            let b = self.u8()?;
+            ext.push(b);
+            if b & 0x80 == 0 {
+                return Ok(ext);
+            }
+        }
+    }
+}
+
+/// Reads the `u16` size at the start of a metadata block, and returns a reader over the rest of
+/// the block.
+fn sized_block(data: &[u8]) -> Result<Reader<'_>, DecodeError> {
+    let mut r = Reader { data };
+    let size = r.u16()? as usize;
+    if size < 2 || size > data.len() {
+        return Err(DecodeError::InvalidSize);
+    }
+    Ok(Reader {
+        data: &data[2..size],
+    })
+}
+
+/// Parses provider metadata (or provider traits).
+pub fn parse_provider_metadata(data: &[u8]) -> Result<ProviderMetadata, DecodeError> {
+    let mut r = sized_block(data)?;
+    let name = r.str_z()?;
+    let mut traits = Vec::new();
+    while !r.is_empty() {
+        let size = r.u16()? as usize;
+        if size < 3 {
+            return Err(DecodeError::InvalidSize);
+        }
+        let trait_type = r.u8()?;
+        let data = r.bytes(size - 3)?.to_vec();
+        traits.push(ProviderTrait { trait_type, data });
+    }
+    Ok(ProviderMetadata { name, traits })
+}
+
+/// Parses the metadata of a single event.
+pub fn parse_event_metadata(data: &[u8]) -> Result<EventMetadata, DecodeError> {
+    let mut r = sized_block(data)?;
+    let extensions = r.extension()?;
+    let name = r.str_z()?;
+    let mut fields = Vec::new();
+    while !r.is_empty() {
+        let name = r.str_z()?;
+        let in_byte = r.u8()?;
+        let mut out_type = OutFlag::NULL;
+        let mut out_extensions = Vec::new();
+        if in_byte & InFlag::CHAIN_FLAG.bits() != 0 {
+            let out_byte = r.u8()?;
+            out_type = OutFlag::from_bits_retain(out_byte & 0x7f);
+            if out_byte & 0x80 != 0 {
+                out_extensions = r.extension()?;
+            }
+        }
+        let in_type = InFlag::from_bits_retain(in_byte & !InFlag::CHAIN_FLAG.bits());
+        let mut count = None;
+        let mut type_info = None;
+        let count_flags = in_type & InFlag::COUNT_MASK;
+        if count_flags == InFlag::CUSTOM_FLAG {
+            let size = r.u16()? as usize;
+            type_info = Some(r.bytes(size)?.to_vec());
+        } else if count_flags == InFlag::CCOUNT_FLAG {
+            count = Some(r.u16()?);
+        }
+        fields.push(FieldMetadata {
+            name,
+            in_type,
+            out_type,
+            out_extensions,
+            count,
+            type_info,
+        });
+    }
+    Ok(EventMetadata {
+        name,
+        extensions,
+        fields,
+    })
+}
+
+/// Decodes an event payload, using event metadata that has already been parsed.
+///
+/// `payload` contains the data of all fields, concatenated. This is the data that follows the
+/// provider metadata and event metadata data descriptors.
+pub fn decode_payload(
+    metadata: &EventMetadata,
+    payload: &[u8],
+) -> Result<DecodedEvent, DecodeError> {
+    let mut r = Reader { data: payload };
+    let mut metadata_fields = metadata.fields.iter();
+    let fields = decode_fields(&mut r, &mut metadata_fields, usize::MAX, 0)?;
+    if !r.is_empty() {
+        return Err(DecodeError::TrailingData);
+    }
+    Ok(DecodedEvent {
+        name: metadata.name.clone(),
+        fields,
+    })
+}
+
+/// Decodes up to `count` fields at nesting level `depth`, including the members of any
+/// structures.
+fn decode_fields(
+    r: &mut Reader<'_>,
+    metadata_fields: &mut core::slice::Iter<'_, FieldMetadata>,
+    count: usize,
+    depth: usize,
+) -> Result<Vec<DecodedField>, DecodeError> {
+    let mut fields = Vec::new();
+    while fields.len() < count {
+        let field = match metadata_fields.next() {
+            Some(field) => field,
+            None if count == usize::MAX => break,
+            None => return Err(DecodeError::UnexpectedEnd),
+        };
+        let value = if field.base_type() == InFlag::STRUCT {
+            if field.in_type & InFlag::COUNT_MASK != InFlag::NULL {
+                // Arrays of structures are not supported.
+                return Err(DecodeError::UnsupportedType(field.in_type.bits()));
+            }
+            if depth == MAX_STRUCT_DEPTH {
+                return Err(DecodeError::NestingTooDeep);
+            }
+            let members = field.out_type.bits() as usize;
+            Value::Struct(decode_fields(r, metadata_fields, members, depth + 1)?)
+        } else {
+            decode_field(r, field)?
+        };
+        fields.push(DecodedField {
+            name: field.name.clone(),
+            in_type: field.in_type,
+            out_type: field.out_type,
+            value,
+        });
+    }
+    Ok(fields)
+}
+
+/// Parses event metadata and then decodes an event payload.
+pub fn decode_event(event_metadata: &[u8], payload: &[u8]) -> Result<DecodedEvent, DecodeError> {
+    decode_payload(&parse_event_metadata(event_metadata)?, payload)
+}
+
+fn decode_field(r: &mut Reader<'_>, field: &FieldMetadata) -> Result<Value, DecodeError> {
+    let count_flags = field.in_type & InFlag::COUNT_MASK;
+    if count_flags == InFlag::CUSTOM_FLAG {
+        let size = r.u16()? as usize;
+        return Ok(Value::Binary(r.bytes(size)?.to_vec()));
+    }
+    let count = if count_flags == InFlag::VCOUNT_FLAG {
+        r.u16()?
+    } else if count_flags == InFlag::CCOUNT_FLAG {
+        field.count.unwrap_or(0)
+    } else {
+        return decode_scalar(r, field.base_type());
+    };
+    let mut values = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        values.push(decode_scalar(r, field.base_type())?);
+    }
+    Ok(Value::Array(values))
+}
+
+fn decode_scalar(r: &mut Reader<'_>, ty: InFlag) -> Result<Value, DecodeError> {
+    Ok(match ty {
+        InFlag::UNICODE_STRING => Value::String(r.wstr_z()?),
+        InFlag::ANSI_STRING => Value::String(r.str_z()?),
+        InFlag::INT8 => Value::I8(i8::from_le_bytes(r.array()?)),
+        InFlag::UINT8 => Value::U8(r.u8()?),
+        InFlag::INT16 => Value::I16(i16::from_le_bytes(r.array()?)),
+        InFlag::UINT16 => Value::U16(r.u16()?),
+        InFlag::INT32 => Value::I32(i32::from_le_bytes(r.array()?)),
+        InFlag::UINT32 => Value::U32(u32::from_le_bytes(r.array()?)),
+        InFlag::INT64 => Value::I64(i64::from_le_bytes(r.array()?)),
+        InFlag::UINT64 => Value::U64(u64::from_le_bytes(r.array()?)),
+        InFlag::FLOAT => Value::F32(f32::from_le_bytes(r.array()?)),
+        InFlag::DOUBLE => Value::F64(f64::from_le_bytes(r.array()?)),
+        InFlag::BOOL32 => Value::Bool32(i32::from_le_bytes(r.array()?)),
+        InFlag::BINARY => {
+            let size = r.u16()? as usize;
+            Value::Binary(r.bytes(size)?.to_vec())
+        }
+        InFlag::GUID => Value::Guid(r.array()?),
+        InFlag::FILETIME => Value::FileTime(u64::from_le_bytes(r.array()?)),
+        InFlag::SYSTEMTIME => {
+            let mut fields = [0u16; 8];
+            for f in fields.iter_mut() {
+                *f = r.u16()?;
+            }
+            Value::SystemTime(fields)
+        }
+        InFlag::SID => {
+            // A SID is 8 bytes (revision, sub-authority count, and identifier authority),
+            // followed by a u32 for each sub-authority.
+            let header = r.array::<8>()?;
+            let sub_authorities = r.bytes(header[1] as usize * 4)?;
+            let mut sid = header.to_vec();
+            sid.extend_from_slice(sub_authorities);
+            Value::Sid(sid)
+        }
+        InFlag::HEXINT32 => Value::HexInt32(u32::from_le_bytes(r.array()?)),
+        InFlag::HEXINT64 => Value::HexInt64(u64::from_le_bytes(r.array()?)),
+        InFlag::COUNTED_UNICODE_STRING => {
+            let size = r.u16()? as usize;
+            if size & 1 != 0 {
+                return Err(DecodeError::OddStringSize);
+            }
+            let units: Vec<u16> = r
+                .bytes(size)?
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            Value::String(String::from_utf16_lossy(&units))
+        }
+        InFlag::COUNTED_ANSI_STRING => {
+            let size = r.u16()? as usize;
+            Value::String(String::from_utf8_lossy(r.bytes(size)?).into_owned())
+        }
+        _ => return Err(DecodeError::UnsupportedType(ty.bits())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn provider_metadata() {
+        let p = parse_provider_metadata(b"\x08\x00Hello\x00").unwrap();
+        assert_eq!(p.name, "Hello");
+        assert!(p.traits.is_empty());
+
+        let mut data = vec![0, 0];
+        data.extend_from_slice(b"P\x00");
+        data.extend_from_slice(&[19, 0, 1]);
+        data.extend_from_slice(&[0xaa; 16]);
+        data[0] = data.len() as u8;
+        let p = parse_provider_metadata(&data).unwrap();
+        assert_eq!(p.name, "P");
+        assert_eq!(
+            p.traits,
+            vec![ProviderTrait {
+                trait_type: 1,
+                data: vec![0xaa; 16]
+            }]
+        );
+
+        assert_eq!(
+            parse_provider_metadata(b"\x20\x00P\x00"),
+            Err(DecodeError::InvalidSize)
+        );
+    }
+
+    #[test]
+    fn event_metadata() {
+        // name "ev", field "a" u32 (hex), field "s" counted utf-8 string, field "v" u16[],
+        // field "c" u8[4]
+        let mut m = vec![0, 0, 0];
+        m.extend_from_slice(b"ev\x00");
+        m.extend_from_slice(b"a\x00");
+        m.extend_from_slice(&[
+            (InFlag::UINT32 | InFlag::CHAIN_FLAG).bits(),
+            OutFlag::HEX.bits(),
+        ]);
+        m.extend_from_slice(b"s\x00");
+        m.extend_from_slice(&[
+            (InFlag::COUNTED_ANSI_STRING | InFlag::CHAIN_FLAG).bits(),
+            OutFlag::UTF8.bits(),
+        ]);
+        m.extend_from_slice(b"v\x00");
+        m.push((InFlag::UINT16 | InFlag::VCOUNT_FLAG).bits());
+        m.extend_from_slice(b"c\x00");
+        m.push((InFlag::UINT8 | InFlag::CCOUNT_FLAG).bits());
+        m.extend_from_slice(&4u16.to_le_bytes());
+        m[0] = m.len() as u8;
+
+        let meta = parse_event_metadata(&m).unwrap();
+        assert_eq!(meta.name, "ev");
+        assert_eq!(meta.extensions, vec![0]);
+        assert_eq!(meta.fields.len(), 4);
+        assert_eq!(meta.fields[0].in_type, InFlag::UINT32);
+        assert_eq!(meta.fields[0].out_type, OutFlag::HEX);
+        assert_eq!(meta.fields[2].base_type(), InFlag::UINT16);
+        assert!(meta.fields[2].is_array());
+        assert_eq!(meta.fields[3].count, Some(4));
+
+        let mut payload = vec![];
+        payload.extend_from_slice(&0xf00du32.to_le_bytes());
+        payload.extend_from_slice(&3u16.to_le_bytes());
+        payload.extend_from_slice(b"abc");
+        payload.extend_from_slice(&2u16.to_le_bytes());
+        payload.extend_from_slice(&7u16.to_le_bytes());
+        payload.extend_from_slice(&8u16.to_le_bytes());
+        payload.extend_from_slice(&[1, 2, 3, 4]);
+
+        let event = decode_payload(&meta, &payload).unwrap();
+        assert_eq!(event.name, "ev");
+        assert_eq!(event.field("a"), Some(&Value::U32(0xf00d)));
+        assert_eq!(event.field("s"), Some(&Value::String("abc".into())));
+        assert_eq!(
+            event.field("v"),
+            Some(&Value::Array(vec![Value::U16(7), Value::U16(8)]))
+        );
+        assert_eq!(
+            event.field("c"),
+            Some(&Value::Array(vec![
+                Value::U8(1),
+                Value::U8(2),
+                Value::U8(3),
+                Value::U8(4)
+            ]))
+        );
+        assert_eq!(event.fields[1].out_type, OutFlag::UTF8);
+
+        // Truncated and oversized payloads are rejected.
+        assert_eq!(
+            decode_payload(&meta, &payload[..payload.len() - 1]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        payload.push(0);
+        assert_eq!(
+            decode_payload(&meta, &payload),
+            Err(DecodeError::TrailingData)
+        );
+    }
+
+    #[test]
+    fn strings_and_binary() {
+        let mut m = vec![0, 0, 0];
+        m.extend_from_slice(b"strs\x00");
+        m.extend_from_slice(b"w\x00");
+        m.push(InFlag::COUNTED_UNICODE_STRING.bits());
+        m.extend_from_slice(b"z\x00");
+        m.push(InFlag::ANSI_STRING.bits());
+        m.extend_from_slice(b"b\x00");
+        m.push(InFlag::BINARY.bits());
+        m.extend_from_slice(b"g\x00");
+        m.push(InFlag::GUID.bits());
+        m[0] = m.len() as u8;
+
+        let mut payload = vec![];
+        payload.extend_from_slice(&4u16.to_le_bytes());
+        payload.extend_from_slice(&[b'h', 0, b'i', 0]);
+        payload.extend_from_slice(b"zed\x00");
+        payload.extend_from_slice(&2u16.to_le_bytes());
+        payload.extend_from_slice(&[0xde, 0xad]);
+        payload.extend_from_slice(&[9; 16]);
+
+        let event = decode_event(&m, &payload).unwrap();
+        assert_eq!(event.field("w"), Some(&Value::String("hi".into())));
+        assert_eq!(event.field("z"), Some(&Value::String("zed".into())));
+        assert_eq!(event.field("b"), Some(&Value::Binary(vec![0xde, 0xad])));
+        assert_eq!(event.field("g"), Some(&Value::Guid([9; 16])));
+
+        // A counted UTF-16 string cannot contain half of a code unit.
+        payload[0] = 3;
+        assert_eq!(decode_event(&m, &payload), Err(DecodeError::OddStringSize));
+    }
+
+    #[test]
+    fn structs() {
+        // field "a" u8, struct "s" { "b" u16, struct "t" { "c" u32 } }, field "d" u8
+        let mut m = vec![0, 0, 0];
+        m.extend_from_slice(b"st\x00");
+        m.extend_from_slice(b"a\x00");
+        m.push(InFlag::UINT8.bits());
+        m.extend_from_slice(b"s\x00");
+        m.extend_from_slice(&[(InFlag::STRUCT | InFlag::CHAIN_FLAG).bits(), 2]);
+        m.extend_from_slice(b"b\x00");
+        m.push(InFlag::UINT16.bits());
+        m.extend_from_slice(b"t\x00");
+        m.extend_from_slice(&[(InFlag::STRUCT | InFlag::CHAIN_FLAG).bits(), 1]);
+        m.extend_from_slice(b"c\x00");
+        m.push(InFlag::UINT32.bits());
+        m.extend_from_slice(b"d\x00");
+        m.push(InFlag::UINT8.bits());
+        m[0] = m.len() as u8;
+
+        let mut payload = vec![1];
+        payload.extend_from_slice(&2u16.to_le_bytes());
+        payload.extend_from_slice(&3u32.to_le_bytes());
+        payload.push(4);
+
+        let event = decode_event(&m, &payload).unwrap();
+        assert_eq!(event.fields.len(), 3);
+        assert_eq!(event.field("a"), Some(&Value::U8(1)));
+        assert_eq!(event.field("d"), Some(&Value::U8(4)));
+        let s = match event.field("s") {
+            Some(Value::Struct(fields)) => fields,
+            other => panic!("unexpected value {:?}", other),
+        };
+        assert_eq!(s.len(), 2);
+        assert_eq!(s[0].value, Value::U16(2));
+        match &s[1].value {
+            Value::Struct(t) => assert_eq!(t[0].value, Value::U32(3)),
+            other => panic!("unexpected value {:?}", other),
+        }
+
+        // The metadata ends before the last member of the structure.
+        let truncated = &m[..m.len() - 10];
+        let mut truncated = truncated.to_vec();
+        truncated[0] = truncated.len() as u8;
+        assert_eq!(
+            decode_event(&truncated, &payload[..3]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    /// Builds event metadata with `depth` nested single-member structures around a `u8` field.
+    fn nested_structs(depth: usize) -> Vec<u8> {
+        let mut m = vec![0, 0, 0];
+        m.extend_from_slice(b"e\x00");
+        for _ in 0..depth {
+            m.extend_from_slice(&[0, (InFlag::STRUCT | InFlag::CHAIN_FLAG).bits(), 1]);
+        }
+        m.extend_from_slice(&[0, InFlag::UINT8.bits()]);
+        let len = m.len() as u16;
+        m[..2].copy_from_slice(&len.to_le_bytes());
+        m
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let event = decode_event(&nested_structs(MAX_STRUCT_DEPTH), &[7]).unwrap();
+        let mut value = &event.fields[0].value;
+        for _ in 0..MAX_STRUCT_DEPTH {
+            value = match value {
+                Value::Struct(members) => &members[0].value,
+                other => panic!("unexpected value {:?}", other),
+            };
+        }
+        assert_eq!(value, &Value::U8(7));
+
+        assert_eq!(
+            decode_event(&nested_structs(MAX_STRUCT_DEPTH + 1), &[7]),
+            Err(DecodeError::NestingTooDeep)
+        );
+        // This would overflow the stack without the limit.
+        assert_eq!(
+            decode_event(&nested_structs(21_000), &[7]),
+            Err(DecodeError::NestingTooDeep)
+        );
+    }
+
+    #[test]
+    fn unsupported_type() {
+        let mut m = vec![0, 0, 0];
+        m.extend_from_slice(b"e\x00p\x00");
+        m.push(16); // POINTER
+        m[0] = m.len() as u8;
+        assert_eq!(
+            decode_event(&m, &[0; 8]),
+            Err(DecodeError::UnsupportedType(16))
+        );
+    }
+}
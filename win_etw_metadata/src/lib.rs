@@ -7,8 +7,15 @@
 #![no_std]
 #![deny(missing_docs)]
 
+extern crate alloc;
+
 use bitflags::bitflags;
 
+mod builder;
+pub mod decode;
+
+pub use builder::{MetadataBuilder, MAX_METADATA_BUILDER_LEN};
+
 /// This structure describes the start of the ETW metadata section. A single static instance of
 /// this structure is placed in PE/COFF modules, and it identifies the start of the ETW metadata
 /// section. In this implementation, that single instance is `ETW_TRACE_LOGGING_METADATA`.
@@ -55,7 +62,7 @@ static ETW_TRACE_LOGGING_METADATA: TraceLoggingMetadata = TraceLoggingMetadata {
 
 /// Predefined event tracing levels
 #[repr(transparent)]
-#[derive(Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[derive(Copy, Clone, Default, Eq, PartialEq, Hash, Ord, PartialOrd, Debug)]
 pub struct Level(pub u8);
 
 impl Level {
@@ -75,9 +82,33 @@ impl Level {
     pub const VERBOSE: Level = Level(5);
 }
 
+/// The event is an informational event. This is the default opcode.
+pub const WINEVENT_OPCODE_INFO: u8 = 0;
+/// The event marks the start of an activity.
+pub const WINEVENT_OPCODE_START: u8 = 1;
+/// The event marks the end of an activity.
+pub const WINEVENT_OPCODE_STOP: u8 = 2;
+/// The event describes an object that existed when a session started (a "rundown" event).
+pub const WINEVENT_OPCODE_DC_START: u8 = 3;
+/// The event describes an object that existed when a session stopped.
+pub const WINEVENT_OPCODE_DC_STOP: u8 = 4;
+/// The event is an extension of another event.
+pub const WINEVENT_OPCODE_EXTENSION: u8 = 5;
+/// The event is a reply to a request.
+pub const WINEVENT_OPCODE_REPLY: u8 = 6;
+/// The event marks the resumption of an activity that was suspended.
+pub const WINEVENT_OPCODE_RESUME: u8 = 7;
+/// The event marks the suspension of an activity.
+pub const WINEVENT_OPCODE_SUSPEND: u8 = 8;
+/// The event describes a transfer of data or control to another component.
+pub const WINEVENT_OPCODE_SEND: u8 = 9;
+/// The event describes a transfer of data or control from another component.
+pub const WINEVENT_OPCODE_RECEIVE: u8 = 240;
+
 bitflags! {
     /// Defines the input type of a field.
     /// In traceloggingprovider.h, this is the 'TlgIn_t` enumerated type.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
     #[repr(transparent)]
     pub struct InFlag: u8 {
         /// No value at all
@@ -130,15 +161,18 @@ bitflags! {
         const HEXINT64 = 21;
         /// A counted wide string (UTF-16), corresponding to `UNICODE_STRING` in Win32.
         /// This type uses two data descriptor slots. The first is a `u16` value, giving the
-        /// length of the string data in WCHAR units (not bytes). The second points to the
+        /// length of the string data in bytes (not WCHAR units). The second points to the
         /// character data.
         const COUNTED_UNICODE_STRING = 22;
         /// A counted ANSI string, corresponding to `STRING` in Win32.
         /// The character set can be specified as UTF-8 by using `OutFlag::UTF8`.
         /// This type uses two data descriptor slots. The first is a `u16` value, giving the
-        /// length of the string data in WCHAR units (not bytes). The second points to the
-        /// character data.
+        /// length of the string data in bytes. The second points to the character data.
         const COUNTED_ANSI_STRING = 23;
+        /// A structure, which groups the fields that follow it. This type has no data of its
+        /// own. It is always used with `CHAIN_FLAG`, and the `OutFlag` byte contains the number
+        /// of fields in the structure (1 to 127), rather than an output type.
+        const STRUCT = 24;
         /// A flag which indicates that this field is an array of constant length.
         /// If this field is present, then the metadata contains an additional `u16` field, which
         /// is the constant length.
@@ -181,6 +215,7 @@ impl InFlag {
 
 bitflags! {
     /// Specifies how a field should be interpreted or displayed.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
     #[repr(transparent)]
     pub struct OutFlag: u8 {
         /// No display at all.
@@ -0,0 +1,352 @@
+//! Support for activities that are described by a pair of start and stop events.
+//!
+//! The `#[activity]` attribute of `#[trace_logging_provider]` generates a `_start` method, which
+//! writes the start event and returns an `ActivityGuard`. The guard writes the stop event when it
+//! is passed to the generated `_stop` method, or when it is dropped if the stop event has no
+//! fields.
+//!
+//! This module also provides a portable _activity scope_. `with_activity` sets the activity ID
+//! of the current OS thread, which is not useful for async code, because a task can run on a
+//! different thread each time it is polled. `ActivityScope` instead sets a thread-local activity
+//! ID only while its future is being polled. Generated event methods use the scoped activity ID
+//! when `EventOptions::activity_id` is `None`. On platforms other than Windows, there is no OS
+//! activity ID, so `with_activity` sets the scoped activity ID as well.
+
+use crate::{new_activity_id, EventOptions, GUID};
+#[cfg(feature = "std")]
+use core::future::Future;
+#[cfg(feature = "std")]
+use core::pin::Pin;
+#[cfg(feature = "std")]
+use core::task::{Context, Poll};
+#[cfg(feature = "std")]
+use std::cell::RefCell;
+
+impl EventOptions {
+    /// Creates the options for the start and stop events of a new activity. The new activity ID
+    /// is generated by `new_activity_id`. If `parent` specifies an activity ID, then it becomes
+    /// the related activity ID of the new activity. The level is copied from `parent`.
+    pub fn for_new_activity(parent: Option<&EventOptions>) -> EventOptions {
+        EventOptions {
+            level: parent.and_then(|p| p.level),
+            activity_id: Some(new_activity_id().unwrap_or_default()),
+            related_activity_id: parent.and_then(|p| p.activity_id.clone()),
+        }
+    }
+}
+
+/// Represents an activity that has been started, but not yet stopped.
+///
+/// If the stop event of the activity has no fields, then dropping the guard writes the stop
+/// event. If it has fields, then dropping the guard does not write anything, because there are no
+/// values for the fields; pass the guard to the generated `_stop` method instead.
+#[must_use = "pass the guard to the generated `_stop` method to write the stop event"]
+pub struct ActivityGuard<'a, P: ?Sized> {
+    provider: &'a P,
+    options: EventOptions,
+    stop: Option<fn(&P, &EventOptions)>,
+}
+
+impl<'a, P: ?Sized> ActivityGuard<'a, P> {
+    /// Creates a guard. This is called by generated code.
+    ///
+    /// `stop` writes the stop event, using the given options. If `stop` is `None`, then the guard
+    /// does not write anything when it is dropped.
+    #[doc(hidden)]
+    pub fn new(
+        provider: &'a P,
+        options: EventOptions,
+        stop: Option<fn(&P, &EventOptions)>,
+    ) -> Self {
+        Self {
+            provider,
+            options,
+            stop,
+        }
+    }
+
+    /// The ID of the activity. Events that are part of the activity should use this as their
+    /// activity ID.
+    pub fn activity_id(&self) -> Option<&GUID> {
+        self.options.activity_id.as_ref()
+    }
+
+    /// The options that are used for the start and stop events of the activity.
+    pub fn options(&self) -> &EventOptions {
+        &self.options
+    }
+
+    /// Returns options that can be passed to other events, to make them part of the activity.
+    pub fn event_options(&self) -> EventOptions {
+        EventOptions {
+            activity_id: self.options.activity_id.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Drops the guard. If the stop event has no fields, then this writes the stop event.
+    pub fn finish(self) {}
+
+    /// Releases the guard without writing the stop event, and returns the options of the
+    /// activity. This is called by the generated `_stop` method.
+    #[doc(hidden)]
+    pub fn into_options(mut self) -> EventOptions {
+        self.stop = None;
+        core::mem::take(&mut self.options)
+    }
+}
+
+impl<P: ?Sized> Drop for ActivityGuard<'_, P> {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop(self.provider, &self.options);
+        }
+    }
+}
+
+impl<P: ?Sized> core::fmt::Debug for ActivityGuard<'_, P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ActivityGuard")
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static SCOPED_ACTIVITY_ID: RefCell<Option<GUID>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous scoped activity ID when dropped, even if a panic occurs.
+#[cfg(feature = "std")]
+struct RestoreScope {
+    previous: Option<GUID>,
+}
+
+#[cfg(feature = "std")]
+impl RestoreScope {
+    fn enter(activity_id: &GUID) -> Self {
+        let previous = SCOPED_ACTIVITY_ID.with(|id| id.borrow_mut().replace(activity_id.clone()));
+        Self { previous }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for RestoreScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        SCOPED_ACTIVITY_ID.with(|id| *id.borrow_mut() = previous);
+    }
+}
+
+/// Returns the activity ID of the innermost `ActivityScope` or `with_activity_id` call that is
+/// running on the current thread, if any.
+#[cfg(feature = "std")]
+pub fn scoped_activity_id() -> Option<GUID> {
+    SCOPED_ACTIVITY_ID.with(|id| id.borrow().clone())
+}
+
+/// Runs `f` with `activity_id` as the scoped activity ID of the current thread. Events that are
+/// written by `f` use this activity ID, unless their options specify a different one.
+#[cfg(feature = "std")]
+pub fn with_activity_id<F: FnOnce() -> R, R>(activity_id: &GUID, f: F) -> R {
+    let _restore = RestoreScope::enter(activity_id);
+    f()
+}
+
+/// Wraps a future, so that events written while the future is being polled use an activity ID.
+///
+/// Unlike `with_activity`, this works when the future moves between threads, because the
+/// activity ID is set each time the future is polled, and restored when `poll` returns.
+///
+/// ```
+/// # use win_etw_provider::{ActivityScope, GUID};
+/// # async fn handle_request() {}
+/// # let activity_id = GUID::default();
+/// let task = ActivityScope::new(activity_id, handle_request());
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct ActivityScope<F> {
+    activity_id: GUID,
+    future: F,
+}
+
+#[cfg(feature = "std")]
+impl<F> ActivityScope<F> {
+    /// Wraps `future`, using `activity_id` as its activity ID.
+    pub fn new(activity_id: GUID, future: F) -> Self {
+        Self {
+            activity_id,
+            future,
+        }
+    }
+
+    /// Wraps `future`, using a new activity ID.
+    pub fn new_activity(future: F) -> Self {
+        Self::new(new_activity_id().unwrap_or_default(), future)
+    }
+
+    /// The activity ID of the scope.
+    pub fn activity_id(&self) -> &GUID {
+        &self.activity_id
+    }
+}
+
+#[cfg(feature = "std")]
+impl<F: Future> Future for ActivityScope<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: `future` is structurally pinned; it is never moved out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let _restore = RestoreScope::enter(&this.activity_id);
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        future.poll(cx)
+    }
+}
+
+/// Returns `options`, with the scoped activity ID (if any) applied when `options` does not
+/// specify an activity ID. This is called by generated code.
+#[doc(hidden)]
+#[inline(always)]
+pub fn apply_activity_scope<'a>(
+    options: Option<&'a EventOptions>,
+    storage: &'a mut Option<EventOptions>,
+) -> Option<&'a EventOptions> {
+    #[cfg(feature = "std")]
+    {
+        if options.map_or(true, |o| o.activity_id.is_none()) {
+            if let Some(activity_id) = scoped_activity_id() {
+                let mut scoped = options.cloned().unwrap_or_default();
+                scoped.activity_id = Some(activity_id);
+                return Some(storage.insert(scoped));
+            }
+        }
+    }
+    let _ = storage;
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::vec::Vec;
+
+    struct Stops(RefCell<Vec<EventOptions>>);
+
+    fn stop(stops: &Stops, options: &EventOptions) {
+        stops.0.borrow_mut().push(options.clone());
+    }
+
+    #[test]
+    fn guard() {
+        let stops = Stops(RefCell::new(Vec::new()));
+        let parent = EventOptions {
+            activity_id: Some(GUID {
+                data1: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let options = EventOptions::for_new_activity(Some(&parent));
+        assert_eq!(options.related_activity_id, parent.activity_id);
+
+        drop(ActivityGuard::new(&stops, options.clone(), Some(stop)));
+        ActivityGuard::new(&stops, options.clone(), Some(stop)).finish();
+        assert_eq!(stops.0.borrow().len(), 2);
+
+        let released = ActivityGuard::new(&stops, options.clone(), Some(stop)).into_options();
+        assert_eq!(released.related_activity_id, parent.activity_id);
+        assert_eq!(stops.0.borrow().len(), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn scope() {
+        use std::sync::Arc;
+        use std::task::{Wake, Waker};
+
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        /// Returns `Pending` once, and records the scoped activity ID each time it is polled.
+        struct YieldOnce {
+            polls: Vec<Option<GUID>>,
+        }
+        impl Future for YieldOnce {
+            type Output = Vec<Option<GUID>>;
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                self.polls.push(scoped_activity_id());
+                if self.polls.len() == 1 {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                } else {
+                    Poll::Ready(core::mem::take(&mut self.polls))
+                }
+            }
+        }
+
+        let a = GUID {
+            data1: 1,
+            ..Default::default()
+        };
+        let b = GUID {
+            data1: 2,
+            ..Default::default()
+        };
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(ActivityScope::new(
+            a.clone(),
+            YieldOnce { polls: Vec::new() },
+        ));
+
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(scoped_activity_id(), None);
+        let polls = with_activity_id(&b, || {
+            // The inner scope replaces the outer scope while the future is polled.
+            let polls = future.as_mut().poll(&mut cx);
+            assert_eq!(scoped_activity_id(), Some(b.clone()));
+            polls
+        });
+        assert_eq!(polls, Poll::Ready(vec![Some(a.clone()), Some(a.clone())]));
+        assert_eq!(scoped_activity_id(), None);
+
+        // Scoped IDs only apply when the options do not specify an activity ID.
+        let mut storage = None;
+        with_activity_id(&a, || {
+            let options = apply_activity_scope(None, &mut storage).unwrap();
+            assert_eq!(options.activity_id, Some(a.clone()));
+        });
+        let explicit = EventOptions {
+            activity_id: Some(b.clone()),
+            ..Default::default()
+        };
+        let mut storage = None;
+        with_activity_id(&a, || {
+            let options = apply_activity_scope(Some(&explicit), &mut storage).unwrap();
+            assert_eq!(options.activity_id, Some(b.clone()));
+        });
+    }
+
+    #[cfg(all(feature = "std", not(target_os = "windows")))]
+    #[test]
+    fn with_activity() {
+        // `with_activity` sets a new scoped activity ID, which events use, and then restores the
+        // previous one.
+        let mut storage = None;
+        let (inner, applied) = crate::with_activity(|| {
+            let options = apply_activity_scope(None, &mut storage).cloned();
+            (crate::get_current_thread_activity_id().unwrap(), options)
+        });
+        assert_ne!(inner, GUID::default());
+        assert_eq!(applied.unwrap().activity_id, Some(inner));
+        assert_eq!(scoped_activity_id(), None);
+        assert_eq!(crate::get_current_thread_activity_id(), Ok(GUID::default()));
+    }
+}
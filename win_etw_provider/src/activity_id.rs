@@ -0,0 +1,118 @@
+//! Portable activity IDs, for platforms other than Windows.
+//!
+//! On Windows, ETW generates activity IDs and stores the activity ID of each thread. On other
+//! platforms, this module generates the IDs itself. They use the layout of
+//! [RFC 9562](https://www.rfc-editor.org/rfc/rfc9562) version 7 UUIDs: a millisecond timestamp,
+//! followed by a sequence number and random bits, so IDs sort roughly in the order in which they
+//! were created. Without the `std` feature there is no clock, so version 4 (random) IDs are
+//! generated instead.
+//!
+//! The activity ID of the current thread is the scoped activity ID of the `activity` module.
+
+use crate::GUID;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts the IDs generated by this process. Combined with the process seed, this guarantees that
+/// the random bits of the IDs that one process generates are unique.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A per-process random value, which distinguishes the IDs of different processes. 0 means that
+/// it has not been computed yet.
+static PROCESS_SEED: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a new activity ID.
+pub(crate) fn new_activity_id() -> GUID {
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    // `splitmix64` is a bijection, so distinct sequence numbers give distinct random values.
+    let random = splitmix64(process_seed().wrapping_add(sequence));
+    let (timestamp, version) = match unix_time_millis() {
+        Some(millis) => (millis, 7),
+        None => (splitmix64(random), 4),
+    };
+    let mut data4 = random.to_be_bytes();
+    // RFC 9562 variant (0b10).
+    data4[0] = (data4[0] & 0x3f) | 0x80;
+    GUID {
+        data1: (timestamp >> 16) as u32,
+        data2: timestamp as u16,
+        data3: (version << 12) | (sequence & 0xfff) as u16,
+        data4,
+    }
+}
+
+fn process_seed() -> u64 {
+    let seed = PROCESS_SEED.load(Ordering::Relaxed);
+    if seed != 0 {
+        return seed;
+    }
+    // Mix in whatever varies between processes. The addresses vary if the OS uses ASLR.
+    let local = 0u8;
+    let mut entropy = splitmix64(&SEQUENCE as *const AtomicU64 as usize as u64)
+        ^ splitmix64(&local as *const u8 as usize as u64);
+    #[cfg(feature = "std")]
+    {
+        entropy ^= splitmix64(u64::from(std::process::id()));
+        if let Ok(elapsed) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            entropy ^= splitmix64(elapsed.as_nanos() as u64);
+        }
+    }
+    let seed = entropy | 1;
+    // If another thread computed a seed first, use its seed, so that all IDs share one sequence.
+    match PROCESS_SEED.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => seed,
+        Err(existing) => existing,
+    }
+}
+
+/// The number of milliseconds since the Unix epoch, truncated to 48 bits.
+fn unix_time_millis() -> Option<u64> {
+    #[cfg(feature = "std")]
+    {
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?;
+        Some(elapsed.as_millis() as u64 & 0xffff_ffff_ffff)
+    }
+    #[cfg(not(feature = "std"))]
+    {
+        None
+    }
+}
+
+/// The SplitMix64 finalizer. See <https://prng.di.unimi.it/splitmix64.c>.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn unique() {
+        let ids: Vec<GUID> = (0..10_000).map(|_| new_activity_id()).collect();
+        let distinct: HashSet<&GUID> = ids.iter().collect();
+        assert_eq!(distinct.len(), ids.len());
+
+        for id in &ids {
+            assert_eq!(id.data4[0] & 0xc0, 0x80, "variant of {:?}", id);
+            #[cfg(feature = "std")]
+            assert_eq!(id.data3 >> 12, 7, "version of {:?}", id);
+            #[cfg(not(feature = "std"))]
+            assert_eq!(id.data3 >> 12, 4, "version of {:?}", id);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn ordered_by_time() {
+        let first = new_activity_id();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = new_activity_id();
+        assert!((first.data1, first.data2) < (second.data1, second.data2));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::guid::GUID;
 use core::marker::PhantomData;
-use core::mem::size_of;
+use core::mem::{size_of, size_of_val};
 use widestring::{U16CStr, U16Str};
 use zerocopy::IntoBytes;
 
@@ -103,6 +103,34 @@ impl<'a> EventDataDescriptor<'a> {
             phantom_ref: PhantomData,
         }
     }
+
+    /// Returns the bytes that this descriptor points to.
+    ///
+    /// This is used by providers that do not pass the descriptors to ETW, but instead need to
+    /// read the event data themselves.
+    pub(crate) fn as_bytes(&self) -> &'a [u8] {
+        if self.size == 0 {
+            &[]
+        } else {
+            // SAFETY: Every constructor of EventDataDescriptor derives `ptr` and `size` from a
+            // reference whose lifetime is `'a`, so the pointed-to memory is valid for `'a`.
+            unsafe {
+                core::slice::from_raw_parts(self.ptr as usize as *const u8, self.size as usize)
+            }
+        }
+    }
+
+    /// Returns `true` if this descriptor points to provider metadata.
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn is_provider_metadata(&self) -> bool {
+        self.kind == EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA
+    }
+
+    /// Returns `true` if this descriptor points to event metadata.
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn is_event_metadata(&self) -> bool {
+        self.kind == EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA
+    }
 }
 
 macro_rules! well_known_types {
@@ -174,3 +202,14 @@ impl<'a> From<&'a GUID> for EventDataDescriptor<'a> {
         }
     }
 }
+
+impl<'a> From<&'a [GUID]> for EventDataDescriptor<'a> {
+    fn from(value: &'a [GUID]) -> EventDataDescriptor<'a> {
+        Self {
+            ptr: value.as_ptr() as usize as u64,
+            size: size_of_val(value) as u32,
+            kind: 0,
+            phantom_ref: PhantomData,
+        }
+    }
+}
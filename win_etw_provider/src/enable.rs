@@ -0,0 +1,712 @@
+//! Handles the requests that ETW controllers send to a provider, through the provider's enable
+//! callback.
+//!
+//! ETW calls the enable callback (see `EnableCallback` in `evntprov.h`) when a session enables or
+//! disables the provider, or asks it to capture its state. The Windows-specific code converts the
+//! raw callback arguments and then calls `StableProviderData::enable_callback`, which does not
+//! depend on any Windows APIs. This allows the handling of each request to be tested on any
+//! platform.
+
+use crate::filter::EventFilters;
+use crate::guid::GUID;
+use crate::sync::{Snapshot, SpinLock};
+use crate::Level;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering::SeqCst};
+
+pub(crate) const EVENT_CONTROL_CODE_DISABLE_PROVIDER: u32 = 0;
+pub(crate) const EVENT_CONTROL_CODE_ENABLE_PROVIDER: u32 = 1;
+pub(crate) const EVENT_CONTROL_CODE_CAPTURE_STATE: u32 = 2;
+
+/// Describes a request from an ETW controller for a provider to log its current state, for
+/// example by writing a "rundown" of the objects that the application has open.
+///
+/// See `EVENT_CONTROL_CODE_CAPTURE_STATE` in
+/// [EnableCallback](https://docs.microsoft.com/en-us/windows/win32/api/evntprov/nc-evntprov-penablecallback).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CaptureStateRequest {
+    /// Identifies the session that sent the request.
+    pub source_id: GUID,
+    /// The level that the session requested. ETW passes level 0 to request all levels, which is
+    /// reported as level 255, as in `EnableState`.
+    pub level: Level,
+    /// The "match any" keyword mask that the session requested. ETW passes a mask of 0 to request
+    /// all keywords, which is reported as `u64::MAX`, as in `EnableState`.
+    pub match_any_keyword: u64,
+    /// The "match all" keyword mask that the session requested.
+    pub match_all_keyword: u64,
+}
+
+/// A function that is called when an ETW controller asks a provider to capture its state.
+///
+/// The function is called on a thread that is owned by ETW. It should write its events and return
+/// promptly.
+#[derive(Clone)]
+pub struct CaptureStateCallback(Arc<dyn Fn(&CaptureStateRequest) + Send + Sync>);
+
+impl CaptureStateCallback {
+    /// Wraps a function.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&CaptureStateRequest) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// Calls the function.
+    pub fn call(&self, request: &CaptureStateRequest) {
+        (self.0)(request)
+    }
+}
+
+impl core::fmt::Debug for CaptureStateCallback {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("CaptureStateCallback")
+    }
+}
+
+/// A snapshot of the level and keywords that ETW has enabled for a provider.
+///
+/// ETW combines the requests of all of the sessions that enable a provider, and passes the
+/// combined level and keywords to every call of the enable callback. The snapshot is therefore
+/// the state of the most recent request, rather than something that the provider merges.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EnableState {
+    /// True if at least one session has enabled the provider.
+    pub enabled: bool,
+    /// The enabled level. ETW passes level 0 to enable all levels, which is reported as level
+    /// 255.
+    pub level: Level,
+    /// The "match any" keyword mask. ETW passes a mask of 0 to match all keywords, which is
+    /// reported as `u64::MAX`.
+    pub match_any_keyword: u64,
+    /// The "match all" keyword mask.
+    pub match_all_keyword: u64,
+    /// Identifies the session that sent the most recent request. This is usually `GUID_NULL`.
+    pub source_id: GUID,
+    /// The filters that were passed with the most recent request.
+    pub filters: EventFilters,
+}
+
+impl EnableState {
+    /// Checks whether an event with the given level and keyword is enabled, using the rules of
+    /// [EventEnabled](https://docs.microsoft.com/en-us/windows/win32/api/evntprov/nf-evntprov-eventenabled).
+    pub fn is_event_enabled(&self, level: Level, keyword: u64) -> bool {
+        is_event_enabled(
+            self.enabled,
+            self.level,
+            self.match_any_keyword,
+            self.match_all_keyword,
+            level,
+            keyword,
+        )
+    }
+}
+
+/// Converts the level and "match any" keyword mask that ETW passes to the enable callback to the
+/// values that are reported to the application. ETW uses 0 for both to mean "all".
+fn requested_level_and_keyword(level: u8, match_any_keyword: u64) -> (Level, u64) {
+    let level = if level == 0 { u8::MAX } else { level };
+    let match_any_keyword = if match_any_keyword == 0 {
+        u64::MAX
+    } else {
+        match_any_keyword
+    };
+    (Level(level), match_any_keyword)
+}
+
+/// An event is enabled if its level is at or below the enabled level, and if either it has no
+/// keywords, or it has at least one of the "match any" keywords and all of the "match all"
+/// keywords.
+#[inline(always)]
+fn is_event_enabled(
+    enabled: bool,
+    enabled_level: Level,
+    match_any_keyword: u64,
+    match_all_keyword: u64,
+    level: Level,
+    keyword: u64,
+) -> bool {
+    enabled
+        && level <= enabled_level
+        && (keyword == 0
+            || (keyword & match_any_keyword != 0
+                && keyword & match_all_keyword == match_all_keyword))
+}
+
+/// A function that is called when an ETW session enables the provider, changes the level or
+/// keywords that it has enabled, or disables the provider.
+///
+/// The function receives the new state of the provider. It is called on a thread that is owned
+/// by ETW, and should return promptly.
+#[derive(Clone)]
+pub struct EnableStateCallback(Arc<dyn Fn(&EnableState) + Send + Sync>);
+
+impl EnableStateCallback {
+    /// Wraps a function.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&EnableState) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// Calls the function.
+    pub fn call(&self, state: &EnableState) {
+        (self.0)(state)
+    }
+}
+
+impl core::fmt::Debug for EnableStateCallback {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("EnableStateCallback")
+    }
+}
+
+/// This data is stored in a Box, so that it has a stable address.
+/// It is used to coordinate with ETW; ETW runs callbacks that need a stable pointer.
+/// See `EventRegister` and the "enable callback".
+///
+/// The state is published as an immutable snapshot, so that `is_event_id_enabled` can read the
+/// event ID filter without taking a lock. The level and keywords are also stored in atomics, so
+/// that `is_event_enabled` does not need to read the snapshot at all.
+pub(crate) struct StableProviderData {
+    enabled: AtomicBool,
+    max_level: AtomicU8,
+    match_any_keyword: AtomicU64,
+    match_all_keyword: AtomicU64,
+    event_id_filtered: AtomicBool,
+    state: Snapshot<EnableState>,
+    capture_state: SpinLock<Option<CaptureStateCallback>>,
+    enable_state_changed: SpinLock<Option<EnableStateCallback>>,
+}
+
+impl StableProviderData {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            max_level: AtomicU8::new(0),
+            match_any_keyword: AtomicU64::new(0),
+            match_all_keyword: AtomicU64::new(0),
+            event_id_filtered: AtomicBool::new(false),
+            state: Snapshot::new(EnableState::default()),
+            capture_state: SpinLock::new(None),
+            enable_state_changed: SpinLock::new(None),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_event_enabled(&self, level: Level, keyword: u64) -> bool {
+        is_event_enabled(
+            self.enabled.load(SeqCst),
+            Level(self.max_level.load(SeqCst)),
+            self.match_any_keyword.load(SeqCst),
+            self.match_all_keyword.load(SeqCst),
+            level,
+            keyword,
+        )
+    }
+
+    /// Checks whether the event ID filter, if any, enables an event.
+    #[inline(always)]
+    pub(crate) fn is_event_id_enabled(&self, id: u16) -> bool {
+        !self.event_id_filtered.load(SeqCst)
+            || self
+                .state
+                .read(|state| state.filters.is_event_id_enabled(id))
+    }
+
+    pub(crate) fn enable_state(&self) -> EnableState {
+        self.state.read(EnableState::clone)
+    }
+
+    /// Replaces the state of the provider.
+    ///
+    /// `enabled` is only cleared when the provider is disabled. When a session changes the level
+    /// or keywords, the sessions that are already enabled keep receiving events, and a concurrent
+    /// call to `is_event_enabled` may briefly see a mix of the old and new values.
+    fn set_state(&self, state: EnableState) {
+        let enabled = state.enabled;
+        if !enabled {
+            self.enabled.store(false, SeqCst);
+        }
+        self.max_level.store(state.level.0, SeqCst);
+        self.match_any_keyword
+            .store(state.match_any_keyword, SeqCst);
+        self.match_all_keyword
+            .store(state.match_all_keyword, SeqCst);
+        let filtered = state.filters.event_ids.is_some();
+        self.state.publish(state);
+        self.event_id_filtered.store(filtered, SeqCst);
+        if enabled {
+            self.enabled.store(true, SeqCst);
+        }
+    }
+
+    pub(crate) fn set_capture_state_callback(&self, callback: Option<CaptureStateCallback>) {
+        let previous = core::mem::replace(&mut *self.capture_state.lock(), callback);
+        // Drop the previous callback after releasing the lock; dropping it may run app code.
+        drop(previous);
+    }
+
+    pub(crate) fn set_enable_state_callback(&self, callback: Option<EnableStateCallback>) {
+        let previous = core::mem::replace(&mut *self.enable_state_changed.lock(), callback);
+        drop(previous);
+    }
+
+    fn notify_enable_state_changed(&self) {
+        let callback = self.enable_state_changed.lock().clone();
+        if let Some(callback) = callback {
+            callback.call(&self.enable_state());
+        }
+    }
+
+    /// Handles one call to the enable callback.
+    pub(crate) fn enable_callback(
+        &self,
+        control_code: u32,
+        source_id: &GUID,
+        level: u8,
+        match_any_keyword: u64,
+        match_all_keyword: u64,
+        filters: EventFilters,
+    ) {
+        match control_code {
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER => {
+                #[cfg(feature = "dev")]
+                {
+                    eprintln!("ETW is ENABLING this provider.  setting level: {}", level);
+                }
+                // ETW passes the combined level and keywords of all sessions, so the request
+                // replaces the previous state.
+                let (level, match_any_keyword) =
+                    requested_level_and_keyword(level, match_any_keyword);
+                self.set_state(EnableState {
+                    enabled: true,
+                    level,
+                    match_any_keyword,
+                    match_all_keyword,
+                    source_id: source_id.clone(),
+                    filters,
+                });
+                self.notify_enable_state_changed();
+            }
+            EVENT_CONTROL_CODE_DISABLE_PROVIDER => {
+                #[cfg(feature = "dev")]
+                {
+                    eprintln!("ETW is DISABLING this provider.  setting level: {}", level);
+                }
+                // The provider is disabled only when no session is left, so all state is cleared.
+                self.set_state(EnableState::default());
+                self.notify_enable_state_changed();
+            }
+            EVENT_CONTROL_CODE_CAPTURE_STATE => {
+                // ETW is requesting that the provider log its state information. The meaning of
+                // this is provider-dependent, so we forward it to the application.
+                #[cfg(feature = "dev")]
+                {
+                    eprintln!("EVENT_CONTROL_CODE_CAPTURE_STATE");
+                }
+                // Clone the callback so that the lock is not held while it runs. This allows the
+                // callback to replace itself.
+                let callback = self.capture_state.lock().clone();
+                if let Some(callback) = callback {
+                    let (level, match_any_keyword) =
+                        requested_level_and_keyword(level, match_any_keyword);
+                    callback.call(&CaptureStateRequest {
+                        source_id: source_id.clone(),
+                        level,
+                        match_any_keyword,
+                        match_all_keyword,
+                    });
+                }
+            }
+            _ => {
+                // The control code is unrecognized.
+                #[cfg(feature = "dev")]
+                {
+                    eprintln!(
+                        "enable_callback: control code {} is not recognized",
+                        control_code
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::EventIdFilter;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    #[test]
+    fn capture_state() {
+        let data = StableProviderData::new();
+        let source_id = GUID {
+            data1: 7,
+            ..Default::default()
+        };
+
+        // Without a callback, the request is ignored.
+        data.enable_callback(
+            EVENT_CONTROL_CODE_CAPTURE_STATE,
+            &source_id,
+            4,
+            1,
+            0,
+            Default::default(),
+        );
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let r = requests.clone();
+        data.set_capture_state_callback(Some(CaptureStateCallback::new(move |req| {
+            r.lock().unwrap().push(req.clone());
+        })));
+        data.enable_callback(
+            EVENT_CONTROL_CODE_CAPTURE_STATE,
+            &source_id,
+            4,
+            0xf0,
+            0x10,
+            Default::default(),
+        );
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &source_id,
+            5,
+            0,
+            0,
+            Default::default(),
+        );
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![CaptureStateRequest {
+                source_id: source_id.clone(),
+                level: Level::INFO,
+                match_any_keyword: 0xf0,
+                match_all_keyword: 0x10,
+            }]
+        );
+        assert_eq!(data.enable_state().level, Level::VERBOSE);
+
+        data.set_capture_state_callback(None);
+        data.enable_callback(
+            EVENT_CONTROL_CODE_CAPTURE_STATE,
+            &source_id,
+            4,
+            0,
+            0,
+            Default::default(),
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn capture_state_all_levels_and_keywords() {
+        let data = StableProviderData::new();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let r = requests.clone();
+        data.set_capture_state_callback(Some(CaptureStateCallback::new(move |req| {
+            r.lock().unwrap().push(req.clone());
+        })));
+        data.enable_callback(
+            EVENT_CONTROL_CODE_CAPTURE_STATE,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            Default::default(),
+        );
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            Default::default(),
+        );
+
+        // Level 0 and keyword 0 mean "all", and are reported in the same way as `EnableState`.
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request.level, Level(255));
+        assert_eq!(request.match_any_keyword, u64::MAX);
+        assert!(request.level >= Level::INFO);
+        let state = data.enable_state();
+        assert_eq!(state.level, request.level);
+        assert_eq!(state.match_any_keyword, request.match_any_keyword);
+    }
+
+    #[test]
+    fn callback_can_replace_itself() {
+        let data = Arc::new(StableProviderData::new());
+        let d = data.clone();
+        data.set_capture_state_callback(Some(CaptureStateCallback::new(move |_| {
+            d.set_capture_state_callback(None);
+        })));
+        data.enable_callback(
+            EVENT_CONTROL_CODE_CAPTURE_STATE,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            Default::default(),
+        );
+        assert!(data.capture_state.lock().is_none());
+    }
+
+    #[test]
+    fn keyword_semantics() {
+        let data = StableProviderData::new();
+        assert!(!data.is_event_enabled(Level::CRITICAL, 0));
+
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &GUID::default(),
+            4,
+            0x6,
+            0x2,
+            Default::default(),
+        );
+        assert!(data.is_event_enabled(Level::INFO, 0));
+        assert!(!data.is_event_enabled(Level::VERBOSE, 0));
+        assert!(data.is_event_enabled(Level::WARN, 0x2));
+        assert!(data.is_event_enabled(Level::WARN, 0x3));
+        // Has a "match any" keyword, but not the "match all" keyword.
+        assert!(!data.is_event_enabled(Level::WARN, 0x4));
+        // Has no "match any" keyword.
+        assert!(!data.is_event_enabled(Level::WARN, 0x8));
+
+        data.enable_callback(
+            EVENT_CONTROL_CODE_DISABLE_PROVIDER,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            Default::default(),
+        );
+        assert!(!data.is_event_enabled(Level::CRITICAL, 0));
+        assert_eq!(data.enable_state(), EnableState::default());
+    }
+
+    #[test]
+    fn enable_replaces_state() {
+        let data = StableProviderData::new();
+        let a = GUID {
+            data1: 1,
+            ..Default::default()
+        };
+        let b = GUID {
+            data1: 2,
+            ..Default::default()
+        };
+
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &a,
+            2,
+            0x1,
+            0x1,
+            Default::default(),
+        );
+        assert!(data.is_event_enabled(Level::ERROR, 0x1));
+
+        // ETW passes the combined state of all sessions, so a second request is not merged with
+        // the first one.
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &b,
+            4,
+            0x2,
+            0,
+            Default::default(),
+        );
+        assert_eq!(
+            data.enable_state(),
+            EnableState {
+                enabled: true,
+                level: Level::INFO,
+                match_any_keyword: 0x2,
+                match_all_keyword: 0,
+                source_id: b.clone(),
+                filters: EventFilters::default(),
+            }
+        );
+        assert!(data.is_event_enabled(Level::INFO, 0x2));
+        assert!(!data.is_event_enabled(Level::ERROR, 0x1));
+
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &b,
+            0,
+            0,
+            0,
+            Default::default(),
+        );
+        let state = data.enable_state();
+        assert_eq!(state.level, Level(255));
+        assert_eq!(state.match_any_keyword, u64::MAX);
+        assert!(data.is_event_enabled(Level::VERBOSE, 0x80));
+
+        // Disabling clears all state, whichever session sent the request.
+        data.enable_callback(
+            EVENT_CONTROL_CODE_DISABLE_PROVIDER,
+            &a,
+            0,
+            0,
+            0,
+            Default::default(),
+        );
+        assert_eq!(data.enable_state(), EnableState::default());
+        assert!(!data.is_event_enabled(Level::CRITICAL, 0));
+    }
+
+    #[test]
+    fn enable_does_not_interrupt_enabled_events() {
+        let data = std::sync::Arc::new(StableProviderData::new());
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &GUID::default(),
+            4,
+            0x1,
+            0,
+            Default::default(),
+        );
+        let done = std::sync::Arc::new(AtomicBool::new(false));
+        let reader = {
+            let data = data.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(SeqCst) {
+                    // Every request below enables this event.
+                    assert!(data.is_event_enabled(Level::ERROR, 0x1));
+                }
+            })
+        };
+        for i in 0..10_000u64 {
+            data.enable_callback(
+                EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+                &GUID::default(),
+                4 + (i % 2) as u8,
+                0x1 | (2 << (i % 2)),
+                0,
+                Default::default(),
+            );
+        }
+        done.store(true, SeqCst);
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn enable_state_changed() {
+        let data = StableProviderData::new();
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let s = states.clone();
+        data.set_enable_state_callback(Some(EnableStateCallback::new(move |state| {
+            s.lock().unwrap().push(state.clone());
+        })));
+
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &GUID::default(),
+            3,
+            0x5,
+            0,
+            Default::default(),
+        );
+        // Capture-state requests do not change the enable state.
+        data.enable_callback(
+            EVENT_CONTROL_CODE_CAPTURE_STATE,
+            &GUID::default(),
+            3,
+            0x5,
+            0,
+            Default::default(),
+        );
+        data.enable_callback(
+            EVENT_CONTROL_CODE_DISABLE_PROVIDER,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            Default::default(),
+        );
+
+        let states = states.lock().unwrap();
+        assert_eq!(states.len(), 2);
+        assert!(states[0].enabled);
+        assert_eq!(states[0].level, Level::WARN);
+        assert_eq!(states[0].match_any_keyword, 0x5);
+        assert_eq!(states[1], EnableState::default());
+    }
+
+    #[test]
+    fn event_id_filters() {
+        let data = StableProviderData::new();
+        let filter = |filter_in, ids: &[u16]| EventFilters {
+            event_ids: Some(EventIdFilter {
+                filter_in,
+                event_ids: ids.to_vec(),
+            }),
+            ..Default::default()
+        };
+
+        assert!(data.is_event_id_enabled(1));
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            filter(true, &[1, 2]),
+        );
+        assert!(data.is_event_id_enabled(1));
+        assert!(!data.is_event_id_enabled(3));
+        assert_eq!(data.enable_state().filters, filter(true, &[1, 2]));
+        // The filter belongs to one session, so it does not disable events for other sessions.
+        assert!(data.is_event_enabled(Level::VERBOSE, 0x8));
+
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            filter(false, &[1]),
+        );
+        assert!(!data.is_event_id_enabled(1));
+        assert!(data.is_event_id_enabled(3));
+
+        // A request without an event ID filter enables all events.
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            EventFilters::default(),
+        );
+        assert!(data.is_event_id_enabled(1));
+
+        data.enable_callback(
+            EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            filter(true, &[2]),
+        );
+        data.enable_callback(
+            EVENT_CONTROL_CODE_DISABLE_PROVIDER,
+            &GUID::default(),
+            0,
+            0,
+            0,
+            EventFilters::default(),
+        );
+        assert!(data.is_event_id_enabled(3));
+        assert_eq!(data.enable_state().filters, EventFilters::default());
+    }
+}
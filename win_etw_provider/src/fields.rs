@@ -0,0 +1,387 @@
+//! Support for event fields whose data is built at runtime: types that implement `EventField`,
+//! such as structs that derive `EventFields` and enums that derive `EventEnum`, and arrays of
+//! strings.
+
+use crate::types::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use crate::{EventDataDescriptor, FILETIME, GUID};
+use alloc::vec::Vec;
+use widestring::{U16CStr, U16Str};
+use win_etw_metadata::{InFlag, MetadataBuilder, OutFlag};
+use zerocopy::IntoBytes;
+
+/// A type that can be used as the type of an event field, and which is encoded as a TraceLogging
+/// structure: a named group of fields.
+///
+/// Use `#[derive(EventFields)]` (from `win_etw_macros`) to implement this trait for a struct with
+/// named fields. The derive also implements `EventField`, which generated code uses to encode the
+/// struct, so this trait should not be implemented by hand. Each field of the struct must have a
+/// type that can be used as an event parameter, including other types that implement
+/// `EventFields`.
+///
+/// ```ignore
+/// #[derive(EventFields)]
+/// struct RequestInfo<'a> {
+///     url: &'a str,
+///     status: u32,
+/// }
+///
+/// #[trace_logging_provider]
+/// trait MyAppEvents {
+///     fn request(info: &RequestInfo);
+/// }
+/// ```
+pub trait EventFields {
+    /// The number of fields in the structure. This must be between 1 and 127.
+    const FIELD_COUNT: u8;
+
+    /// The metadata of the fields, in the format that is used for the fields of an event.
+    const FIELD_METADATA: MetadataBuilder;
+
+    /// Appends the data of the fields to `data`, in the order that is described by
+    /// `FIELD_METADATA`.
+    fn write_fields(&self, data: &mut Vec<u8>);
+}
+
+/// A fieldless enum that can be used as the type of an event field. It is encoded as the integer
+/// type that is specified by its `#[repr]` attribute.
+///
+/// Use `#[derive(EventEnum)]` (from `win_etw_macros`) to implement this trait. If an event
+/// parameter has the `#[event(variant_name)]` attribute, then the name of the variant is also
+/// written, as a string field whose name is the parameter name followed by `_name`.
+///
+/// ```ignore
+/// #[derive(Clone, Copy, EventEnum)]
+/// #[repr(u8)]
+/// enum ConnectionState {
+///     Connecting,
+///     Connected,
+///     Closed = 10,
+/// }
+///
+/// #[trace_logging_provider]
+/// trait MyAppEvents {
+///     fn state_changed(#[event(variant_name)] state: ConnectionState);
+/// }
+/// ```
+pub trait EventEnum {
+    /// The name of the variant.
+    fn variant_name(&self) -> &'static str;
+}
+
+/// A type that can be used as the type of an event field, and which provides its own encoding.
+///
+/// `win_etw_macros` recognizes the well-known field types (integers, strings, `GUID`, etc.) by
+/// name. Any other named type, including a type alias, must implement this trait. The trait is
+/// implemented by `#[derive(EventFields)]` and `#[derive(EventEnum)]`, and it is also implemented
+/// for the well-known types, so an alias such as `type Timestamp = SystemTime;` can be used as
+/// the type of a field.
+///
+/// Implement this trait to use your own types as event fields. `IN_TYPE` specifies how the data
+/// of the field is encoded, and `OUT_TYPE` specifies how trace consumers should display it.
+///
+/// ```
+/// use win_etw_provider::metadata::{InFlag, OutFlag};
+/// use win_etw_provider::{counted_bytes, EventDataDescriptor, EventField};
+///
+/// /// Displayed in hexadecimal.
+/// struct UserId(u64);
+///
+/// impl EventField for UserId {
+///     const IN_TYPE: InFlag = InFlag::UINT64;
+///     const OUT_TYPE: OutFlag = OutFlag::HEX;
+///
+///     fn data_descriptor<'a>(&'a self, _buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+///         EventDataDescriptor::from(&self.0)
+///     }
+/// }
+///
+/// /// Binary data is preceded by its length.
+/// struct Sha256([u8; 32]);
+///
+/// impl EventField for Sha256 {
+///     const IN_TYPE: InFlag = InFlag::BINARY;
+///
+///     fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+///         counted_bytes(&self.0, buffer)
+///     }
+/// }
+/// ```
+pub trait EventField {
+    /// The type of the data of the field.
+    const IN_TYPE: InFlag;
+
+    /// How the field should be displayed. `OutFlag::NULL` uses the default format of `IN_TYPE`.
+    const OUT_TYPE: OutFlag = OutFlag::NULL;
+
+    /// The metadata of the field that follows the field name: the InType and OutType of the
+    /// field, and for a structure, the metadata of its fields. By default, this is built from
+    /// `IN_TYPE` and `OUT_TYPE`. Only types that are encoded as structures need to override it.
+    const TYPE_METADATA: MetadataBuilder = type_metadata(Self::IN_TYPE, Self::OUT_TYPE);
+
+    /// Returns a data descriptor that points to the value of the field. If the value must be
+    /// converted before it is written, then the converted value is stored in `buffer`.
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a>;
+}
+
+impl<T: EventField + ?Sized> EventField for &T {
+    const IN_TYPE: InFlag = T::IN_TYPE;
+    const OUT_TYPE: OutFlag = T::OUT_TYPE;
+    const TYPE_METADATA: MetadataBuilder = T::TYPE_METADATA;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        T::data_descriptor(*self, buffer)
+    }
+}
+
+/// Builds the metadata of a field whose type is described by `in_type` and `out_type`.
+const fn type_metadata(in_type: InFlag, out_type: OutFlag) -> MetadataBuilder {
+    if out_type.bits() == OutFlag::NULL.bits() {
+        MetadataBuilder::new().bytes(&[in_type.bits()])
+    } else {
+        MetadataBuilder::new().bytes(&[in_type.bits() | InFlag::CHAIN_FLAG.bits(), out_type.bits()])
+    }
+}
+
+/// Stores `bytes` in `buffer`, preceded by their length, as a little-endian `u16`, and returns a
+/// data descriptor that points to `buffer`. This is the encoding of `InFlag::BINARY` and of the
+/// counted string types. Only the first 65535 bytes are stored.
+pub fn counted_bytes<'a>(bytes: &[u8], buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+    let bytes = &bytes[..bytes.len().min(0xffff)];
+    buffer.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(bytes);
+    EventDataDescriptor::from(&buffer[..])
+}
+
+/// Stores a counted UTF-16 string in `buffer`. Only the first 32767 code units are stored, so
+/// that the length in bytes fits in a `u16`.
+fn counted_u16_string<'a>(s: &[u16], buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+    counted_bytes(s[..s.len().min(0x7fff)].as_bytes(), buffer)
+}
+
+macro_rules! primitive_event_fields {
+    (
+        $(
+            $t:ty => $in_type:expr, $out_type:expr;
+        )*
+    ) => {
+        $(
+            impl EventField for $t {
+                const IN_TYPE: InFlag = $in_type;
+                const OUT_TYPE: OutFlag = $out_type;
+
+                fn data_descriptor<'a>(&'a self, _buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+                    EventDataDescriptor::from(self)
+                }
+            }
+        )*
+    }
+}
+
+primitive_event_fields! {
+    bool => InFlag::UINT8, OutFlag::BOOLEAN;
+    u8 => InFlag::UINT8, OutFlag::NULL;
+    u16 => InFlag::UINT16, OutFlag::NULL;
+    u32 => InFlag::UINT32, OutFlag::NULL;
+    u64 => InFlag::UINT64, OutFlag::NULL;
+    i8 => InFlag::INT8, OutFlag::NULL;
+    i16 => InFlag::INT16, OutFlag::NULL;
+    i32 => InFlag::INT32, OutFlag::NULL;
+    i64 => InFlag::INT64, OutFlag::NULL;
+    f32 => InFlag::FLOAT, OutFlag::NULL;
+    f64 => InFlag::DOUBLE, OutFlag::NULL;
+    usize => InFlag::USIZE, OutFlag::NULL;
+    isize => InFlag::ISIZE, OutFlag::NULL;
+    GUID => InFlag::GUID, OutFlag::NULL;
+}
+
+impl EventField for str {
+    const IN_TYPE: InFlag = InFlag::COUNTED_ANSI_STRING;
+    const OUT_TYPE: OutFlag = OutFlag::UTF8;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        counted_bytes(self.as_bytes(), buffer)
+    }
+}
+
+impl EventField for U16Str {
+    const IN_TYPE: InFlag = InFlag::COUNTED_UNICODE_STRING;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        counted_u16_string(self.as_slice(), buffer)
+    }
+}
+
+impl EventField for U16CStr {
+    const IN_TYPE: InFlag = InFlag::COUNTED_UNICODE_STRING;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        counted_u16_string(self.as_slice(), buffer)
+    }
+}
+
+impl EventField for FILETIME {
+    const IN_TYPE: InFlag = InFlag::FILETIME;
+
+    fn data_descriptor<'a>(&'a self, _buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        EventDataDescriptor::from(&self.0)
+    }
+}
+
+impl EventField for core::net::SocketAddrV4 {
+    const IN_TYPE: InFlag = InFlag::BINARY;
+    const OUT_TYPE: OutFlag = OutFlag::SOCKETADDRESS;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        counted_bytes(SocketAddrV4::from(self).as_bytes(), buffer)
+    }
+}
+
+impl EventField for core::net::SocketAddrV6 {
+    const IN_TYPE: InFlag = InFlag::BINARY;
+    const OUT_TYPE: OutFlag = OutFlag::SOCKETADDRESS;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        counted_bytes(SocketAddrV6::from(self).as_bytes(), buffer)
+    }
+}
+
+impl EventField for core::net::SocketAddr {
+    const IN_TYPE: InFlag = InFlag::BINARY;
+    const OUT_TYPE: OutFlag = OutFlag::SOCKETADDRESS;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        match self {
+            core::net::SocketAddr::V4(a) => a.data_descriptor(buffer),
+            core::net::SocketAddr::V6(a) => a.data_descriptor(buffer),
+        }
+    }
+}
+
+impl EventField for core::net::Ipv4Addr {
+    const IN_TYPE: InFlag = InFlag::UINT32;
+    const OUT_TYPE: OutFlag = OutFlag::IPV4;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        buffer.extend_from_slice(Ipv4Addr::from(self).as_bytes());
+        EventDataDescriptor::from(&buffer[..])
+    }
+}
+
+impl EventField for core::net::Ipv6Addr {
+    const IN_TYPE: InFlag = InFlag::BINARY;
+    const OUT_TYPE: OutFlag = OutFlag::IPV6;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        counted_bytes(Ipv6Addr::from(self).as_bytes(), buffer)
+    }
+}
+
+impl EventField for core::net::IpAddr {
+    const IN_TYPE: InFlag = InFlag::BINARY;
+    const OUT_TYPE: OutFlag = OutFlag::IPV6;
+
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        counted_bytes(Ipv6Addr::from(self).as_bytes(), buffer)
+    }
+}
+
+#[cfg(feature = "std")]
+impl EventField for std::time::SystemTime {
+    const IN_TYPE: InFlag = InFlag::FILETIME;
+
+    // If the time cannot be converted to a `FILETIME`, then the data descriptor is empty.
+    fn data_descriptor<'a>(&'a self, buffer: &'a mut Vec<u8>) -> EventDataDescriptor<'a> {
+        use core::convert::TryFrom;
+        match FILETIME::try_from(*self) {
+            Ok(t) => {
+                buffer.extend_from_slice(&t.0.to_le_bytes());
+                EventDataDescriptor::from(&buffer[..])
+            }
+            Err(_) => EventDataDescriptor::empty(),
+        }
+    }
+}
+
+/// Appends the data that `descriptors` point to. This is called by generated code.
+#[doc(hidden)]
+pub fn append_event_data(descriptors: &[EventDataDescriptor<'_>], data: &mut Vec<u8>) {
+    for descriptor in descriptors.iter() {
+        data.extend_from_slice(descriptor.as_bytes());
+    }
+}
+
+/// Appends an array of counted UTF-8 strings: the number of strings, followed by the length
+/// and the bytes of each string. This is called by generated code.
+///
+/// The array is encoded into one buffer, rather than with two data descriptors per string,
+/// because ETW limits the number of data descriptors of an event. Only the first 65535 strings,
+/// and the first 65535 bytes of each string, are written.
+#[doc(hidden)]
+pub fn append_str_array<S: AsRef<str>>(strings: &[S], data: &mut Vec<u8>) {
+    let strings = &strings[..strings.len().min(0xffff)];
+    data.extend_from_slice(&(strings.len() as u16).to_le_bytes());
+    for s in strings.iter() {
+        let bytes = s.as_ref().as_bytes();
+        let bytes = &bytes[..bytes.len().min(0xffff)];
+        data.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+        data.extend_from_slice(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append() {
+        let a = 1u16;
+        let b = [2u8, 3];
+        let mut data = Vec::new();
+        append_event_data(
+            &[
+                EventDataDescriptor::from(&a),
+                EventDataDescriptor::from(&b[..]),
+            ],
+            &mut data,
+        );
+        assert_eq!(data, [1, 0, 2, 3]);
+    }
+
+    #[test]
+    fn str_array() {
+        let mut data = Vec::new();
+        append_str_array(&["ab", "", "c"], &mut data);
+        assert_eq!(data, [3, 0, 2, 0, b'a', b'b', 0, 0, 1, 0, b'c']);
+    }
+
+    #[test]
+    fn well_known_types() {
+        assert_eq!(u16::TYPE_METADATA.as_bytes(), [InFlag::UINT16.bits()]);
+        assert_eq!(<&u16>::TYPE_METADATA.as_bytes(), [InFlag::UINT16.bits()]);
+        assert_eq!(
+            bool::TYPE_METADATA.as_bytes(),
+            [
+                InFlag::UINT8.bits() | InFlag::CHAIN_FLAG.bits(),
+                OutFlag::BOOLEAN.bits()
+            ]
+        );
+
+        let mut buffer = Vec::new();
+        assert_eq!(
+            "ab".data_descriptor(&mut buffer).as_bytes(),
+            [2, 0, b'a', b'b']
+        );
+        let mut buffer = Vec::new();
+        let addr = core::net::SocketAddrV4::new(core::net::Ipv4Addr::LOCALHOST, 80);
+        let bytes = addr.data_descriptor(&mut buffer).as_bytes();
+        assert_eq!(bytes.len(), 18);
+        assert_eq!(bytes[..6], [16, 0, 2, 0, 0, 80]);
+
+        let mut buffer = Vec::new();
+        let ip = core::net::IpAddr::V4(core::net::Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(
+            ip.data_descriptor(&mut buffer).as_bytes(),
+            [16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 1]
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Parses the filters that an ETW controller can attach to a session, when it enables a provider.
+//!
+//! A controller describes each filter with an `EVENT_FILTER_DESCRIPTOR`, which contains the
+//! filter type and a pointer to the filter data. See
+//! [EVENT_FILTER_DESCRIPTOR](https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_filter_descriptor).
+//! The functions in this module only parse the filter data, so they can be tested on any
+//! platform.
+//!
+//! ```
+//! use win_etw_provider::filter::{EventFilters, EVENT_FILTER_TYPE_EVENT_ID};
+//!
+//! // EVENT_FILTER_EVENT_ID: FilterIn = 1, Reserved = 0, Count = 2, Events = [3, 5]
+//! let data = [1, 0, 2, 0, 3, 0, 5, 0];
+//! let filters = EventFilters::parse([(EVENT_FILTER_TYPE_EVENT_ID, &data[..])]).unwrap();
+//! assert!(filters.is_event_id_enabled(3));
+//! assert!(!filters.is_event_id_enabled(4));
+//! ```
+
+use crate::Error;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+/// The filter data is a chain of schematized filters, which are interpreted by the provider. Each
+/// filter starts with an `EVENT_FILTER_HEADER`.
+pub const EVENT_FILTER_TYPE_SCHEMATIZED: u32 = 0x8000_0000;
+/// The filter data is a list of process IDs (`u32`).
+pub const EVENT_FILTER_TYPE_PID: u32 = 0x8000_0004;
+/// The filter data is a list of executable names, as a UTF-16 string separated by semicolons.
+pub const EVENT_FILTER_TYPE_EXECUTABLE_NAME: u32 = 0x8000_0008;
+/// The filter data is a payload filter, created by `TdhAggregatePayloadFilters`.
+pub const EVENT_FILTER_TYPE_PAYLOAD: u32 = 0x8000_0100;
+/// The filter data is an `EVENT_FILTER_EVENT_ID` structure.
+pub const EVENT_FILTER_TYPE_EVENT_ID: u32 = 0x8000_0200;
+
+/// Allows or denies events, based on their event ID.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EventIdFilter {
+    /// If true, only the events in `event_ids` are enabled. If false, the events in `event_ids`
+    /// are disabled, and all other events are enabled.
+    pub filter_in: bool,
+    /// The event IDs in the filter.
+    pub event_ids: Vec<u16>,
+}
+
+impl EventIdFilter {
+    /// Checks whether this filter enables the event with the given ID.
+    pub fn is_event_id_enabled(&self, id: u16) -> bool {
+        self.event_ids.contains(&id) == self.filter_in
+    }
+}
+
+/// One filter in the chain of a schematized filter (`EVENT_FILTER_TYPE_SCHEMATIZED`). The
+/// format of the data is defined by the provider.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SchematizedFilter {
+    /// The `Id` field of the `EVENT_FILTER_HEADER`, which identifies the filter.
+    pub id: u16,
+    /// The `Version` field of the `EVENT_FILTER_HEADER`.
+    pub version: u8,
+    /// The `InstanceId` field of the `EVENT_FILTER_HEADER`, which identifies the session that
+    /// attached the filter.
+    pub instance_id: u64,
+    /// The filter data that follows the header.
+    pub data: Vec<u8>,
+}
+
+/// A filter whose type this crate does not interpret.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawEventFilter {
+    /// The `EVENT_FILTER_TYPE_*` value.
+    pub filter_type: u32,
+    /// The filter data.
+    pub data: Vec<u8>,
+}
+
+/// The filters that one session attached when it enabled a provider.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EventFilters {
+    /// The event ID filter (`EVENT_FILTER_TYPE_EVENT_ID`), if any.
+    pub event_ids: Option<EventIdFilter>,
+    /// The process IDs that the session is limited to (`EVENT_FILTER_TYPE_PID`). ETW applies this
+    /// filter itself.
+    pub process_ids: Vec<u32>,
+    /// The executable names that the session is limited to (`EVENT_FILTER_TYPE_EXECUTABLE_NAME`).
+    /// ETW applies this filter itself.
+    pub executable_names: Vec<String>,
+    /// The payload filters (`EVENT_FILTER_TYPE_PAYLOAD`). The format of these is defined by TDH,
+    /// and ETW applies them itself, so they are not interpreted.
+    pub payload: Vec<Vec<u8>>,
+    /// The schematized filters (`EVENT_FILTER_TYPE_SCHEMATIZED`), in the order of their chain.
+    pub schematized: Vec<SchematizedFilter>,
+    /// Filters of any other type.
+    pub other: Vec<RawEventFilter>,
+    /// The types of the filters that could not be parsed, when the filters were parsed by
+    /// `from_descriptors`.
+    pub invalid: Vec<u32>,
+}
+
+impl EventFilters {
+    /// Parses a sequence of filters. Each item contains the `Type` field of an
+    /// `EVENT_FILTER_DESCRIPTOR` and the data that its `Ptr` and `Size` fields point to.
+    ///
+    /// Returns `Error::InvalidFilter` if the data of a filter is malformed, or if the sequence
+    /// contains more than one event ID filter.
+    pub fn parse<'a, I>(descriptors: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (u32, &'a [u8])>,
+    {
+        let mut filters = Self::default();
+        for (filter_type, data) in descriptors {
+            filters.add(filter_type, data)?;
+        }
+        Ok(filters)
+    }
+
+    /// Parses a sequence of filters, like `parse`, but does not fail if a filter is malformed.
+    /// Instead, the filter is ignored, and its type is added to `invalid`.
+    pub fn from_descriptors<'a, I>(descriptors: I) -> Self
+    where
+        I: IntoIterator<Item = (u32, &'a [u8])>,
+    {
+        let mut filters = Self::default();
+        for (filter_type, data) in descriptors {
+            if filters.add(filter_type, data).is_err() {
+                filters.invalid.push(filter_type);
+            }
+        }
+        filters
+    }
+
+    fn add(&mut self, filter_type: u32, data: &[u8]) -> Result<(), Error> {
+        match filter_type {
+            EVENT_FILTER_TYPE_EVENT_ID => {
+                if self.event_ids.is_some() {
+                    return Err(Error::InvalidFilter(filter_type));
+                }
+                self.event_ids = Some(parse_event_id_filter(data)?);
+            }
+            EVENT_FILTER_TYPE_PID => {
+                if data.len() % 4 != 0 {
+                    return Err(Error::InvalidFilter(filter_type));
+                }
+                self.process_ids.extend(
+                    data.chunks_exact(4)
+                        .map(|c| u32::from_le_bytes(c.try_into().unwrap())),
+                );
+            }
+            EVENT_FILTER_TYPE_EXECUTABLE_NAME => {
+                if data.len() % 2 != 0 {
+                    return Err(Error::InvalidFilter(filter_type));
+                }
+                let units: Vec<u16> = data
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|&c| c != 0)
+                    .collect();
+                let names = String::from_utf16_lossy(&units);
+                self.executable_names.extend(
+                    names
+                        .split(';')
+                        .filter(|name| !name.is_empty())
+                        .map(String::from),
+                );
+            }
+            EVENT_FILTER_TYPE_PAYLOAD => self.payload.push(data.to_vec()),
+            EVENT_FILTER_TYPE_SCHEMATIZED => {
+                let chain = parse_schematized_filters(data)?;
+                self.schematized.extend(chain);
+            }
+            _ => self.other.push(RawEventFilter {
+                filter_type,
+                data: data.to_vec(),
+            }),
+        }
+        Ok(())
+    }
+
+    /// Checks whether the event ID filter, if any, enables the event with the given ID.
+    pub fn is_event_id_enabled(&self, id: u16) -> bool {
+        match &self.event_ids {
+            Some(filter) => filter.is_event_id_enabled(id),
+            None => true,
+        }
+    }
+}
+
+/// Parses an `EVENT_FILTER_EVENT_ID` structure:
+///
+/// ```text
+/// BOOLEAN FilterIn;
+/// UCHAR   Reserved;
+/// USHORT  Count;
+/// USHORT  Events[Count];
+/// ```
+fn parse_event_id_filter(data: &[u8]) -> Result<EventIdFilter, Error> {
+    let invalid = Error::InvalidFilter(EVENT_FILTER_TYPE_EVENT_ID);
+    if data.len() < 4 {
+        return Err(invalid);
+    }
+    let count = u16::from_le_bytes([data[2], data[3]]) as usize;
+    let events = &data[4..];
+    if events.len() < count * 2 {
+        return Err(invalid);
+    }
+    Ok(EventIdFilter {
+        filter_in: data[0] != 0,
+        event_ids: events[..count * 2]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect(),
+    })
+}
+
+/// The size of an `EVENT_FILTER_HEADER`.
+const EVENT_FILTER_HEADER_SIZE: usize = 24;
+
+/// Parses a chain of schematized filters. Each filter starts with an `EVENT_FILTER_HEADER`:
+///
+/// ```text
+/// USHORT    Id;
+/// UCHAR     Version;
+/// UCHAR     Reserved[5];
+/// ULONGLONG InstanceId;
+/// ULONG     Size;        // The size of the filter, including this header.
+/// ULONG     NextOffset;  // The offset from this filter to the next one, or 0.
+/// ```
+fn parse_schematized_filters(data: &[u8]) -> Result<Vec<SchematizedFilter>, Error> {
+    let invalid = Error::InvalidFilter(EVENT_FILTER_TYPE_SCHEMATIZED);
+    let mut filters = Vec::new();
+    let mut rest = data;
+    loop {
+        if rest.len() < EVENT_FILTER_HEADER_SIZE {
+            return Err(invalid);
+        }
+        let size = u32::from_le_bytes(rest[16..20].try_into().unwrap()) as usize;
+        let next_offset = u32::from_le_bytes(rest[20..24].try_into().unwrap()) as usize;
+        if size < EVENT_FILTER_HEADER_SIZE || size > rest.len() {
+            return Err(invalid);
+        }
+        filters.push(SchematizedFilter {
+            id: u16::from_le_bytes([rest[0], rest[1]]),
+            version: rest[2],
+            instance_id: u64::from_le_bytes(rest[8..16].try_into().unwrap()),
+            data: rest[EVENT_FILTER_HEADER_SIZE..size].to_vec(),
+        });
+        if next_offset == 0 {
+            return Ok(filters);
+        }
+        // Each filter must follow the previous one, so that the chain cannot loop.
+        if next_offset < size || next_offset > rest.len() {
+            return Err(invalid);
+        }
+        rest = &rest[next_offset..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_id_filter() {
+        let deny = [0, 0, 1, 0, 7, 0];
+        let filters = EventFilters::parse([(EVENT_FILTER_TYPE_EVENT_ID, &deny[..])]).unwrap();
+        assert_eq!(
+            filters.event_ids,
+            Some(EventIdFilter {
+                filter_in: false,
+                event_ids: vec![7],
+            })
+        );
+        assert!(!filters.is_event_id_enabled(7));
+        assert!(filters.is_event_id_enabled(8));
+
+        assert!(EventFilters::default().is_event_id_enabled(7));
+
+        // Truncated header, truncated list, and duplicate filter.
+        let truncated = [1, 0, 2, 0, 7, 0];
+        for data in [&[1u8, 0, 2][..], &truncated[..]] {
+            assert_eq!(
+                EventFilters::parse([(EVENT_FILTER_TYPE_EVENT_ID, data)]),
+                Err(Error::InvalidFilter(EVENT_FILTER_TYPE_EVENT_ID))
+            );
+        }
+        assert_eq!(
+            EventFilters::parse([
+                (EVENT_FILTER_TYPE_EVENT_ID, &deny[..]),
+                (EVENT_FILTER_TYPE_EVENT_ID, &deny[..])
+            ]),
+            Err(Error::InvalidFilter(EVENT_FILTER_TYPE_EVENT_ID))
+        );
+    }
+
+    const EVENT_FILTER_TYPE_STACKWALK: u32 = 0x8000_1000;
+
+    #[test]
+    fn other_filters() {
+        let pids = [1, 0, 0, 0, 0x34, 0x12, 0, 0];
+        let names: Vec<u8> = "a.exe;b.exe\0"
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        let payload = [0xaa, 0xbb];
+        let filters = EventFilters::parse([
+            (EVENT_FILTER_TYPE_PID, &pids[..]),
+            (EVENT_FILTER_TYPE_EXECUTABLE_NAME, &names[..]),
+            (EVENT_FILTER_TYPE_PAYLOAD, &payload[..]),
+            (EVENT_FILTER_TYPE_STACKWALK, &payload[..]),
+        ])
+        .unwrap();
+        assert_eq!(filters.process_ids, vec![1, 0x1234]);
+        assert_eq!(filters.executable_names, vec!["a.exe", "b.exe"]);
+        assert_eq!(filters.payload, vec![payload.to_vec()]);
+        assert_eq!(
+            filters.other,
+            vec![RawEventFilter {
+                filter_type: EVENT_FILTER_TYPE_STACKWALK,
+                data: payload.to_vec(),
+            }]
+        );
+        assert!(filters.event_ids.is_none());
+
+        assert_eq!(
+            EventFilters::parse([(EVENT_FILTER_TYPE_PID, &pids[..3])]),
+            Err(Error::InvalidFilter(EVENT_FILTER_TYPE_PID))
+        );
+    }
+
+    fn filter_header(id: u16, instance_id: u64, data: &[u8], next_offset: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&id.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&instance_id.to_le_bytes());
+        bytes.extend_from_slice(&(24 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&next_offset.to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn schematized_filters() {
+        // Two filters, with padding between them.
+        let mut chain = filter_header(3, 10, &[0xaa], 32);
+        chain.resize(32, 0);
+        chain.extend(filter_header(4, 11, &[0xbb, 0xcc], 0));
+        let filters = EventFilters::parse([(EVENT_FILTER_TYPE_SCHEMATIZED, &chain[..])]).unwrap();
+        assert_eq!(
+            filters.schematized,
+            vec![
+                SchematizedFilter {
+                    id: 3,
+                    version: 1,
+                    instance_id: 10,
+                    data: vec![0xaa],
+                },
+                SchematizedFilter {
+                    id: 4,
+                    version: 1,
+                    instance_id: 11,
+                    data: vec![0xbb, 0xcc],
+                },
+            ]
+        );
+
+        // Truncated header, size past the end, and a chain that points backwards.
+        let past_end = filter_header(3, 10, &[0xaa], 0);
+        let backwards = filter_header(3, 10, &[0xaa], 4);
+        for data in [
+            &chain[..20],
+            &past_end[..past_end.len() - 1],
+            &backwards[..],
+        ] {
+            assert_eq!(
+                EventFilters::parse([(EVENT_FILTER_TYPE_SCHEMATIZED, data)]),
+                Err(Error::InvalidFilter(EVENT_FILTER_TYPE_SCHEMATIZED))
+            );
+        }
+    }
+
+    #[test]
+    fn from_descriptors() {
+        let pids = [1, 0, 0, 0];
+        let truncated = [1, 0, 2, 0, 7, 0];
+        let filters = EventFilters::from_descriptors([
+            (EVENT_FILTER_TYPE_EVENT_ID, &truncated[..]),
+            (EVENT_FILTER_TYPE_PID, &pids[..]),
+            (EVENT_FILTER_TYPE_PID, &pids[..3]),
+        ]);
+        assert_eq!(filters.event_ids, None);
+        assert_eq!(filters.process_ids, vec![1]);
+        assert_eq!(
+            filters.invalid,
+            vec![EVENT_FILTER_TYPE_EVENT_ID, EVENT_FILTER_TYPE_PID]
+        );
+    }
+}
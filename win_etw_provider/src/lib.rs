@@ -8,8 +8,15 @@
 
 extern crate alloc;
 
+mod activity;
+#[cfg(not(target_os = "windows"))]
+mod activity_id;
+mod enable;
+mod fields;
+pub mod filter;
 mod guid;
 mod provider;
+mod sync;
 
 pub mod types;
 
@@ -19,6 +26,25 @@ pub use guid::GUID;
 #[doc(inline)]
 pub use provider::*;
 
+#[doc(inline)]
+pub use activity::ActivityGuard;
+
+#[doc(hidden)]
+pub use activity::apply_activity_scope;
+
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use activity::{scoped_activity_id, with_activity_id, ActivityScope};
+
+#[doc(inline)]
+pub use fields::{counted_bytes, EventEnum, EventField, EventFields};
+
+#[doc(hidden)]
+pub use fields::{append_event_data, append_str_array};
+
+#[doc(inline)]
+pub use enable::{CaptureStateCallback, CaptureStateRequest, EnableState, EnableStateCallback};
+
 #[doc(hidden)]
 pub use types::*;
 
@@ -33,9 +59,32 @@ mod data_descriptor;
 #[doc(inline)]
 pub use data_descriptor::EventDataDescriptor;
 
+#[cfg(feature = "std")]
+mod recording;
+
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use recording::{RecordedEvent, RecordingProvider};
+
+#[cfg(feature = "std")]
+mod trace_file;
+
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use trace_file::{FileProvider, FileReader, FileRecord, FILE_SIGNATURE, FILE_VERSION};
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+mod user_events;
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+#[doc(inline)]
+pub use user_events::UserEventsProvider;
+
 /// Errors returned by `win_etw_provider` functions.
 ///
-/// When compiling for non-Windows platforms, this Error type becomes an uninhabited type.
+/// The variants for operating system error codes depend on the target platform. The enum is
+/// `#[non_exhaustive]`, so adding variants, such as `InvalidFilter`, does not break code that
+/// matches on it.
 #[derive(Clone, PartialEq, Eq, Debug)]
 #[non_exhaustive]
 pub enum Error {
@@ -43,16 +92,24 @@ pub enum Error {
     #[cfg(target_os = "windows")]
     WindowsError(u32),
 
+    /// A Linux error code (`errno`).
+    #[cfg(target_os = "linux")]
+    LinuxError(i32),
+
     /// The operation is not supported on this platform.
     ///
     /// Most operations defined in this crate do nothing on non-Windows platforms. Those operations
     /// that return information, such as the `new_activity_id()` function, use this error value.
     NotSupported,
+
+    /// The data of an event filter is malformed. The value is the `EVENT_FILTER_TYPE_*` of the
+    /// filter. See the `filter` module.
+    InvalidFilter(u32),
 }
 
 /// Allows an application to override the parameters for an event. The first parameter of each
 /// generated event method is `options: Option<&EventOptions>`.
-#[derive(Default)]
+#[derive(Clone, Debug, Default)]
 pub struct EventOptions {
     /// Overrides the level of the event, if present. Each event method has a default, which can be
     /// specified using (for example) `#[event(level = "warn")]`. If the event declaration does not
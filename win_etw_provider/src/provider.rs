@@ -1,11 +1,11 @@
+use crate::enable::StableProviderData;
 use crate::guid::GUID;
 use crate::Level;
-use crate::{Error, EventDataDescriptor};
+use crate::{CaptureStateCallback, EnableStateCallback, Error, EventDataDescriptor};
 use alloc::boxed::Box;
 use core::convert::TryFrom;
 use core::pin::Pin;
 use core::ptr::null;
-use core::sync::atomic::{AtomicU8, Ordering::SeqCst};
 #[cfg(target_os = "windows")]
 use windows_sys::Win32::System::Diagnostics::Etw::{EventProviderSetTraits, REGHANDLE};
 
@@ -14,7 +14,9 @@ use win_support::*;
 
 /// Generates a new activity ID.
 ///
-/// This function is only implemented on Windows. On other platforms, it will always return `Err`.
+/// On Windows, ETW generates the activity ID. On other platforms, this generates a version 7
+/// UUID (or a version 4 UUID, without the `std` feature), which is unique within the process and
+/// very likely to be unique across processes.
 pub fn new_activity_id() -> Result<GUID, Error> {
     #[cfg(target_os = "windows")]
     {
@@ -23,13 +25,15 @@ pub fn new_activity_id() -> Result<GUID, Error> {
 
     #[cfg(not(target_os = "windows"))]
     {
-        Err(Error::NotSupported)
+        Ok(crate::activity_id::new_activity_id())
     }
 }
 
 /// Gets the current activity ID.
 ///
-/// This function is only implemented on Windows. On other platforms, it will always return `Err`.
+/// On Windows, this is the activity ID that ETW stores for the current thread. On other platforms,
+/// it is the scoped activity ID (see `scoped_activity_id`), or all zeroes if there is none. This
+/// requires the `std` feature; without it, this function always returns `Err` on other platforms.
 pub fn get_current_thread_activity_id() -> Result<GUID, Error> {
     #[cfg(target_os = "windows")]
     {
@@ -44,7 +48,12 @@ pub fn get_current_thread_activity_id() -> Result<GUID, Error> {
         }
     }
 
-    #[cfg(not(target_os = "windows"))]
+    #[cfg(all(not(target_os = "windows"), feature = "std"))]
+    {
+        Ok(crate::activity::scoped_activity_id().unwrap_or_default())
+    }
+
+    #[cfg(all(not(target_os = "windows"), not(feature = "std")))]
     {
         Err(Error::NotSupported)
     }
@@ -66,6 +75,29 @@ pub trait Provider {
 
     /// Checks whether a specific event is enabled.
     fn is_event_enabled(&self, event_descriptor: &EventDescriptor) -> bool;
+
+    /// Checks whether the event ID filter of the most recent enable request, if any, allows the
+    /// event with the given ID. ETW applies event ID filters to each session itself, so this is
+    /// only a hint, which is used by the generated `*_is_enabled` methods. It is not checked
+    /// before events are written, because the filter of one session must not affect the events
+    /// that other sessions receive.
+    fn is_event_id_enabled(&self, id: u16) -> bool {
+        let _ = id;
+        true
+    }
+
+    /// Sets (or clears) the function that is called when an ETW controller asks the provider to
+    /// capture its state. Providers that never receive such requests ignore the callback.
+    fn set_capture_state_callback(&self, callback: Option<CaptureStateCallback>) {
+        let _ = callback;
+    }
+
+    /// Sets (or clears) the function that is called when an ETW session enables or disables the
+    /// provider, or changes its level or keywords. Providers that are never enabled or disabled
+    /// by a session ignore the callback.
+    fn set_enable_state_callback(&self, callback: Option<EnableStateCallback>) {
+        let _ = callback;
+    }
 }
 
 /// Implements `Provider` by discarding all events.
@@ -112,6 +144,128 @@ impl<T: Provider> Provider for Option<T> {
             None => false,
         }
     }
+    fn is_event_id_enabled(&self, id: u16) -> bool {
+        match self {
+            Some(p) => p.is_event_id_enabled(id),
+            None => true,
+        }
+    }
+
+    fn set_capture_state_callback(&self, callback: Option<CaptureStateCallback>) {
+        if let Some(p) = self {
+            p.set_capture_state_callback(callback);
+        }
+    }
+
+    fn set_enable_state_callback(&self, callback: Option<EnableStateCallback>) {
+        if let Some(p) = self {
+            p.set_enable_state_callback(callback);
+        }
+    }
+}
+
+macro_rules! forward_provider {
+    ($($t:ty),*) => {
+        $(
+            impl<T: Provider + ?Sized> Provider for $t {
+                fn write(
+                    &self,
+                    options: Option<&crate::EventOptions>,
+                    descriptor: &EventDescriptor,
+                    data: &[EventDataDescriptor<'_>],
+                ) {
+                    (**self).write(options, descriptor, data)
+                }
+
+                fn is_enabled(&self, level: u8, keyword: u64) -> bool {
+                    (**self).is_enabled(level, keyword)
+                }
+                fn is_event_enabled(&self, event_descriptor: &EventDescriptor) -> bool {
+                    (**self).is_event_enabled(event_descriptor)
+                }
+                fn is_event_id_enabled(&self, id: u16) -> bool {
+                    (**self).is_event_id_enabled(id)
+                }
+
+                fn set_capture_state_callback(&self, callback: Option<CaptureStateCallback>) {
+                    (**self).set_capture_state_callback(callback)
+                }
+
+                fn set_enable_state_callback(&self, callback: Option<EnableStateCallback>) {
+                    (**self).set_enable_state_callback(callback)
+                }
+            }
+        )*
+    }
+}
+
+forward_provider!(&T, Box<T>, alloc::sync::Arc<T>);
+
+/// Either an `EtwProvider`, or a provider implementation supplied by the application.
+///
+/// This is the provider type stored by the code that is generated by `win_etw_macros`. It allows
+/// applications to redirect the events of a generated provider, for example to a
+/// `RecordingProvider` in unit tests, without changing the type of the generated provider.
+pub enum AnyProvider {
+    /// Events are written to ETW.
+    Etw(EtwProvider),
+    /// Events are written to a provider supplied by the application.
+    Other(Box<dyn Provider + Send + Sync>),
+}
+
+impl AnyProvider {
+    /// Wraps an application-supplied provider.
+    pub fn other<P: Provider + Send + Sync + 'static>(provider: P) -> Self {
+        AnyProvider::Other(Box::new(provider))
+    }
+}
+
+impl Provider for AnyProvider {
+    #[inline(always)]
+    fn write(
+        &self,
+        options: Option<&crate::EventOptions>,
+        descriptor: &EventDescriptor,
+        data: &[EventDataDescriptor<'_>],
+    ) {
+        match self {
+            AnyProvider::Etw(p) => p.write(options, descriptor, data),
+            AnyProvider::Other(p) => p.write(options, descriptor, data),
+        }
+    }
+
+    fn is_enabled(&self, level: u8, keyword: u64) -> bool {
+        match self {
+            AnyProvider::Etw(p) => p.is_enabled(level, keyword),
+            AnyProvider::Other(p) => p.is_enabled(level, keyword),
+        }
+    }
+    fn is_event_enabled(&self, event_descriptor: &EventDescriptor) -> bool {
+        match self {
+            AnyProvider::Etw(p) => p.is_event_enabled(event_descriptor),
+            AnyProvider::Other(p) => p.is_event_enabled(event_descriptor),
+        }
+    }
+    fn is_event_id_enabled(&self, id: u16) -> bool {
+        match self {
+            AnyProvider::Etw(p) => p.is_event_id_enabled(id),
+            AnyProvider::Other(p) => p.is_event_id_enabled(id),
+        }
+    }
+
+    fn set_capture_state_callback(&self, callback: Option<CaptureStateCallback>) {
+        match self {
+            AnyProvider::Etw(p) => p.set_capture_state_callback(callback),
+            AnyProvider::Other(p) => p.set_capture_state_callback(callback),
+        }
+    }
+
+    fn set_enable_state_callback(&self, callback: Option<EnableStateCallback>) {
+        match self {
+            AnyProvider::Etw(p) => p.set_enable_state_callback(callback),
+            AnyProvider::Other(p) => p.set_enable_state_callback(callback),
+        }
+    }
 }
 
 /// Implements `Provider` by registering with ETW.
@@ -119,8 +273,6 @@ pub struct EtwProvider {
     #[cfg(target_os = "windows")]
     handle: REGHANDLE,
 
-    #[cfg(target_os = "windows")]
-    // #[allow(dead_code)] // Needed for lifetime control
     stable: Pin<Box<StableProviderData>>,
 }
 
@@ -188,29 +340,25 @@ impl Provider for EtwProvider {
         }
         #[cfg(not(target_os = "windows"))]
         {
-            false
+            self.stable.is_event_enabled(Level(level), keyword)
         }
     }
 
     fn is_event_enabled(&self, event_descriptor: &EventDescriptor) -> bool {
-        #[cfg(target_os = "windows")]
-        {
-            if false {
-                unsafe {
-                    EventEnabled(
-                        self.handle,
-                        event_descriptor as *const _ as *const EVENT_DESCRIPTOR,
-                    )
-                }
-            } else {
-                let max_level = self.stable.as_ref().max_level.load(SeqCst);
-                event_descriptor.level.0 <= max_level
-            }
-        }
-        #[cfg(not(target_os = "windows"))]
-        {
-            false
-        }
+        self.stable
+            .is_event_enabled(event_descriptor.level, event_descriptor.keyword)
+    }
+
+    fn is_event_id_enabled(&self, id: u16) -> bool {
+        self.stable.is_event_id_enabled(id)
+    }
+
+    fn set_capture_state_callback(&self, callback: Option<CaptureStateCallback>) {
+        self.stable.set_capture_state_callback(callback);
+    }
+
+    fn set_enable_state_callback(&self, callback: Option<EnableStateCallback>) {
+        self.stable.set_enable_state_callback(callback);
     }
 }
 
@@ -226,32 +374,24 @@ fn write_failed(_error: u32) {
 mod win_support {
     pub use windows_sys::Win32::Foundation::ERROR_SUCCESS;
     pub use windows_sys::Win32::System::Diagnostics::Etw::{
-        EventActivityIdControl, EventEnabled, EventProviderEnabled, EventRegister,
-        EventSetInformation, EventUnregister, EventWriteEx, ENABLECALLBACK_ENABLED_STATE,
-        EVENT_ACTIVITY_CTRL_CREATE_ID, EVENT_ACTIVITY_CTRL_CREATE_SET_ID,
-        EVENT_ACTIVITY_CTRL_GET_ID, EVENT_ACTIVITY_CTRL_SET_ID, EVENT_CONTROL_CODE_CAPTURE_STATE,
-        EVENT_CONTROL_CODE_DISABLE_PROVIDER, EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+        EventActivityIdControl, EventProviderEnabled, EventRegister, EventSetInformation,
+        EventUnregister, EventWriteEx, ENABLECALLBACK_ENABLED_STATE, EVENT_ACTIVITY_CTRL_CREATE_ID,
+        EVENT_ACTIVITY_CTRL_CREATE_SET_ID, EVENT_ACTIVITY_CTRL_GET_ID, EVENT_ACTIVITY_CTRL_SET_ID,
         EVENT_DATA_DESCRIPTOR, EVENT_DESCRIPTOR, EVENT_FILTER_DESCRIPTOR,
     };
 
     use super::*;
-
-    /// This data is stored in a Box, so that it has a stable address.
-    /// It is used to coordinate with ETW; ETW runs callbacks that need a stable pointer.
-    /// See `EventRegister` and the "enable callback".
-    pub(crate) struct StableProviderData {
-        pub(crate) max_level: AtomicU8,
-    }
+    use crate::filter::EventFilters;
 
     /// See [PENABLECALLBACK](https://docs.microsoft.com/en-us/windows/win32/api/evntprov/nc-evntprov-penablecallback).
     #[allow(non_snake_case)]
     pub(crate) unsafe extern "system" fn enable_callback(
-        _source_id: *const windows_sys::core::GUID,
+        source_id: *const windows_sys::core::GUID,
         is_enabled_code: ENABLECALLBACK_ENABLED_STATE,
         level: u8,
-        _match_any_keyword: u64,
-        _match_all_keyword: u64,
-        _filter_data: *const EVENT_FILTER_DESCRIPTOR,
+        match_any_keyword: u64,
+        match_all_keyword: u64,
+        filter_data: *const EVENT_FILTER_DESCRIPTOR,
         context: *mut core::ffi::c_void,
     ) {
         // This should never happen.
@@ -260,54 +400,41 @@ mod win_support {
         }
         let stable_data: &StableProviderData = &*(context as *const _ as *const StableProviderData);
 
-        let _source_id: GUID = if _source_id.is_null() {
+        let source_id: GUID = if source_id.is_null() {
             GUID::default()
         } else {
-            (*(_source_id as *const GUID)).clone()
+            (*(source_id as *const GUID)).clone()
         };
         #[cfg(feature = "dev")]
         {
             eprintln!(
                 "enable_callback: source_id {} is_enabled {}, level {}, any {:#x} all {:#x} filter? {:?}",
-                _source_id, is_enabled_code, level, _match_any_keyword, _match_all_keyword,
-                !_filter_data.is_null()
+                source_id, is_enabled_code, level, match_any_keyword, match_all_keyword,
+                !filter_data.is_null()
             );
         }
 
-        match is_enabled_code {
-            EVENT_CONTROL_CODE_ENABLE_PROVIDER => {
-                #[cfg(feature = "dev")]
-                {
-                    eprintln!("ETW is ENABLING this provider.  setting level: {}", level);
-                }
-                stable_data.max_level.store(level, SeqCst);
-            }
-            EVENT_CONTROL_CODE_DISABLE_PROVIDER => {
-                #[cfg(feature = "dev")]
-                {
-                    eprintln!("ETW is DISABLING this provider.  setting level: {}", level);
-                }
-                stable_data.max_level.store(level, SeqCst);
-            }
-            EVENT_CONTROL_CODE_CAPTURE_STATE => {
-                // ETW is requesting that the provider log its state information. The meaning of this
-                // is provider-dependent. Currently, this functionality is not exposed to Rust apps.
-                #[cfg(feature = "dev")]
-                {
-                    eprintln!("EVENT_CONTROL_CODE_CAPTURE_STATE");
-                }
-            }
-            _ => {
-                // The control code is unrecognized.
-                #[cfg(feature = "dev")]
-                {
-                    eprintln!(
-                        "enable_callback: control code {} is not recognized",
-                        is_enabled_code
-                    );
-                }
-            }
-        }
+        // ETW passes at most one filter descriptor to the enable callback. When several sessions
+        // attach schematized filters, its data is a chain of filters, which `EventFilters` parses
+        // in full. A malformed filter is ignored, and its type is recorded in
+        // `EventFilters::invalid`. The filters belong to the session that sent this request, and
+        // ETW applies them to that session itself.
+        let filters = if filter_data.is_null() || (*filter_data).Ptr == 0 {
+            EventFilters::default()
+        } else {
+            let filter = &*filter_data;
+            let data = core::slice::from_raw_parts(filter.Ptr as *const u8, filter.Size as usize);
+            EventFilters::from_descriptors([(filter.Type, data)])
+        };
+
+        stable_data.enable_callback(
+            is_enabled_code,
+            &source_id,
+            level,
+            match_any_keyword,
+            match_all_keyword,
+            filters,
+        );
     }
 
     pub fn new_activity_id() -> Result<GUID, Error> {
@@ -331,9 +458,7 @@ impl EtwProvider {
         #[cfg(target_os = "windows")]
         {
             unsafe {
-                let mut stable = Box::pin(StableProviderData {
-                    max_level: AtomicU8::new(0),
-                });
+                let mut stable = Box::pin(StableProviderData::new());
                 let mut handle: REGHANDLE = 0;
                 let stable_ptr: &mut StableProviderData = &mut stable;
                 let error = EventRegister(
@@ -351,10 +476,47 @@ impl EtwProvider {
         }
         #[cfg(not(target_os = "windows"))]
         {
-            Ok(EtwProvider {})
+            let _ = provider_id;
+            Ok(EtwProvider {
+                stable: Box::pin(StableProviderData::new()),
+            })
         }
     }
 
+    /// Sets the function that is called when an ETW controller asks this provider to capture its
+    /// state (`EVENT_CONTROL_CODE_CAPTURE_STATE`). For example, the function could write events
+    /// that describe the connections that the application currently has open. This replaces any
+    /// previous function.
+    ///
+    /// The function is called on a thread that is owned by ETW.
+    pub fn on_capture_state<F>(&self, callback: F)
+    where
+        F: Fn(&crate::CaptureStateRequest) + Send + Sync + 'static,
+    {
+        self.stable
+            .set_capture_state_callback(Some(CaptureStateCallback::new(callback)));
+    }
+
+    /// Sets the function that is called when an ETW session enables or disables this provider,
+    /// or changes the level or keywords that it has enabled. This replaces any previous function.
+    ///
+    /// This can be used to start expensive instrumentation only while a session is listening.
+    /// The function is only called for changes that happen after it is set; use `enable_state`
+    /// to read the current state. The function is called on a thread that is owned by ETW.
+    pub fn on_enable_state_change<F>(&self, callback: F)
+    where
+        F: Fn(&crate::EnableState) + Send + Sync + 'static,
+    {
+        self.stable
+            .set_enable_state_callback(Some(EnableStateCallback::new(callback)));
+    }
+
+    /// Returns a snapshot of the level, keywords and filters that ETW has enabled for this
+    /// provider. These are the values that `is_enabled` and `is_event_enabled` use.
+    pub fn enable_state(&self) -> crate::EnableState {
+        self.stable.enable_state()
+    }
+
     /// See TraceLoggingRegisterEx in traceloggingprovider.h.
     /// This registers provider metadata.
     pub fn register_provider_metadata(&mut self, provider_metadata: &[u8]) -> Result<(), Error> {
@@ -434,6 +596,7 @@ unsafe impl Sync for EtwProvider {}
 /// Describes parameters for an event. This is an implementation detail, and should not be directly
 /// used by applications.
 #[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[allow(missing_docs)]
 pub struct EventDescriptor {
     pub id: u16,
@@ -445,11 +608,31 @@ pub struct EventDescriptor {
     pub keyword: u64,
 }
 
+#[cfg(test)]
+impl EventDescriptor {
+    /// A descriptor with a distinct, non-zero value in every field, for tests.
+    pub(crate) fn for_tests() -> Self {
+        Self {
+            id: 3,
+            version: 1,
+            channel: 11,
+            level: Level::INFO,
+            opcode: 2,
+            task: 7,
+            keyword: 0x8000_0000_0000_0010,
+        }
+    }
+}
+
 /// Allows an application to enter a nested activity scope. This creates a new activity ID,
 /// sets this activity ID as the current activity ID of the current thread, and then runs the
 /// provided function. After the function finishes, it restores the activity ID of the calling
 /// thread (even if a panic occurs).
 ///
+/// On platforms other than Windows, the activity ID becomes the scoped activity ID of the current
+/// thread (see `with_activity_id`), which generated event methods use. This requires the `std`
+/// feature. Without it, this function only runs `f`.
+///
 /// See `[EventActivityIdControl](https://docs.microsoft.com/en-us/windows/win32/api/evntprov/nf-evntprov-eventactivityidcontrol)`.
 #[inline(always)]
 pub fn with_activity<F: FnOnce() -> R, R>(f: F) -> R {
@@ -480,28 +663,31 @@ pub fn with_activity<F: FnOnce() -> R, R>(f: F) -> R {
         result
     }
 
-    #[cfg(not(target_os = "windows"))]
+    #[cfg(all(not(target_os = "windows"), feature = "std"))]
+    {
+        crate::activity::with_activity_id(&crate::activity_id::new_activity_id(), f)
+    }
+
+    #[cfg(all(not(target_os = "windows"), not(feature = "std")))]
     {
         f()
     }
 }
 
+#[cfg(target_os = "windows")]
 struct RestoreActivityHolder {
     previous_activity_id: Option<GUID>,
 }
 
+#[cfg(target_os = "windows")]
 impl Drop for RestoreActivityHolder {
     fn drop(&mut self) {
-        #[cfg(target_os = "windows")]
-        {
-            unsafe {
-                if let Some(previous_activity_id) = self.previous_activity_id.as_ref() {
-                    EventActivityIdControl(
-                        EVENT_ACTIVITY_CTRL_SET_ID,
-                        previous_activity_id as *const GUID as *const windows_sys::core::GUID
-                            as *mut _,
-                    );
-                }
+        unsafe {
+            if let Some(previous_activity_id) = self.previous_activity_id.as_ref() {
+                EventActivityIdControl(
+                    EVENT_ACTIVITY_CTRL_SET_ID,
+                    previous_activity_id as *const GUID as *const windows_sys::core::GUID as *mut _,
+                );
             }
         }
     }
@@ -0,0 +1,231 @@
+//! An in-memory `Provider`, which is useful for testing code that reports events.
+
+use crate::provider::{EventDescriptor, Provider};
+use crate::{
+    CaptureStateCallback, CaptureStateRequest, EnableState, EnableStateCallback,
+    EventDataDescriptor, EventOptions,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::vec::Vec;
+
+/// A copy of a single event that was written to a `RecordingProvider`.
+#[derive(Clone, Debug)]
+pub struct RecordedEvent {
+    /// The event descriptor that was passed to `Provider::write`.
+    pub descriptor: EventDescriptor,
+
+    /// The options that were passed to `Provider::write`, if any.
+    pub options: Option<EventOptions>,
+
+    /// A copy of the provider metadata, if the event contained it. For events written by
+    /// `win_etw_macros` providers, this is always present.
+    pub provider_metadata: Option<Vec<u8>>,
+
+    /// A copy of the event metadata, if the event contained it. For events written by
+    /// `win_etw_macros` providers, this is always present.
+    pub event_metadata: Option<Vec<u8>>,
+
+    /// A copy of the payload of each data descriptor, excluding the provider and event metadata
+    /// descriptors. The descriptors are in the order in which they were passed to
+    /// `Provider::write`.
+    pub data: Vec<Vec<u8>>,
+}
+
+impl RecordedEvent {
+    /// Returns the payload of all data descriptors, concatenated. This is the same byte sequence
+    /// that ETW would store for the event.
+    pub fn payload(&self) -> Vec<u8> {
+        self.data.concat()
+    }
+}
+
+/// Implements `Provider` by storing a copy of every event in memory.
+///
+/// This provider works on all platforms. It is intended for unit tests that need to verify
+/// that events are reported, and that their fields have the expected values. The provider is
+/// enabled when it is created; see `set_enabled`.
+///
+/// ```
+/// use std::sync::Arc;
+/// use win_etw_provider::{Provider, RecordingProvider};
+///
+/// let recorder = Arc::new(RecordingProvider::new());
+/// // Pass `recorder.clone()` to a provider, e.g. `MyEvents::with_provider(recorder.clone())`.
+/// assert!(recorder.events().is_empty());
+/// ```
+pub struct RecordingProvider {
+    enabled: AtomicBool,
+    events: Mutex<Vec<RecordedEvent>>,
+    capture_state: Mutex<Option<CaptureStateCallback>>,
+    enable_state_changed: Mutex<Option<EnableStateCallback>>,
+}
+
+impl Default for RecordingProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecordingProvider {
+    /// Creates a new, empty `RecordingProvider`. The provider is initially enabled.
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+            events: Mutex::new(Vec::new()),
+            capture_state: Mutex::new(None),
+            enable_state_changed: Mutex::new(None),
+        }
+    }
+
+    /// Controls the value returned by `is_enabled` and `is_event_enabled`.
+    ///
+    /// Events that are passed to `write` are recorded regardless of this setting. However, the
+    /// event methods of generated providers check `is_enabled` before they encode an event, so
+    /// they do not call `write` at all while the provider is disabled.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Returns a copy of all events that have been recorded.
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        self.lock().clone()
+    }
+
+    /// Removes and returns all events that have been recorded.
+    pub fn take_events(&self) -> Vec<RecordedEvent> {
+        core::mem::take(&mut *self.lock())
+    }
+
+    /// Simulates a request from an ETW controller to capture state, by calling the function that
+    /// was passed to `set_capture_state_callback`, if any.
+    pub fn request_capture_state(&self, request: &CaptureStateRequest) {
+        let callback = self
+            .capture_state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let Some(callback) = callback {
+            callback.call(request);
+        }
+    }
+
+    /// Simulates an ETW session enabling or disabling the provider. This sets the value returned
+    /// by `is_enabled` and `is_event_enabled` to `state.enabled`, and then calls the function that
+    /// was passed to `set_enable_state_callback`, if any.
+    pub fn change_enable_state(&self, state: &EnableState) {
+        self.set_enabled(state.enabled);
+        let callback = self
+            .enable_state_changed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let Some(callback) = callback {
+            callback.call(state);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<RecordedEvent>> {
+        // A panic in one test thread should not hide events from other threads.
+        self.events.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Provider for RecordingProvider {
+    fn write(
+        &self,
+        options: Option<&EventOptions>,
+        descriptor: &EventDescriptor,
+        data: &[EventDataDescriptor<'_>],
+    ) {
+        let mut event = RecordedEvent {
+            descriptor: *descriptor,
+            options: options.cloned(),
+            provider_metadata: None,
+            event_metadata: None,
+            data: Vec::with_capacity(data.len()),
+        };
+        for d in data.iter() {
+            if d.is_provider_metadata() {
+                event.provider_metadata = Some(d.as_bytes().to_vec());
+            } else if d.is_event_metadata() {
+                event.event_metadata = Some(d.as_bytes().to_vec());
+            } else {
+                event.data.push(d.as_bytes().to_vec());
+            }
+        }
+        self.lock().push(event);
+    }
+
+    fn is_enabled(&self, _level: u8, _keyword: u64) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    fn is_event_enabled(&self, _event_descriptor: &EventDescriptor) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    fn set_capture_state_callback(&self, callback: Option<CaptureStateCallback>) {
+        *self.capture_state.lock().unwrap_or_else(|e| e.into_inner()) = callback;
+    }
+
+    fn set_enable_state_callback(&self, callback: Option<EnableStateCallback>) {
+        *self
+            .enable_state_changed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = callback;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Level;
+
+    #[test]
+    fn records_events() {
+        let recorder = RecordingProvider::new();
+        let descriptor = EventDescriptor {
+            id: 7,
+            version: 0,
+            channel: 11,
+            level: Level::INFO,
+            opcode: 0,
+            task: 0,
+            keyword: 0,
+        };
+        let value: u32 = 0x1234_5678;
+        let options = EventOptions {
+            level: Some(Level::WARN),
+            ..Default::default()
+        };
+        recorder.write(
+            Some(&options),
+            &descriptor,
+            &[
+                EventDataDescriptor::for_provider_metadata(b"prov"),
+                EventDataDescriptor::for_event_metadata(b"meta"),
+                EventDataDescriptor::from(&value),
+                EventDataDescriptor::empty(),
+            ],
+        );
+
+        let events = recorder.take_events();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.descriptor, descriptor);
+        assert_eq!(event.options.as_ref().unwrap().level, Some(Level::WARN));
+        assert_eq!(event.provider_metadata.as_deref(), Some(&b"prov"[..]));
+        assert_eq!(event.event_metadata.as_deref(), Some(&b"meta"[..]));
+        assert_eq!(event.data, vec![value.to_le_bytes().to_vec(), vec![]]);
+        assert!(recorder.events().is_empty());
+    }
+
+    #[test]
+    fn enabled() {
+        let recorder = RecordingProvider::new();
+        assert!(recorder.is_enabled(5, 0));
+        recorder.set_enabled(false);
+        assert!(!recorder.is_enabled(5, 0));
+    }
+}
@@ -0,0 +1,177 @@
+//! Minimal synchronization primitives. This crate supports `no_std`, so it cannot use
+//! `std::sync::Mutex`.
+//!
+//! The spin lock is only held for short, non-blocking operations, such as replacing a callback or
+//! copying a small amount of state. It must never be held while calling application code.
+//!
+//! `Snapshot` publishes an immutable value that can be read without taking a lock, for state that
+//! is read on the path that writes events.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+pub(crate) struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: Access to `value` is serialized by `locked`.
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> SpinLockGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        SpinLockGuard { lock: self }
+    }
+}
+
+pub(crate) struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: The guard proves that this thread holds the lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard proves that this thread holds the lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Holds an immutable value, which is replaced as a whole by `publish`.
+///
+/// Readers never wait: `read` only counts the active readers while it uses the value. A value
+/// that has been replaced is freed by a later call to `publish` that sees no active readers, or
+/// when the `Snapshot` is dropped. Since values are only replaced when an ETW session changes, few
+/// of them are ever waiting to be freed.
+pub(crate) struct Snapshot<T> {
+    current: AtomicPtr<T>,
+    readers: AtomicUsize,
+    retired: SpinLock<Vec<Box<T>>>,
+}
+
+// SAFETY: The value is shared between threads by `read`, and moved between threads by `publish`.
+unsafe impl<T: Send + Sync> Send for Snapshot<T> {}
+unsafe impl<T: Send + Sync> Sync for Snapshot<T> {}
+
+impl<T> Snapshot<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            readers: AtomicUsize::new(0),
+            retired: SpinLock::new(Vec::new()),
+        }
+    }
+
+    /// Calls `f` with the current value.
+    #[inline(always)]
+    pub(crate) fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        // The reader must be counted before it loads the pointer; see `publish`.
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let _guard = ReadGuard(&self.readers);
+        // SAFETY: `current` always points to a valid value. `publish` does not free a value while
+        // any reader is counted, and this reader is counted until `_guard` is dropped.
+        f(unsafe { NonNull::new_unchecked(self.current.load(Ordering::SeqCst)).as_ref() })
+    }
+
+    /// Replaces the current value. Readers that are using the previous value can continue to use
+    /// it.
+    pub(crate) fn publish(&self, value: T) {
+        let new = Box::into_raw(Box::new(value));
+        let freed = {
+            let mut retired = self.retired.lock();
+            let previous = self.current.swap(new, Ordering::SeqCst);
+            // SAFETY: `previous` was created by `Box::into_raw`, and is no longer reachable
+            // through `current`.
+            retired.push(unsafe { Box::from_raw(previous) });
+            // A reader that loaded one of the retired values was counted before the value was
+            // swapped out, and is still counted. A reader that is counted after this point loads
+            // the new value.
+            if self.readers.load(Ordering::SeqCst) == 0 {
+                core::mem::take(&mut *retired)
+            } else {
+                Vec::new()
+            }
+        };
+        // Drop the values after releasing the lock.
+        drop(freed);
+    }
+}
+
+impl<T> Drop for Snapshot<T> {
+    fn drop(&mut self) {
+        // SAFETY: `current` was created by `Box::into_raw`, and `&mut self` proves that there are
+        // no readers.
+        drop(unsafe { Box::from_raw(*self.current.get_mut()) });
+    }
+}
+
+struct ReadGuard<'a>(&'a AtomicUsize);
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn snapshot() {
+        let snapshot = Arc::new(Snapshot::new(vec![0u32; 4]));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let snapshot = snapshot.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        // Every published value has identical elements.
+                        snapshot.read(|v| assert!(v.iter().all(|&x| x == v[0])));
+                    }
+                })
+            })
+            .collect();
+        for i in 1..1_000 {
+            snapshot.publish(vec![i; 4]);
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(snapshot.read(|v| v[0]), 999);
+        assert_eq!(snapshot.readers.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,596 @@
+//! A `Provider` that writes events to a portable trace file, and a reader for those files.
+//!
+//! The file begins with an 8-byte signature (`FILE_SIGNATURE`) and a `u32` format version,
+//! followed by a sequence of records. All integers are little-endian. Each record contains:
+//!
+//! * `u32` record size, in bytes, including this field
+//! * `u64` timestamp, in nanoseconds since the Unix epoch
+//! * `u32` process ID and `u64` thread ID
+//! * the `EventDescriptor`: `u16` id, `u8` version, `u8` channel, `u8` level, `u8` opcode,
+//!   `u16` task, `u64` keyword
+//! * `u8` flags (bit 0: activity ID is present, bit 1: related activity ID is present), followed
+//!   by the activity ID and related activity ID (16 bytes each, always written)
+//! * the provider metadata and the event metadata, each as a `u32` length followed by the bytes
+//! * a `u32` count of data descriptors, followed by each descriptor as a `u32` length and the
+//!   bytes
+//!
+//! Because each record contains the TraceLogging provider and event metadata, the file can be
+//! decoded without access to the provider definitions, e.g. by using `win_etw_metadata::decode`.
+
+use crate::provider::{EventDescriptor, Provider};
+use crate::{EventDataDescriptor, EventOptions, Level, RecordedEvent, GUID};
+use core::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+/// The signature at the start of every trace file.
+pub const FILE_SIGNATURE: [u8; 8] = *b"RustETW\0";
+
+/// The version of the trace file format that `FileProvider` writes.
+pub const FILE_VERSION: u32 = 1;
+
+const FLAG_ACTIVITY_ID: u8 = 1;
+const FLAG_RELATED_ACTIVITY_ID: u8 = 2;
+
+/// Implements `Provider` by appending every event to a trace file.
+///
+/// This provider works on all platforms, and does not require an ETW session. Use `FileReader`
+/// to read the file.
+///
+/// ```ignore
+/// let my_app_events = MyAppEvents::with_provider(FileProvider::create("events.trace")?);
+/// ```
+///
+/// If the writer fails, then the file may end with part of a record, and any record written after
+/// it would be misread. So the provider keeps the error for `last_error`, and writes no more
+/// events. The records before the failed one can still be read.
+pub struct FileProvider<W: Write = File> {
+    state: Mutex<WriterState<W>>,
+}
+
+struct WriterState<W> {
+    writer: W,
+    last_error: Option<io::ErrorKind>,
+    /// Set when a record could not be written. Nothing is written after that.
+    failed: bool,
+}
+
+impl FileProvider<File> {
+    /// Creates a trace file at `path`, replacing any existing file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+}
+
+impl<W: Write> FileProvider<W> {
+    /// Creates a provider that writes a trace file to `writer`. The file header is written
+    /// immediately.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&FILE_SIGNATURE)?;
+        writer.write_all(&FILE_VERSION.to_le_bytes())?;
+        Ok(Self {
+            state: Mutex::new(WriterState {
+                writer,
+                last_error: None,
+                failed: false,
+            }),
+        })
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&self) -> io::Result<()> {
+        self.lock().writer.flush()
+    }
+
+    /// Returns the most recent error, if any. This is set if an event was too large to be
+    /// written, or if the underlying writer failed; in the second case, no more events are
+    /// written.
+    pub fn last_error(&self) -> Option<io::ErrorKind> {
+        self.lock().last_error
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.state
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .writer
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WriterState<W>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write> Provider for FileProvider<W> {
+    fn write(
+        &self,
+        options: Option<&EventOptions>,
+        descriptor: &EventDescriptor,
+        data: &[EventDataDescriptor<'_>],
+    ) {
+        // A record whose size does not fit in its `u32` size prefix would corrupt the file, so it
+        // is dropped.
+        let record = encode_record(options, descriptor, data);
+        let mut state = self.lock();
+        if state.failed {
+            return;
+        }
+        match record {
+            Some(record) => {
+                if let Err(e) = state.writer.write_all(&record) {
+                    state.last_error = Some(e.kind());
+                    state.failed = true;
+                }
+            }
+            None => state.last_error = Some(io::ErrorKind::InvalidInput),
+        }
+    }
+
+    fn is_enabled(&self, _level: u8, _keyword: u64) -> bool {
+        true
+    }
+
+    fn is_event_enabled(&self, _event_descriptor: &EventDescriptor) -> bool {
+        true
+    }
+}
+
+/// Encodes one record of a trace file. Returns `None` if the record, or any of its fields, is too
+/// large to be described by a `u32` size.
+fn encode_record(
+    options: Option<&EventOptions>,
+    descriptor: &EventDescriptor,
+    data: &[EventDataDescriptor<'_>],
+) -> Option<Vec<u8>> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+
+    let mut record: Vec<u8> = Vec::with_capacity(256);
+    record.extend_from_slice(&0u32.to_le_bytes()); // reserve space for the record size
+    record.extend_from_slice(&timestamp.to_le_bytes());
+    record.extend_from_slice(&std::process::id().to_le_bytes());
+    record.extend_from_slice(&current_thread_id().to_le_bytes());
+
+    record.extend_from_slice(&descriptor.id.to_le_bytes());
+    record.push(descriptor.version);
+    record.push(descriptor.channel);
+    record.push(descriptor.level.0);
+    record.push(descriptor.opcode);
+    record.extend_from_slice(&descriptor.task.to_le_bytes());
+    record.extend_from_slice(&descriptor.keyword.to_le_bytes());
+
+    let activity_id = options.and_then(|o| o.activity_id.as_ref());
+    let related_activity_id = options.and_then(|o| o.related_activity_id.as_ref());
+    let mut flags = 0;
+    if activity_id.is_some() {
+        flags |= FLAG_ACTIVITY_ID;
+    }
+    if related_activity_id.is_some() {
+        flags |= FLAG_RELATED_ACTIVITY_ID;
+    }
+    record.push(flags);
+    let zero = GUID::default();
+    put_guid(&mut record, activity_id.unwrap_or(&zero));
+    put_guid(&mut record, related_activity_id.unwrap_or(&zero));
+
+    let mut provider_metadata: &[u8] = &[];
+    let mut event_metadata: &[u8] = &[];
+    let mut fields: Vec<&[u8]> = Vec::with_capacity(data.len());
+    for d in data.iter() {
+        if d.is_provider_metadata() {
+            provider_metadata = d.as_bytes();
+        } else if d.is_event_metadata() {
+            event_metadata = d.as_bytes();
+        } else {
+            fields.push(d.as_bytes());
+        }
+    }
+    put_bytes(&mut record, provider_metadata)?;
+    put_bytes(&mut record, event_metadata)?;
+    record.extend_from_slice(&u32::try_from(fields.len()).ok()?.to_le_bytes());
+    for field in fields.iter() {
+        put_bytes(&mut record, field)?;
+    }
+
+    let record_len = u32::try_from(record.len()).ok()?;
+    record[..4].copy_from_slice(&record_len.to_le_bytes());
+    Some(record)
+}
+
+fn put_guid(record: &mut Vec<u8>, guid: &GUID) {
+    record.extend_from_slice(&guid.data1.to_le_bytes());
+    record.extend_from_slice(&guid.data2.to_le_bytes());
+    record.extend_from_slice(&guid.data3.to_le_bytes());
+    record.extend_from_slice(&guid.data4);
+}
+
+fn put_bytes(record: &mut Vec<u8>, bytes: &[u8]) -> Option<()> {
+    record.extend_from_slice(&u32::try_from(bytes.len()).ok()?.to_le_bytes());
+    record.extend_from_slice(bytes);
+    Some(())
+}
+
+/// Returns the operating system's identifier for the current thread.
+fn current_thread_id() -> u64 {
+    #[cfg(target_os = "windows")]
+    {
+        // SAFETY: GetCurrentThreadId has no preconditions.
+        unsafe { windows_sys::Win32::System::Threading::GetCurrentThreadId() as u64 }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // This uses the system call, because the `gettid` wrapper requires glibc 2.30 or later.
+        // SAFETY: gettid has no preconditions.
+        unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+    }
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+    {
+        0
+    }
+}
+
+/// One event that was read from a trace file.
+#[derive(Clone, Debug)]
+pub struct FileRecord {
+    /// The time at which the event was written.
+    pub timestamp: SystemTime,
+    /// The ID of the process that wrote the event.
+    pub process_id: u32,
+    /// The operating system's ID of the thread that wrote the event. This is 0 on platforms where
+    /// the thread ID is not available.
+    pub thread_id: u64,
+    /// The event. `options` contains the activity IDs of the event, if any.
+    pub event: RecordedEvent,
+}
+
+/// Reads the records of a trace file that was written by `FileProvider`.
+///
+/// ```no_run
+/// use win_etw_provider::FileReader;
+/// use win_etw_metadata::decode::decode_event;
+///
+/// for record in FileReader::open("events.trace").unwrap() {
+///     let record = record.unwrap();
+///     let metadata = record.event.event_metadata.as_ref().unwrap();
+///     println!("{:?}", decode_event(metadata, &record.event.payload()));
+/// }
+/// ```
+pub struct FileReader<R: Read = io::BufReader<File>> {
+    reader: R,
+}
+
+impl FileReader<io::BufReader<File>> {
+    /// Opens the trace file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(io::BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> FileReader<R> {
+    /// Reads and validates the file header from `reader`.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if header[..8] != FILE_SIGNATURE {
+            return Err(invalid_data("not a trace file"));
+        }
+        if u32::from_le_bytes(header[8..12].try_into().unwrap()) != FILE_VERSION {
+            return Err(invalid_data("unsupported trace file version"));
+        }
+        Ok(Self { reader })
+    }
+
+    /// Reads the next record. Returns `Ok(None)` at the end of the file.
+    pub fn read_record(&mut self) -> io::Result<Option<FileRecord>> {
+        let mut size_bytes = [0u8; 4];
+        let mut n = 0;
+        while n < size_bytes.len() {
+            match self.reader.read(&mut size_bytes[n..]) {
+                Ok(0) if n == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => n += len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let size = u32::from_le_bytes(size_bytes) as usize;
+        if size < 4 {
+            return Err(invalid_data("invalid record size"));
+        }
+        // The size has not been validated, so the buffer grows as data is read, rather than being
+        // allocated up front.
+        let mut record = Vec::new();
+        (&mut self.reader)
+            .take(size as u64 - 4)
+            .read_to_end(&mut record)?;
+        if record.len() != size - 4 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        parse_record(&record)
+            .ok_or_else(|| invalid_data("invalid record"))
+            .map(Some)
+    }
+}
+
+impl<R: Read> Iterator for FileReader<R> {
+    type Item = io::Result<FileRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct RecordReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> RecordReader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Some(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.bytes(N)?.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.array()?))
+    }
+
+    fn guid(&mut self) -> Option<GUID> {
+        Some(GUID {
+            data1: self.u32()?,
+            data2: self.u16()?,
+            data3: self.u16()?,
+            data4: self.array()?,
+        })
+    }
+
+    fn counted(&mut self) -> Option<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Some(self.bytes(len)?.to_vec())
+    }
+}
+
+fn parse_record(data: &[u8]) -> Option<FileRecord> {
+    let mut r = RecordReader { data };
+    let timestamp = UNIX_EPOCH + Duration::from_nanos(r.u64()?);
+    let process_id = r.u32()?;
+    let thread_id = r.u64()?;
+    let descriptor = EventDescriptor {
+        id: r.u16()?,
+        version: r.u8()?,
+        channel: r.u8()?,
+        level: Level(r.u8()?),
+        opcode: r.u8()?,
+        task: r.u16()?,
+        keyword: r.u64()?,
+    };
+    let flags = r.u8()?;
+    let activity_id = r.guid()?;
+    let related_activity_id = r.guid()?;
+    let options = if flags & (FLAG_ACTIVITY_ID | FLAG_RELATED_ACTIVITY_ID) != 0 {
+        Some(EventOptions {
+            level: None,
+            activity_id: Some(activity_id).filter(|_| flags & FLAG_ACTIVITY_ID != 0),
+            related_activity_id: Some(related_activity_id)
+                .filter(|_| flags & FLAG_RELATED_ACTIVITY_ID != 0),
+        })
+    } else {
+        None
+    };
+    let provider_metadata = r.counted()?;
+    let event_metadata = r.counted()?;
+    let count = r.u32()? as usize;
+    let mut fields = Vec::with_capacity(count.min(r.data.len()));
+    for _ in 0..count {
+        fields.push(r.counted()?);
+    }
+    if !r.data.is_empty() {
+        return None;
+    }
+    Some(FileRecord {
+        timestamp,
+        process_id,
+        thread_id,
+        event: RecordedEvent {
+            descriptor,
+            options,
+            provider_metadata: Some(provider_metadata).filter(|m| !m.is_empty()),
+            event_metadata: Some(event_metadata).filter(|m| !m.is_empty()),
+            data: fields,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let provider = FileProvider::new(Vec::new()).unwrap();
+        let value = 42u32;
+        let options = EventOptions {
+            activity_id: Some(GUID {
+                data1: 0x1234_5678,
+                data2: 1,
+                data3: 2,
+                data4: [3; 8],
+            }),
+            ..Default::default()
+        };
+        provider.write(
+            Some(&options),
+            &EventDescriptor::for_tests(),
+            &[
+                EventDataDescriptor::for_provider_metadata(b"prov"),
+                EventDataDescriptor::for_event_metadata(b"meta"),
+                EventDataDescriptor::from(&value),
+                EventDataDescriptor::from("hello"),
+            ],
+        );
+        provider.write(None, &EventDescriptor::for_tests(), &[]);
+
+        let before = SystemTime::now();
+        let file = provider.into_inner();
+        let records = FileReader::new(&file[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+
+        let record = &records[0];
+        assert!(record.timestamp <= before);
+        assert_eq!(record.process_id, std::process::id());
+        assert_eq!(record.event.descriptor, EventDescriptor::for_tests());
+        let options = record.event.options.as_ref().unwrap();
+        assert_eq!(options.activity_id.as_ref().unwrap().data1, 0x1234_5678);
+        assert!(options.related_activity_id.is_none());
+        assert_eq!(
+            record.event.provider_metadata.as_deref(),
+            Some(&b"prov"[..])
+        );
+        assert_eq!(record.event.event_metadata.as_deref(), Some(&b"meta"[..]));
+        assert_eq!(
+            record.event.data,
+            vec![value.to_le_bytes().to_vec(), b"hello".to_vec()]
+        );
+
+        let record = &records[1];
+        assert!(record.event.options.is_none());
+        assert!(record.event.provider_metadata.is_none());
+        assert!(record.event.data.is_empty());
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert_eq!(
+            FileReader::new(&b"NotTrace\x01\x00\x00\x00"[..])
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            FileReader::new(&b"RustETW\0\x02\x00\x00\x00"[..])
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let provider = FileProvider::new(Vec::new()).unwrap();
+        provider.write(None, &EventDescriptor::for_tests(), &[]);
+        let file = provider.into_inner();
+
+        // A truncated record is an error.
+        let mut reader = FileReader::new(&file[..file.len() - 1]).unwrap();
+        assert_eq!(
+            reader.read_record().err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        // A record size that is larger than the file does not allocate a buffer of that size.
+        let mut huge = file[..12].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        huge.extend_from_slice(&file[16..]);
+        let mut reader = FileReader::new(&huge[..]).unwrap();
+        assert_eq!(
+            reader.read_record().err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        // A complete file ends cleanly.
+        let mut reader = FileReader::new(&file[..]).unwrap();
+        assert!(reader.read_record().unwrap().is_some());
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    /// Fails once, after a short write that ends at `fail_at` bytes. Later writes succeed.
+    struct FailingWriter {
+        data: Vec<u8>,
+        fail_at: usize,
+        failed: bool,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut n = buf.len();
+            if !self.failed && self.data.len() + n > self.fail_at {
+                n = self.fail_at - self.data.len();
+                if n == 0 {
+                    self.failed = true;
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+            }
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_failure() {
+        let value = 1u32;
+        let data = [EventDataDescriptor::from(&value)];
+        let record_len = encode_record(None, &EventDescriptor::for_tests(), &data)
+            .unwrap()
+            .len();
+
+        // The second record is only partially written.
+        let fail_at = 12 + record_len + 10;
+        let provider = FileProvider::new(FailingWriter {
+            data: Vec::new(),
+            fail_at,
+            failed: false,
+        })
+        .unwrap();
+        provider.write(None, &EventDescriptor::for_tests(), &data);
+        assert_eq!(provider.last_error(), None);
+        provider.write(None, &EventDescriptor::for_tests(), &data);
+        assert_eq!(provider.last_error(), Some(io::ErrorKind::WriteZero));
+        // Nothing is written after the failure, although the writer would accept it.
+        provider.write(None, &EventDescriptor::for_tests(), &data);
+        let file = provider.into_inner().data;
+        assert_eq!(file.len(), fail_at);
+
+        // The records before the failure can be read.
+        let mut reader = FileReader::new(&file[..]).unwrap();
+        assert!(reader.read_record().unwrap().is_some());
+        assert_eq!(
+            reader.read_record().err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
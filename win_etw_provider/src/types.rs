@@ -3,6 +3,7 @@
 //! should use these types.
 #![doc(hidden)]
 
+pub use alloc::vec::Vec;
 pub use widestring::{U16CStr, U16CString};
 
 use crate::EventDataDescriptor;
@@ -82,6 +83,61 @@ impl<'a> From<&'a crate::types::SocketAddrV6> for EventDataDescriptor<'a> {
     }
 }
 
+/// An IPv4 address, in the form that is used with `OutFlag::IPV4`.
+#[repr(C)]
+#[derive(IntoBytes, Immutable, Clone)]
+pub struct Ipv4Addr {
+    /// IPv4 address, stored in big-endian form.
+    pub address: [u8; 4],
+}
+
+impl From<&core::net::Ipv4Addr> for Ipv4Addr {
+    fn from(value: &core::net::Ipv4Addr) -> Self {
+        Self {
+            address: value.octets(),
+        }
+    }
+}
+
+impl<'a> From<&'a crate::types::Ipv4Addr> for EventDataDescriptor<'a> {
+    fn from(value: &'a crate::types::Ipv4Addr) -> EventDataDescriptor<'a> {
+        Self::from(value.as_bytes())
+    }
+}
+
+/// An IPv6 address, in the form that is used with `OutFlag::IPV6`.
+#[repr(C)]
+#[derive(IntoBytes, Immutable, Clone)]
+pub struct Ipv6Addr {
+    /// IPv6 address, stored in big-endian form.
+    pub address: [u8; 16],
+}
+
+impl From<&core::net::Ipv6Addr> for Ipv6Addr {
+    fn from(value: &core::net::Ipv6Addr) -> Self {
+        Self {
+            address: value.octets(),
+        }
+    }
+}
+
+/// IPv4 addresses are converted to IPv4-mapped IPv6 addresses, such as `::ffff:10.0.0.1`, so
+/// that all values of `IpAddr` have the same encoding.
+impl From<&core::net::IpAddr> for Ipv6Addr {
+    fn from(value: &core::net::IpAddr) -> Self {
+        match value {
+            core::net::IpAddr::V4(v4) => Self::from(&v4.to_ipv6_mapped()),
+            core::net::IpAddr::V6(v6) => Self::from(v6),
+        }
+    }
+}
+
+impl<'a> From<&'a crate::types::Ipv6Addr> for EventDataDescriptor<'a> {
+    fn from(value: &'a crate::types::Ipv6Addr) -> EventDataDescriptor<'a> {
+        Self::from(value.as_bytes())
+    }
+}
+
 /// See `[FILETIME](https://docs.microsoft.com/en-us/windows/win32/api/minwinbase/ns-minwinbase-filetime)`.
 #[repr(transparent)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
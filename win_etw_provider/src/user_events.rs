@@ -0,0 +1,1175 @@
+//! A `Provider` that writes events to the Linux
+//! [`user_events`](https://docs.kernel.org/trace/user_events.html) facility, using the
+//! [EventHeader](https://github.com/microsoft/LinuxTracepoints) event format.
+//!
+//! Events written by `win_etw_macros` providers are self-describing TraceLogging events. This
+//! provider converts the TraceLogging metadata and payload of each event into the equivalent
+//! EventHeader metadata and payload, so that tools such as `perf` and `decode-perf` can decode
+//! the event fields. The EventHeader metadata of an event is built the first time that the event
+//! is written. Most payloads are stored in the same way by both formats, and are written as they
+//! are; only events with GUID, time, counted UTF-16 string or SID fields are converted.
+//!
+//! EventHeader tracepoints are named `<provider>_L<level>K<keyword>`, where the level and keyword
+//! are in lowercase hexadecimal. Tracepoints are registered with the kernel the first time that
+//! an event with a given level and keyword is checked or written.
+
+use crate::provider::{EventDescriptor, Provider};
+use crate::{Error, EventDataDescriptor, EventOptions, GUID};
+use core::convert::TryFrom;
+use core::ptr::NonNull;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{IoSlice, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::vec::Vec;
+use win_etw_metadata::decode::{self, DecodedField, EventMetadata, FieldMetadata, Value};
+use win_etw_metadata::{InFlag, OutFlag};
+
+/// Implements `Provider` by writing events to the Linux `user_events` facility.
+///
+/// ```ignore
+/// let provider = MyAppEvents::with_provider(
+///     UserEventsProvider::new(MyAppEvents::PROVIDER_NAME).unwrap(),
+/// );
+/// ```
+///
+/// Use `perf record -e user_events:MyAppEvents_L4K0` (for example) to collect events.
+///
+/// An event is dropped if its tracepoint could not be registered, if its fields cannot be
+/// converted to the EventHeader format, or if the kernel rejects it. `last_error` returns the
+/// `errno` of the most recent such failure.
+pub struct UserEventsProvider {
+    name: String,
+    data_file: Arc<File>,
+    /// The registered tracepoints, by level and keyword. These only change the first time that
+    /// an event with a given level and keyword is written, so events rarely wait for the lock.
+    tracepoints: RwLock<HashMap<(u8, u64), Arc<Tracepoint>>>,
+    /// The EventHeader form of each event's metadata, by the address of its TraceLogging
+    /// metadata.
+    formats: RwLock<HashMap<usize, Arc<EventFormat>>>,
+    last_error: AtomicI32,
+}
+
+impl UserEventsProvider {
+    /// Opens the `user_events_data` file of the system's tracefs file system, and creates a
+    /// provider with the given name. Typically, `name` is the `PROVIDER_NAME` constant of a
+    /// generated provider.
+    pub fn new(name: &str) -> Result<Self, Error> {
+        Self::with_tracefs(find_tracefs(), name)
+    }
+
+    /// Creates a provider that uses the tracefs file system that is mounted at `tracefs`, rather
+    /// than searching for it.
+    pub fn with_tracefs(tracefs: impl AsRef<Path>, name: &str) -> Result<Self, Error> {
+        // The EventHeader specification reserves these characters.
+        if name.is_empty() || name.contains([' ', ':', '\0']) {
+            return Err(Error::LinuxError(EINVAL));
+        }
+        let data_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tracefs.as_ref().join("user_events_data"))
+            .map_err(|e| Error::LinuxError(e.raw_os_error().unwrap_or(EINVAL)))?;
+        Ok(Self {
+            name: name.into(),
+            data_file: Arc::new(data_file),
+            tracepoints: RwLock::new(HashMap::new()),
+            formats: RwLock::new(HashMap::new()),
+            last_error: AtomicI32::new(0),
+        })
+    }
+
+    /// Returns the most recent error, if any. This is set if a tracepoint could not be registered
+    /// with the kernel, or if an event could not be converted or written.
+    pub fn last_error(&self) -> Option<Error> {
+        match self.last_error.load(Ordering::Relaxed) {
+            0 => None,
+            errno => Some(Error::LinuxError(errno)),
+        }
+    }
+
+    #[inline(never)]
+    fn set_error(&self, errno: i32) {
+        #[cfg(feature = "dev")]
+        {
+            eprintln!("UserEventsProvider: error {}", errno);
+        }
+        self.last_error.store(errno, Ordering::Relaxed);
+    }
+
+    /// Returns the write index of the tracepoint for `level` and `keyword`, if the tracepoint is
+    /// enabled. This registers the tracepoint if necessary.
+    #[inline(always)]
+    fn enabled_tracepoint(&self, level: u8, keyword: u64) -> Option<u32> {
+        let key = (level, keyword);
+        let enabled_index = read_lock(&self.tracepoints)
+            .get(&key)
+            .map(|t| t.enabled_index());
+        match enabled_index {
+            Some(index) => index,
+            None => self.register(key).enabled_index(),
+        }
+    }
+
+    #[cold]
+    fn register(&self, key: (u8, u64)) -> Arc<Tracepoint> {
+        let mut tracepoints = write_lock(&self.tracepoints);
+        if let Some(tracepoint) = tracepoints.get(&key) {
+            return tracepoint.clone();
+        }
+        let name = tracepoint_name(&self.name, key.0, key.1);
+        let tracepoint = Tracepoint::register(&self.data_file, &name);
+        if let Err(errno) = tracepoint.registration {
+            self.set_error(errno);
+        }
+        tracepoints.insert(key, tracepoint.clone());
+        tracepoint
+    }
+
+    /// Returns the EventHeader form of the event metadata.
+    ///
+    /// The metadata of generated events is static, so it is cached by address. The cached copy
+    /// of the TraceLogging metadata guards against a different event that uses the same address.
+    fn event_format(&self, metadata: &[u8]) -> Result<Arc<EventFormat>, Unsupported> {
+        let key = metadata.as_ptr() as usize;
+        let cached = read_lock(&self.formats)
+            .get(&key)
+            .filter(|f| f.tracelogging == metadata)
+            .cloned();
+        if let Some(format) = cached {
+            return Ok(format);
+        }
+
+        let format = Arc::new(EventFormat::new(metadata)?);
+        let mut formats = write_lock(&self.formats);
+        if formats.len() < MAX_CACHED_FORMATS {
+            formats.insert(key, format.clone());
+        }
+        Ok(format)
+    }
+}
+
+/// Locks `lock` for reading. The caches are always consistent, so a poisoned lock is ignored.
+fn read_lock<T>(lock: &RwLock<T>) -> std::sync::RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Locks `lock` for writing. The caches are always consistent, so a poisoned lock is ignored.
+fn write_lock<T>(lock: &RwLock<T>) -> std::sync::RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Limits the number of cached event formats, in case an application writes events whose
+/// metadata is not static.
+const MAX_CACHED_FORMATS: usize = 4096;
+
+/// The maximum number of `IoSlice`s that are passed to one `writev` call (`IOV_MAX`).
+const MAX_IO_SLICES: usize = 1024;
+
+impl Provider for UserEventsProvider {
+    fn write(
+        &self,
+        options: Option<&EventOptions>,
+        descriptor: &EventDescriptor,
+        data: &[EventDataDescriptor<'_>],
+    ) {
+        let write_index = match self.enabled_tracepoint(descriptor.level.0, descriptor.keyword) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let format = match data.iter().find(|d| d.is_event_metadata()) {
+            Some(metadata) => self.event_format(metadata.as_bytes()),
+            None => Err(Unsupported),
+        };
+        let format = match format {
+            Ok(format) => format,
+            Err(Unsupported) => return self.set_error(EINVAL),
+        };
+        let payload = data
+            .iter()
+            .filter(|d| !d.is_event_metadata() && !d.is_provider_metadata())
+            .map(|d| d.as_bytes());
+
+        let mut header = Vec::with_capacity(64);
+        header.extend_from_slice(&write_index.to_le_bytes());
+        encode_header(&mut header, options, descriptor, &format.eventheader);
+        let result = if format.passthrough && data.len() < MAX_IO_SLICES {
+            // The TraceLogging payload is also a valid EventHeader payload, so it is written
+            // without copying it.
+            let mut slices = Vec::with_capacity(data.len() + 2);
+            slices.push(IoSlice::new(&header));
+            slices.push(IoSlice::new(&format.eventheader));
+            slices.extend(payload.map(IoSlice::new));
+            write_all_or_nothing(&self.data_file, &slices)
+        } else {
+            let payload = payload.collect::<Vec<_>>().concat();
+            let mut fields = Vec::with_capacity(payload.len());
+            if encode_payload(&mut fields, &format, &payload).is_err() {
+                return self.set_error(EINVAL);
+            }
+            write_all_or_nothing(
+                &self.data_file,
+                &[
+                    IoSlice::new(&header),
+                    IoSlice::new(&format.eventheader),
+                    IoSlice::new(&fields),
+                ],
+            )
+        };
+        if let Err(errno) = result {
+            self.set_error(errno);
+        }
+    }
+
+    fn is_enabled(&self, level: u8, keyword: u64) -> bool {
+        self.enabled_tracepoint(level, keyword).is_some()
+    }
+
+    fn is_event_enabled(&self, event_descriptor: &EventDescriptor) -> bool {
+        self.is_enabled(event_descriptor.level.0, event_descriptor.keyword)
+    }
+}
+
+/// Writes an event with a single `writev` call. The kernel writes either all of the event, or
+/// none of it.
+fn write_all_or_nothing(file: &File, slices: &[IoSlice<'_>]) -> Result<(), i32> {
+    let len: usize = slices.iter().map(|s| s.len()).sum();
+    match (&*file).write_vectored(slices) {
+        Ok(n) if n == len => Ok(()),
+        Ok(_) => Err(EIO),
+        Err(e) => Err(e.raw_os_error().unwrap_or(EIO)),
+    }
+}
+
+/// Searches `/proc/mounts` for the tracefs file system.
+fn find_tracefs() -> PathBuf {
+    if let Ok(mounts) = std::fs::read_to_string("/proc/mounts") {
+        for line in mounts.lines() {
+            let mut fields = line.split(' ');
+            if let (Some(_), Some(path), Some("tracefs")) =
+                (fields.next(), fields.next(), fields.next())
+            {
+                return PathBuf::from(path);
+            }
+        }
+    }
+    PathBuf::from("/sys/kernel/tracing")
+}
+
+fn tracepoint_name(provider_name: &str, level: u8, keyword: u64) -> String {
+    format!("{}_L{:x}K{:x}", provider_name, level, keyword)
+}
+
+const EIO: i32 = 5;
+const EINVAL: i32 = 22;
+
+// The user_events ioctls, from include/uapi/linux/user_events.h. The type of the request
+// argument of `ioctl` depends on the C library (`c_ulong` for glibc, `c_int` for musl), so these
+// are converted when they are passed to `libc::ioctl`.
+const DIAG_IOC_MAGIC: u32 = b'*' as u32;
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+const DIAG_IOCSREG: u32 = ((IOC_READ | IOC_WRITE) << 30)
+    | ((core::mem::size_of::<*const UserReg>() as u32) << 16)
+    | (DIAG_IOC_MAGIC << 8);
+const DIAG_IOCSUNREG: u32 = (IOC_WRITE << 30)
+    | ((core::mem::size_of::<*const UserUnreg>() as u32) << 16)
+    | (DIAG_IOC_MAGIC << 8)
+    | 2;
+
+#[repr(C, packed)]
+struct UserReg {
+    size: u32,
+    enable_bit: u8,
+    enable_size: u8,
+    flags: u16,
+    enable_addr: u64,
+    name_args: u64,
+    write_index: u32,
+}
+
+#[repr(C, packed)]
+struct UserUnreg {
+    size: u32,
+    disable_bit: u8,
+    reserved: u8,
+    reserved2: u16,
+    disable_addr: u64,
+}
+
+/// A registered tracepoint. The kernel sets bit 0 of `enabled` while at least one session is
+/// collecting events from the tracepoint.
+///
+/// The kernel can write to `enabled` at any time after the tracepoint is registered, so it is
+/// allocated separately, and is only ever accessed through a shared reference.
+struct Tracepoint {
+    data_file: Arc<File>,
+    enabled: NonNull<AtomicU32>,
+    /// The write index, or the `errno` if registration failed. The tracepoint is never enabled
+    /// if registration failed.
+    registration: Result<u32, i32>,
+}
+
+// SAFETY: `enabled` is owned by the tracepoint, and is only accessed atomically.
+unsafe impl Send for Tracepoint {}
+unsafe impl Sync for Tracepoint {}
+
+impl Tracepoint {
+    fn register(data_file: &Arc<File>, name: &str) -> Arc<Tracepoint> {
+        let enabled = NonNull::from(Box::leak(Box::new(AtomicU32::new(0))));
+        let name_args = format!(
+            "{} u8 eventheader_flags; u8 version; u16 id; u16 tag; u8 opcode; u8 level\0",
+            name
+        );
+        let mut reg = UserReg {
+            size: core::mem::size_of::<UserReg>() as u32,
+            enable_bit: 0,
+            enable_size: 4,
+            flags: 0,
+            enable_addr: enabled.as_ptr() as usize as u64,
+            name_args: name_args.as_ptr() as usize as u64,
+            write_index: 0,
+        };
+        // SAFETY: `reg` points to valid memory for the duration of the call. The kernel writes
+        // to `enabled` until the tracepoint is unregistered, which `Drop` does before `enabled`
+        // is freed.
+        let result = unsafe {
+            libc::ioctl(
+                data_file.as_raw_fd(),
+                DIAG_IOCSREG as _,
+                &mut reg as *mut UserReg,
+            )
+        };
+        let registration = if result >= 0 {
+            Ok(reg.write_index)
+        } else {
+            Err(std::io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or(EINVAL))
+        };
+        Arc::new(Tracepoint {
+            data_file: data_file.clone(),
+            enabled,
+            registration,
+        })
+    }
+
+    /// Returns the write index, if the tracepoint is enabled.
+    #[inline(always)]
+    fn enabled_index(&self) -> Option<u32> {
+        match self.registration {
+            Ok(index) if self.enabled().load(Ordering::Relaxed) != 0 => Some(index),
+            _ => None,
+        }
+    }
+
+    fn enabled(&self) -> &AtomicU32 {
+        // SAFETY: `enabled` is valid until the tracepoint is dropped.
+        unsafe { self.enabled.as_ref() }
+    }
+}
+
+impl Drop for Tracepoint {
+    fn drop(&mut self) {
+        if self.registration.is_ok() {
+            let mut unreg = UserUnreg {
+                size: core::mem::size_of::<UserUnreg>() as u32,
+                disable_bit: 0,
+                reserved: 0,
+                reserved2: 0,
+                disable_addr: self.enabled.as_ptr() as usize as u64,
+            };
+            // SAFETY: `unreg` points to valid memory for the duration of the call.
+            unsafe {
+                libc::ioctl(
+                    self.data_file.as_raw_fd(),
+                    DIAG_IOCSUNREG as _,
+                    &mut unreg as *mut UserUnreg,
+                );
+            }
+        }
+        // SAFETY: `enabled` was created by `Box::leak`, and the kernel no longer writes to it.
+        drop(unsafe { Box::from_raw(self.enabled.as_ptr()) });
+    }
+}
+
+// EventHeader definitions, from eventheader.h.
+const HEADER_FLAG_POINTER64: u8 = 0x01;
+const HEADER_FLAG_LITTLE_ENDIAN: u8 = 0x02;
+const HEADER_FLAG_EXTENSION: u8 = 0x04;
+
+const EXTENSION_KIND_METADATA: u16 = 1;
+const EXTENSION_KIND_ACTIVITY_ID: u16 = 2;
+const EXTENSION_KIND_CHAIN_FLAG: u16 = 0x8000;
+
+const ENCODING_STRUCT: u8 = 1;
+const ENCODING_VALUE8: u8 = 2;
+const ENCODING_VALUE16: u8 = 3;
+const ENCODING_VALUE32: u8 = 4;
+const ENCODING_VALUE64: u8 = 5;
+const ENCODING_VALUE128: u8 = 6;
+const ENCODING_ZSTRING_CHAR8: u8 = 7;
+const ENCODING_ZSTRING_CHAR16: u8 = 8;
+const ENCODING_STRING_LENGTH16_CHAR8: u8 = 10;
+const ENCODING_STRING_LENGTH16_CHAR16: u8 = 11;
+const ENCODING_CARRAY_FLAG: u8 = 0x20;
+const ENCODING_VARRAY_FLAG: u8 = 0x40;
+const ENCODING_CHAIN_FLAG: u8 = 0x80;
+
+const FORMAT_UNSIGNED_INT: u8 = 1;
+const FORMAT_SIGNED_INT: u8 = 2;
+const FORMAT_HEX_INT: u8 = 3;
+const FORMAT_PID: u8 = 5;
+const FORMAT_TIME: u8 = 6;
+const FORMAT_BOOLEAN: u8 = 7;
+const FORMAT_FLOAT: u8 = 8;
+const FORMAT_HEX_BYTES: u8 = 9;
+const FORMAT_STRING_UTF: u8 = 11;
+const FORMAT_STRING_XML: u8 = 13;
+const FORMAT_STRING_JSON: u8 = 14;
+const FORMAT_UUID: u8 = 15;
+const FORMAT_PORT: u8 = 16;
+const FORMAT_IP_ADDRESS: u8 = 17;
+
+/// The field cannot be represented in the EventHeader format.
+#[derive(Debug)]
+struct Unsupported;
+
+/// Returns the EventHeader encoding (without array flags) and format of a TraceLogging field.
+fn field_encoding(field: &FieldMetadata) -> Result<(u8, u8), Unsupported> {
+    let out = field.out_type;
+    let int_format = |default: u8| match out {
+        OutFlag::BOOLEAN => FORMAT_BOOLEAN,
+        OutFlag::HEX | OutFlag::HRESULT | OutFlag::NTSTATUS | OutFlag::WIN32ERROR => FORMAT_HEX_INT,
+        OutFlag::PID | OutFlag::TID => FORMAT_PID,
+        OutFlag::PORT => FORMAT_PORT,
+        OutFlag::IPV4 => FORMAT_IP_ADDRESS,
+        _ => default,
+    };
+    let string_format = match out {
+        OutFlag::XML => FORMAT_STRING_XML,
+        OutFlag::JSON => FORMAT_STRING_JSON,
+        _ => FORMAT_STRING_UTF,
+    };
+    if field.in_type & InFlag::COUNT_MASK == InFlag::CUSTOM_FLAG {
+        return Ok((ENCODING_STRING_LENGTH16_CHAR8, FORMAT_HEX_BYTES));
+    }
+    Ok(match field.base_type() {
+        // Both formats store the number of fields of a structure in place of the format.
+        InFlag::STRUCT if !field.is_array() => (ENCODING_STRUCT, out.bits()),
+        InFlag::UNICODE_STRING => (ENCODING_ZSTRING_CHAR16, string_format),
+        InFlag::ANSI_STRING => (ENCODING_ZSTRING_CHAR8, string_format),
+        InFlag::COUNTED_UNICODE_STRING => (ENCODING_STRING_LENGTH16_CHAR16, string_format),
+        InFlag::COUNTED_ANSI_STRING => (ENCODING_STRING_LENGTH16_CHAR8, string_format),
+        InFlag::INT8 => (ENCODING_VALUE8, int_format(FORMAT_SIGNED_INT)),
+        InFlag::UINT8 => (ENCODING_VALUE8, int_format(FORMAT_UNSIGNED_INT)),
+        InFlag::INT16 => (ENCODING_VALUE16, int_format(FORMAT_SIGNED_INT)),
+        InFlag::UINT16 => (ENCODING_VALUE16, int_format(FORMAT_UNSIGNED_INT)),
+        InFlag::INT32 => (ENCODING_VALUE32, int_format(FORMAT_SIGNED_INT)),
+        InFlag::UINT32 => (ENCODING_VALUE32, int_format(FORMAT_UNSIGNED_INT)),
+        InFlag::INT64 => (ENCODING_VALUE64, int_format(FORMAT_SIGNED_INT)),
+        InFlag::UINT64 => (ENCODING_VALUE64, int_format(FORMAT_UNSIGNED_INT)),
+        InFlag::HEXINT32 => (ENCODING_VALUE32, FORMAT_HEX_INT),
+        InFlag::HEXINT64 => (ENCODING_VALUE64, FORMAT_HEX_INT),
+        InFlag::FLOAT => (ENCODING_VALUE32, FORMAT_FLOAT),
+        InFlag::DOUBLE => (ENCODING_VALUE64, FORMAT_FLOAT),
+        InFlag::BOOL32 => (ENCODING_VALUE32, FORMAT_BOOLEAN),
+        InFlag::GUID => (ENCODING_VALUE128, FORMAT_UUID),
+        InFlag::FILETIME | InFlag::SYSTEMTIME => (ENCODING_VALUE64, FORMAT_TIME),
+        InFlag::BINARY if out == OutFlag::IPV6 => {
+            (ENCODING_STRING_LENGTH16_CHAR8, FORMAT_IP_ADDRESS)
+        }
+        InFlag::BINARY | InFlag::SID => (ENCODING_STRING_LENGTH16_CHAR8, FORMAT_HEX_BYTES),
+        _ => return Err(Unsupported),
+    })
+}
+
+/// The EventHeader form of the metadata of one event.
+struct EventFormat {
+    /// A copy of the TraceLogging metadata.
+    tracelogging: Vec<u8>,
+    metadata: EventMetadata,
+    /// The EventHeader metadata, which is written in an `EXTENSION_KIND_METADATA` extension.
+    eventheader: Vec<u8>,
+    /// The EventHeader encoding of each field, in metadata order.
+    encodings: Vec<u8>,
+    /// True if the TraceLogging payload of the event is also a valid EventHeader payload, so
+    /// that it does not need to be converted.
+    passthrough: bool,
+}
+
+impl EventFormat {
+    fn new(tracelogging: &[u8]) -> Result<Self, Unsupported> {
+        let metadata = decode::parse_event_metadata(tracelogging).map_err(|_| Unsupported)?;
+        let mut eventheader = Vec::new();
+        eventheader.extend_from_slice(metadata.name.as_bytes());
+        eventheader.push(0);
+        let mut encodings = Vec::with_capacity(metadata.fields.len());
+        let mut passthrough = true;
+        for field in metadata.fields.iter() {
+            let (encoding, format) = field_encoding(field)?;
+            encodings.push(encoding);
+            passthrough &= is_passthrough(field);
+            let count = field.in_type & InFlag::COUNT_MASK;
+            let array_flag = if count == InFlag::VCOUNT_FLAG {
+                ENCODING_VARRAY_FLAG
+            } else if count == InFlag::CCOUNT_FLAG {
+                ENCODING_CARRAY_FLAG
+            } else {
+                0
+            };
+            eventheader.extend_from_slice(field.name.as_bytes());
+            eventheader.push(0);
+            eventheader.push(encoding | array_flag | ENCODING_CHAIN_FLAG);
+            eventheader.push(format);
+            if array_flag == ENCODING_CARRAY_FLAG {
+                eventheader.extend_from_slice(&field.count.unwrap_or(0).to_le_bytes());
+            }
+        }
+        if u16::try_from(eventheader.len()).is_err() {
+            return Err(Unsupported);
+        }
+        Ok(Self {
+            tracelogging: tracelogging.to_vec(),
+            metadata,
+            eventheader,
+            encodings,
+            passthrough,
+        })
+    }
+}
+
+/// Checks whether TraceLogging and EventHeader store a field in the same way.
+fn is_passthrough(field: &FieldMetadata) -> bool {
+    field.in_type & InFlag::COUNT_MASK == InFlag::CUSTOM_FLAG
+        || !matches!(
+            field.base_type(),
+            // GUIDs are stored in a different byte order, times in different units, the length of
+            // a counted UTF-16 string in different units, and a SID without its length.
+            InFlag::GUID
+                | InFlag::FILETIME
+                | InFlag::SYSTEMTIME
+                | InFlag::COUNTED_UNICODE_STRING
+                | InFlag::SID
+        )
+}
+
+/// Appends the EventHeader header and extensions of an event to `out`. The metadata extension
+/// ends with its header, so `eventheader_metadata` must be written after it.
+fn encode_header(
+    out: &mut Vec<u8>,
+    options: Option<&EventOptions>,
+    descriptor: &EventDescriptor,
+    eventheader_metadata: &[u8],
+) {
+    let mut flags = HEADER_FLAG_LITTLE_ENDIAN | HEADER_FLAG_EXTENSION;
+    if cfg!(target_pointer_width = "64") {
+        flags |= HEADER_FLAG_POINTER64;
+    }
+    out.push(flags);
+    out.push(descriptor.version);
+    out.extend_from_slice(&descriptor.id.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes()); // tag
+    out.push(descriptor.opcode);
+    out.push(descriptor.level.0);
+
+    if let Some(activity_id) = options.and_then(|o| o.activity_id.as_ref()) {
+        let related_id = options.and_then(|o| o.related_activity_id.as_ref());
+        let size: u16 = if related_id.is_some() { 32 } else { 16 };
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(
+            &(EXTENSION_KIND_ACTIVITY_ID | EXTENSION_KIND_CHAIN_FLAG).to_le_bytes(),
+        );
+        out.extend_from_slice(&guid_to_uuid_bytes(activity_id));
+        if let Some(related_id) = related_id {
+            out.extend_from_slice(&guid_to_uuid_bytes(related_id));
+        }
+    }
+
+    // `EventFormat::new` checked that the length fits.
+    out.extend_from_slice(&(eventheader_metadata.len() as u16).to_le_bytes());
+    out.extend_from_slice(&EXTENSION_KIND_METADATA.to_le_bytes());
+}
+
+/// Appends the EventHeader payload of an event to `out`, converting its TraceLogging payload.
+fn encode_payload(
+    out: &mut Vec<u8>,
+    format: &EventFormat,
+    tracelogging_payload: &[u8],
+) -> Result<(), Unsupported> {
+    let event =
+        decode::decode_payload(&format.metadata, tracelogging_payload).map_err(|_| Unsupported)?;
+    encode_fields(out, &event.fields, &mut format.encodings.iter().copied(), 0)
+}
+
+/// Appends the payload of `fields`, which are at nesting level `depth`. `encodings` contains the
+/// encoding of each field, in metadata order, so the members of a structure follow the structure
+/// itself.
+fn encode_fields(
+    out: &mut Vec<u8>,
+    fields: &[DecodedField],
+    encodings: &mut impl Iterator<Item = u8>,
+    depth: usize,
+) -> Result<(), Unsupported> {
+    for field in fields.iter() {
+        let encoding = encodings.next().ok_or(Unsupported)?;
+        match &field.value {
+            Value::Struct(members) => {
+                if depth == decode::MAX_STRUCT_DEPTH {
+                    return Err(Unsupported);
+                }
+                encode_fields(out, members, encodings, depth + 1)?
+            }
+            Value::Array(values) => {
+                if field.in_type & InFlag::COUNT_MASK == InFlag::VCOUNT_FLAG {
+                    out.extend_from_slice(&(values.len() as u16).to_le_bytes());
+                }
+                for value in values.iter() {
+                    encode_value(out, encoding, value)?;
+                }
+            }
+            value => encode_value(out, encoding, value)?,
+        }
+    }
+    Ok(())
+}
+
+fn encode_value(out: &mut Vec<u8>, encoding: u8, value: &Value) -> Result<(), Unsupported> {
+    match value {
+        Value::String(s) => match encoding {
+            ENCODING_ZSTRING_CHAR8 => {
+                out.extend_from_slice(s.as_bytes());
+                out.push(0);
+            }
+            ENCODING_ZSTRING_CHAR16 => {
+                for unit in s.encode_utf16() {
+                    out.extend_from_slice(&unit.to_le_bytes());
+                }
+                out.extend_from_slice(&[0, 0]);
+            }
+            ENCODING_STRING_LENGTH16_CHAR8 => encode_counted(out, s.as_bytes())?,
+            ENCODING_STRING_LENGTH16_CHAR16 => {
+                let units: Vec<u16> = s.encode_utf16().collect();
+                let len = u16::try_from(units.len()).map_err(|_| Unsupported)?;
+                out.extend_from_slice(&len.to_le_bytes());
+                for unit in units {
+                    out.extend_from_slice(&unit.to_le_bytes());
+                }
+            }
+            _ => return Err(Unsupported),
+        },
+        Value::I8(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::U8(v) => out.push(*v),
+        Value::I16(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::U16(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::I32(v) | Value::Bool32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::U32(v) | Value::HexInt32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::I64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::U64(v) | Value::HexInt64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::F32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::F64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        Value::Binary(b) | Value::Sid(b) => encode_counted(out, b)?,
+        Value::Guid(b) => {
+            // TraceLogging stores GUIDs in Windows (little-endian) byte order. EventHeader
+            // stores UUIDs in RFC 4122 (big-endian) byte order.
+            out.extend_from_slice(&[b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6]]);
+            out.extend_from_slice(&b[8..]);
+        }
+        Value::FileTime(ft) => out.extend_from_slice(&filetime_to_time64(*ft).to_le_bytes()),
+        Value::SystemTime(st) => out.extend_from_slice(&systemtime_to_time64(st).to_le_bytes()),
+        _ => return Err(Unsupported),
+    }
+    Ok(())
+}
+
+fn encode_counted(out: &mut Vec<u8>, bytes: &[u8]) -> Result<(), Unsupported> {
+    let len = u16::try_from(bytes.len()).map_err(|_| Unsupported)?;
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn guid_to_uuid_bytes(guid: &GUID) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[0..4].copy_from_slice(&guid.data1.to_be_bytes());
+    bytes[4..6].copy_from_slice(&guid.data2.to_be_bytes());
+    bytes[6..8].copy_from_slice(&guid.data3.to_be_bytes());
+    bytes[8..16].copy_from_slice(&guid.data4);
+    bytes
+}
+
+/// The number of seconds between the `FILETIME` epoch (1601) and the Unix epoch (1970).
+const FILETIME_UNIX_EPOCH_SECONDS: i64 = 11_644_473_600;
+
+/// Converts a `FILETIME` (100ns units since 1601) to a `time_t` (seconds since 1970).
+fn filetime_to_time64(ft: u64) -> i64 {
+    (ft / 10_000_000) as i64 - FILETIME_UNIX_EPOCH_SECONDS
+}
+
+/// Converts a `SYSTEMTIME` (year, month, day of week, day, hour, minute, second, milliseconds)
+/// to a `time_t`.
+fn systemtime_to_time64(st: &[u16; 8]) -> i64 {
+    // Days since 1970-01-01, from Howard Hinnant's `days_from_civil` algorithm.
+    let (month, day) = (st[1] as i64, st[3] as i64);
+    let year = st[0] as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    days * 86_400 + st[4] as i64 * 3_600 + st[5] as i64 * 60 + st[6] as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Level;
+
+    /// Builds TraceLogging event metadata from (name, in_type, out_type) tuples.
+    fn tl_metadata_bytes(event_name: &str, fields: &[(&str, InFlag, OutFlag)]) -> Vec<u8> {
+        let mut m = vec![0, 0, 0];
+        m.extend_from_slice(event_name.as_bytes());
+        m.push(0);
+        for (name, in_type, out_type) in fields.iter() {
+            m.extend_from_slice(name.as_bytes());
+            m.push(0);
+            if *out_type == OutFlag::NULL {
+                m.push(in_type.bits());
+            } else {
+                m.push((*in_type | InFlag::CHAIN_FLAG).bits());
+                m.push(out_type.bits());
+            }
+        }
+        m[0] = m.len() as u8;
+        m
+    }
+
+    fn tl_metadata(event_name: &str, fields: &[(&str, InFlag, OutFlag)]) -> EventFormat {
+        EventFormat::new(&tl_metadata_bytes(event_name, fields)).unwrap()
+    }
+
+    /// Encodes a complete event, as `UserEventsProvider::write` does (without the write index).
+    fn encode_event(
+        out: &mut Vec<u8>,
+        options: Option<&EventOptions>,
+        descriptor: &EventDescriptor,
+        format: &EventFormat,
+        payload: &[u8],
+    ) -> Result<(), Unsupported> {
+        encode_header(out, options, descriptor, &format.eventheader);
+        out.extend_from_slice(&format.eventheader);
+        encode_payload(out, format, payload)
+    }
+
+    #[test]
+    fn tracepoint_names() {
+        assert_eq!(tracepoint_name("MyProvider", 5, 0), "MyProvider_L5K0");
+        assert_eq!(
+            tracepoint_name("My.Provider", 4, 0xf00d),
+            "My.Provider_L4Kf00d"
+        );
+    }
+
+    #[test]
+    fn encode_header_and_metadata() {
+        let metadata = tl_metadata(
+            "ev",
+            &[
+                ("a", InFlag::UINT32, OutFlag::HEX),
+                ("s", InFlag::COUNTED_ANSI_STRING, OutFlag::UTF8),
+                ("v", InFlag::UINT16 | InFlag::VCOUNT_FLAG, OutFlag::NULL),
+            ],
+        );
+        let mut payload = vec![];
+        payload.extend_from_slice(&0xcafef00du32.to_le_bytes());
+        payload.extend_from_slice(&2u16.to_le_bytes());
+        payload.extend_from_slice(b"hi");
+        payload.extend_from_slice(&1u16.to_le_bytes());
+        payload.extend_from_slice(&9u16.to_le_bytes());
+
+        let mut out = vec![];
+        encode_event(
+            &mut out,
+            None,
+            &EventDescriptor::for_tests(),
+            &metadata,
+            &payload,
+        )
+        .unwrap();
+
+        let pointer64 = if cfg!(target_pointer_width = "64") {
+            1
+        } else {
+            0
+        };
+        let mut expected = vec![
+            HEADER_FLAG_LITTLE_ENDIAN | HEADER_FLAG_EXTENSION | pointer64,
+            1, // version
+            3,
+            0, // id
+            0,
+            0, // tag
+            2, // opcode
+            4, // level
+        ];
+        let mut eh_metadata = b"ev\0".to_vec();
+        eh_metadata.extend_from_slice(b"a\0");
+        eh_metadata.extend_from_slice(&[ENCODING_VALUE32 | ENCODING_CHAIN_FLAG, FORMAT_HEX_INT]);
+        eh_metadata.extend_from_slice(b"s\0");
+        eh_metadata.extend_from_slice(&[
+            ENCODING_STRING_LENGTH16_CHAR8 | ENCODING_CHAIN_FLAG,
+            FORMAT_STRING_UTF,
+        ]);
+        eh_metadata.extend_from_slice(b"v\0");
+        eh_metadata.extend_from_slice(&[
+            ENCODING_VALUE16 | ENCODING_VARRAY_FLAG | ENCODING_CHAIN_FLAG,
+            FORMAT_UNSIGNED_INT,
+        ]);
+        expected.extend_from_slice(&(eh_metadata.len() as u16).to_le_bytes());
+        expected.extend_from_slice(&EXTENSION_KIND_METADATA.to_le_bytes());
+        expected.extend_from_slice(&eh_metadata);
+        expected.extend_from_slice(&payload);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn encode_converted_values() {
+        let metadata = tl_metadata(
+            "ev",
+            &[
+                ("g", InFlag::GUID, OutFlag::NULL),
+                ("t", InFlag::FILETIME, OutFlag::NULL),
+                ("w", InFlag::COUNTED_UNICODE_STRING, OutFlag::NULL),
+            ],
+        );
+        let guid = GUID {
+            data1: 0x0011_2233,
+            data2: 0x4455,
+            data3: 0x6677,
+            data4: [0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff],
+        };
+        let mut payload = vec![];
+        payload.extend_from_slice(&guid_to_le_bytes(&guid));
+        payload.extend_from_slice(
+            &((FILETIME_UNIX_EPOCH_SECONDS as u64 + 60) * 10_000_000).to_le_bytes(),
+        );
+        payload.extend_from_slice(&4u16.to_le_bytes());
+        payload.extend_from_slice(&[b'o', 0, b'k', 0]);
+
+        let mut out = vec![];
+        encode_event(
+            &mut out,
+            None,
+            &EventDescriptor::for_tests(),
+            &metadata,
+            &payload,
+        )
+        .unwrap();
+
+        let mut expected_payload = vec![];
+        expected_payload.extend_from_slice(&[
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ]);
+        expected_payload.extend_from_slice(&60i64.to_le_bytes());
+        expected_payload.extend_from_slice(&2u16.to_le_bytes()); // length in characters
+        expected_payload.extend_from_slice(&[b'o', 0, b'k', 0]);
+        assert!(out.ends_with(&expected_payload));
+    }
+
+    fn guid_to_le_bytes(guid: &GUID) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[0..4].copy_from_slice(&guid.data1.to_le_bytes());
+        bytes[4..6].copy_from_slice(&guid.data2.to_le_bytes());
+        bytes[6..8].copy_from_slice(&guid.data3.to_le_bytes());
+        bytes[8..16].copy_from_slice(&guid.data4);
+        bytes
+    }
+
+    #[test]
+    fn encode_activity_id() {
+        let metadata = tl_metadata("ev", &[]);
+        let options = EventOptions {
+            activity_id: Some(GUID {
+                data1: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut out = vec![];
+        encode_event(
+            &mut out,
+            Some(&options),
+            &EventDescriptor::for_tests(),
+            &metadata,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(&out[8..10], &16u16.to_le_bytes());
+        assert_eq!(
+            &out[10..12],
+            &(EXTENSION_KIND_ACTIVITY_ID | EXTENSION_KIND_CHAIN_FLAG).to_le_bytes()
+        );
+        assert_eq!(&out[12..16], &[0, 0, 0, 1]);
+        assert_eq!(&out[28..30], &3u16.to_le_bytes());
+        assert_eq!(&out[32..], b"ev\0");
+    }
+
+    #[test]
+    fn encode_struct() {
+        let metadata = tl_metadata(
+            "ev",
+            &[
+                ("s", InFlag::STRUCT, OutFlag::from_bits_retain(2)),
+                ("a", InFlag::UINT8, OutFlag::NULL),
+                ("b", InFlag::UINT16, OutFlag::NULL),
+                ("c", InFlag::UINT8, OutFlag::NULL),
+            ],
+        );
+        let payload = [1, 2, 0, 3];
+        let mut out = vec![];
+        encode_event(
+            &mut out,
+            None,
+            &EventDescriptor::for_tests(),
+            &metadata,
+            &payload,
+        )
+        .unwrap();
+
+        let mut eh_metadata = b"ev\0".to_vec();
+        eh_metadata.extend_from_slice(b"s\0");
+        eh_metadata.extend_from_slice(&[ENCODING_STRUCT | ENCODING_CHAIN_FLAG, 2]);
+        eh_metadata.extend_from_slice(b"a\0");
+        eh_metadata
+            .extend_from_slice(&[ENCODING_VALUE8 | ENCODING_CHAIN_FLAG, FORMAT_UNSIGNED_INT]);
+        eh_metadata.extend_from_slice(b"b\0");
+        eh_metadata
+            .extend_from_slice(&[ENCODING_VALUE16 | ENCODING_CHAIN_FLAG, FORMAT_UNSIGNED_INT]);
+        eh_metadata.extend_from_slice(b"c\0");
+        eh_metadata
+            .extend_from_slice(&[ENCODING_VALUE8 | ENCODING_CHAIN_FLAG, FORMAT_UNSIGNED_INT]);
+        let mut expected = eh_metadata;
+        expected.extend_from_slice(&payload);
+        assert!(out.ends_with(&expected));
+    }
+
+    #[test]
+    fn struct_nesting_limit() {
+        // `depth` nested single-member structures around a `u8` field.
+        let nested = |depth: usize| {
+            let mut field = DecodedField {
+                name: "a".into(),
+                in_type: InFlag::UINT8,
+                out_type: OutFlag::NULL,
+                value: Value::U8(7),
+            };
+            for _ in 0..depth {
+                field = DecodedField {
+                    name: "s".into(),
+                    in_type: InFlag::STRUCT,
+                    out_type: OutFlag::from_bits_retain(1),
+                    value: Value::Struct(vec![field]),
+                };
+            }
+            vec![field]
+        };
+        let encodings = |depth: usize| {
+            let mut encodings = vec![ENCODING_STRUCT; depth];
+            encodings.push(ENCODING_VALUE8);
+            encodings.into_iter()
+        };
+
+        let mut out = vec![];
+        let depth = decode::MAX_STRUCT_DEPTH;
+        encode_fields(&mut out, &nested(depth), &mut encodings(depth), 0).unwrap();
+        assert_eq!(out, [7]);
+        let depth = decode::MAX_STRUCT_DEPTH + 1;
+        assert!(encode_fields(&mut out, &nested(depth), &mut encodings(depth), 0).is_err());
+    }
+
+    #[test]
+    fn unsupported_field() {
+        let metadata =
+            tl_metadata_bytes("ev", &[("p", InFlag::from_bits_retain(16), OutFlag::NULL)]);
+        assert!(EventFormat::new(&metadata).is_err());
+    }
+
+    #[test]
+    fn passthrough() {
+        let format = tl_metadata(
+            "ev",
+            &[
+                ("a", InFlag::UINT32, OutFlag::NULL),
+                ("s", InFlag::COUNTED_ANSI_STRING, OutFlag::UTF8),
+            ],
+        );
+        assert!(format.passthrough);
+        let format = tl_metadata(
+            "ev",
+            &[
+                ("a", InFlag::UINT32, OutFlag::NULL),
+                ("g", InFlag::GUID, OutFlag::NULL),
+            ],
+        );
+        assert!(!format.passthrough);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(filetime_to_time64(116_444_736_000_000_000), 0);
+        assert_eq!(systemtime_to_time64(&[1970, 1, 4, 1, 0, 0, 0, 0]), 0);
+        assert_eq!(
+            systemtime_to_time64(&[2024, 2, 4, 29, 12, 30, 15, 500]),
+            1_709_209_815
+        );
+    }
+
+    /// Creates an empty directory that stands in for tracefs.
+    fn fake_tracefs(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "win_etw_user_events_{}_{}",
+            test_name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn missing_tracefs() {
+        let dir = fake_tracefs("missing");
+        assert_eq!(
+            UserEventsProvider::with_tracefs(&dir, "Test").err(),
+            Some(Error::LinuxError(2)) // ENOENT
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_provider_name() {
+        let dir = fake_tracefs("invalid_name");
+        std::fs::write(dir.join("user_events_data"), b"").unwrap();
+        for name in ["", "a b", "a:b"].iter() {
+            assert_eq!(
+                UserEventsProvider::with_tracefs(&dir, name).err(),
+                Some(Error::LinuxError(EINVAL))
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn registration_failure() {
+        // Registration fails because the ioctl is not supported on a regular file. The provider
+        // must still work, but must never report that it is enabled or write anything.
+        let dir = fake_tracefs("registration_failure");
+        let data_path = dir.join("user_events_data");
+        std::fs::write(&data_path, b"").unwrap();
+        let provider = UserEventsProvider::with_tracefs(&dir, "Test").unwrap();
+        assert!(!provider.is_enabled(5, 0));
+        assert!(!provider.is_event_enabled(&EventDescriptor::for_tests()));
+
+        let metadata = b"\x06\x00\x00ev\x00";
+        let value = 1u32;
+        provider.write(
+            None,
+            &EventDescriptor::for_tests(),
+            &[
+                EventDataDescriptor::for_provider_metadata(b"\x07\x00Test\x00"),
+                EventDataDescriptor::for_event_metadata(&metadata[..]),
+                EventDataDescriptor::from(&value),
+            ],
+        );
+        assert_eq!(std::fs::read(&data_path).unwrap(), b"");
+        assert_eq!(read_lock(&provider.tracepoints).len(), 2);
+        // ENOTTY
+        assert_eq!(provider.last_error(), Some(Error::LinuxError(25)));
+        drop(provider);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Adds a tracepoint that is enabled, as if the kernel had registered and enabled it.
+    fn add_enabled_tracepoint(provider: &UserEventsProvider, level: u8, keyword: u64) {
+        let tracepoint = Arc::new(Tracepoint {
+            data_file: provider.data_file.clone(),
+            enabled: NonNull::from(Box::leak(Box::new(AtomicU32::new(1)))),
+            registration: Ok(7),
+        });
+        write_lock(&provider.tracepoints).insert((level, keyword), tracepoint);
+    }
+
+    #[test]
+    fn write_events() {
+        let dir = fake_tracefs("write_events");
+        let data_path = dir.join("user_events_data");
+        std::fs::write(&data_path, b"").unwrap();
+        let provider = UserEventsProvider::with_tracefs(&dir, "Test").unwrap();
+        add_enabled_tracepoint(
+            &provider,
+            EventDescriptor::for_tests().level.0,
+            EventDescriptor::for_tests().keyword,
+        );
+        assert!(provider.is_event_enabled(&EventDescriptor::for_tests()));
+
+        let value = 0x1234_5678u32;
+        let guid = GUID {
+            data1: 1,
+            ..Default::default()
+        };
+        let guid_bytes = guid_to_le_bytes(&guid);
+        let mut expected = vec![];
+        for (metadata, field) in [
+            (
+                tl_metadata_bytes("ev", &[("a", InFlag::UINT32, OutFlag::NULL)]),
+                &value.to_le_bytes()[..],
+            ),
+            (
+                tl_metadata_bytes("ev", &[("g", InFlag::GUID, OutFlag::NULL)]),
+                &guid_bytes[..],
+            ),
+        ] {
+            provider.write(
+                None,
+                &EventDescriptor::for_tests(),
+                &[
+                    EventDataDescriptor::for_provider_metadata(b"\x07\x00Test\x00"),
+                    EventDataDescriptor::for_event_metadata(&metadata),
+                    EventDataDescriptor::from(field),
+                ],
+            );
+            let format = EventFormat::new(&metadata).unwrap();
+            expected.extend_from_slice(&7u32.to_le_bytes());
+            encode_event(
+                &mut expected,
+                None,
+                &EventDescriptor::for_tests(),
+                &format,
+                field,
+            )
+            .unwrap();
+        }
+        assert_eq!(provider.last_error(), None);
+        assert_eq!(std::fs::read(&data_path).unwrap(), expected);
+        // The GUID was converted to the EventHeader byte order.
+        assert!(expected.ends_with(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(read_lock(&provider.formats).len(), 2);
+
+        // An event without metadata cannot be written.
+        provider.write(
+            None,
+            &EventDescriptor::for_tests(),
+            &[EventDataDescriptor::from(&value)],
+        );
+        assert_eq!(provider.last_error(), Some(Error::LinuxError(EINVAL)));
+        assert_eq!(std::fs::read(&data_path).unwrap(), expected);
+        drop(provider);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}